@@ -0,0 +1,115 @@
+#!/usr/bin/env bash
+# Measure how pnafs write throughput scales with parallel writers.
+#
+# Runs fsx-rs (see test_fsx.sh) against a pnafs --write mount, first as
+# a single process and then as BENCH_JOBS concurrent processes, each on
+# its own file, and prints aggregate ops/s for both. Data operations on
+# different files only share the tree's read lock plus their own
+# per-inode lock, so the parallel figure should approach BENCH_JOBS
+# times the single-writer one (bounded by the FUSE worker thread count).
+#
+# Environment overrides:
+#   PNA_BIN            Path to the pna CLI       (default: pna).
+#   PNAFS_BIN          Path to the pnafs binary  (default: pnafs).
+#   PNAFS_MOUNT_ARGS   Extra `pnafs mount` flags (default: none).
+#   FSX_DIR            fsx-rs checkout/build     (default: target/fsx-rs,
+#                                                 as built by test_fsx.sh).
+#   FSX_CONFIG         Config file               (default: this dir).
+#   BENCH_JOBS         Parallel fsx processes    (default: 4).
+#   BENCH_NUMOPS       Ops per fsx process       (default: 20000).
+#   BENCH_SEED         RNG seed for every fsx    (default: 1).
+
+set -euo pipefail
+
+PNA_BIN="${PNA_BIN:-pna}"
+PNAFS_BIN="${PNAFS_BIN:-pnafs}"
+read -r -a PNAFS_MOUNT_ARGS <<< "${PNAFS_MOUNT_ARGS:-}"
+
+SCRIPT_DIR="$(cd "$(dirname "$0")" && pwd)"
+REPO_ROOT="$(cd "$SCRIPT_DIR/../.." && pwd)"
+
+FSX_DIR="${FSX_DIR:-$REPO_ROOT/target/fsx-rs}"
+FSX_BIN="$FSX_DIR/build/release/fsx"
+FSX_CONFIG="${FSX_CONFIG:-$SCRIPT_DIR/fsx.toml}"
+BENCH_JOBS="${BENCH_JOBS:-4}"
+BENCH_NUMOPS="${BENCH_NUMOPS:-20000}"
+BENCH_SEED="${BENCH_SEED:-1}"
+
+WORKDIR="$(mktemp -d)"
+chmod 0755 "$WORKDIR"
+ARCHIVE="$WORKDIR/bench.pna"
+MOUNTPOINT="$WORKDIR/mnt"
+MOUNT_PID=""
+
+cleanup() {
+  if [ -n "$MOUNT_PID" ]; then
+    kill "$MOUNT_PID" 2>/dev/null || true
+  fi
+  if mount | grep -q "$MOUNTPOINT"; then
+    fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT" 2>/dev/null || true
+  fi
+  rm -rf "$WORKDIR"
+}
+trap cleanup EXIT
+
+ensure_fsx() {
+  if [ ! -x "$FSX_BIN" ]; then
+    echo "fsx not found at $FSX_BIN; building it via test_fsx.sh ..."
+    FSX_NUMOPS=1 "$SCRIPT_DIR/test_fsx.sh" >/dev/null
+  fi
+}
+
+create_seed_archive() {
+  ( cd "$WORKDIR" && echo "seed" > seed.txt && \
+    "$PNA_BIN" create --file "$ARCHIVE" --overwrite seed.txt )
+  rm -f "$WORKDIR/seed.txt"
+}
+
+mount_rw() {
+  mkdir -p "$MOUNTPOINT"
+  "$PNAFS_BIN" mount --foreground --write "${PNAFS_MOUNT_ARGS[@]}" "$ARCHIVE" "$MOUNTPOINT" &
+  MOUNT_PID=$!
+  for _ in $(seq 1 20); do
+    if mount | grep -q "$MOUNTPOINT"; then break; fi
+    sleep 0.5
+  done
+  mount | grep -q "$MOUNTPOINT" || { echo "FAIL: mount did not succeed"; exit 1; }
+}
+
+unmount_wait() {
+  fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT"
+  wait "$MOUNT_PID" 2>/dev/null || true
+  MOUNT_PID=""
+}
+
+# run_jobs N: run N fsx processes at once, each on its own file, and
+# print the aggregate ops/s.
+run_jobs() {
+  local jobs="$1" pids=() start end i
+  for i in $(seq 1 "$jobs"); do
+    : > "$MOUNTPOINT/bench_$jobs.$i"
+  done
+  start="$(date +%s.%N)"
+  for i in $(seq 1 "$jobs"); do
+    "$FSX_BIN" -f "$FSX_CONFIG" -N "$BENCH_NUMOPS" -S "$BENCH_SEED" \
+      -P "$WORKDIR" "$MOUNTPOINT/bench_$jobs.$i" >/dev/null &
+    pids+=("$!")
+  done
+  for i in "${pids[@]}"; do
+    wait "$i" || { echo "FAIL: fsx exited non-zero"; exit 1; }
+  done
+  end="$(date +%s.%N)"
+  awk -v n="$((jobs * BENCH_NUMOPS))" -v s="$start" -v e="$end" -v j="$jobs" \
+    'BEGIN { printf "%2d writer(s): %10.0f ops/s (%.2fs)\n", j, n / (e - s), e - s }'
+}
+
+main() {
+  ensure_fsx
+  create_seed_archive
+  mount_rw
+  run_jobs 1
+  run_jobs "$BENCH_JOBS"
+  unmount_wait
+}
+
+main "$@"
@@ -0,0 +1,93 @@
+#!/usr/bin/env bash
+# Measure what the kernel writeback cache buys for small sequential
+# writes.
+#
+# Writes BENCH_MB MiB through `dd` in BENCH_BS-byte blocks into a pnafs
+# --write mount, once as plain write-through and once with
+# --writeback-cache, and prints the throughput of both. Without the
+# cache every block is its own FUSE request; with it the kernel batches
+# dirty pages into large writes, so the second figure should be well
+# ahead for block sizes below the page size. The kernel writes dirty
+# pages back before `close` returns, so buffered data is counted only
+# once it has reached pnafs.
+#
+# Environment overrides:
+#   PNA_BIN            Path to the pna CLI       (default: pna).
+#   PNAFS_BIN          Path to the pnafs binary  (default: pnafs).
+#   PNAFS_MOUNT_ARGS   Extra `pnafs mount` flags (default: none).
+#   BENCH_MB           MiB written per run       (default: 64).
+#   BENCH_BS           dd block size in bytes    (default: 512).
+
+set -euo pipefail
+
+PNA_BIN="${PNA_BIN:-pna}"
+PNAFS_BIN="${PNAFS_BIN:-pnafs}"
+read -r -a PNAFS_MOUNT_ARGS <<< "${PNAFS_MOUNT_ARGS:-}"
+BENCH_MB="${BENCH_MB:-64}"
+BENCH_BS="${BENCH_BS:-512}"
+
+WORKDIR="$(mktemp -d)"
+chmod 0755 "$WORKDIR"
+ARCHIVE="$WORKDIR/bench.pna"
+MOUNTPOINT="$WORKDIR/mnt"
+MOUNT_PID=""
+
+cleanup() {
+  if [ -n "$MOUNT_PID" ]; then
+    kill "$MOUNT_PID" 2>/dev/null || true
+  fi
+  if mount | grep -q "$MOUNTPOINT"; then
+    fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT" 2>/dev/null || true
+  fi
+  rm -rf "$WORKDIR"
+}
+trap cleanup EXIT
+
+create_seed_archive() {
+  ( cd "$WORKDIR" && echo "seed" > seed.txt && \
+    "$PNA_BIN" create --file "$ARCHIVE" --overwrite seed.txt )
+  rm -f "$WORKDIR/seed.txt"
+}
+
+# mount_rw [flags...]: mount the archive read-write with extra flags.
+mount_rw() {
+  mkdir -p "$MOUNTPOINT"
+  "$PNAFS_BIN" mount --foreground --write "${PNAFS_MOUNT_ARGS[@]}" "$@" "$ARCHIVE" "$MOUNTPOINT" &
+  MOUNT_PID=$!
+  for _ in $(seq 1 20); do
+    if mount | grep -q "$MOUNTPOINT"; then break; fi
+    sleep 0.5
+  done
+  mount | grep -q "$MOUNTPOINT" || { echo "FAIL: mount did not succeed"; exit 1; }
+}
+
+unmount_wait() {
+  fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT"
+  wait "$MOUNT_PID" 2>/dev/null || true
+  MOUNT_PID=""
+}
+
+# run <label> [flags...]: time BENCH_MB MiB of BENCH_BS-byte writes.
+run() {
+  local label="$1" count start end size
+  shift
+  mount_rw "$@"
+  count=$((BENCH_MB * 1024 * 1024 / BENCH_BS))
+  start="$(date +%s.%N)"
+  dd if=/dev/zero of="$MOUNTPOINT/bench.$label" bs="$BENCH_BS" count="$count" status=none
+  end="$(date +%s.%N)"
+  size="$(stat -c %s "$MOUNTPOINT/bench.$label")"
+  [ "$size" -eq $((count * BENCH_BS)) ] || { echo "FAIL: $label wrote $size bytes"; exit 1; }
+  unmount_wait
+  awk -v mb="$BENCH_MB" -v s="$start" -v e="$end" -v l="$label" \
+    'BEGIN { printf "%-10s %8.1f MiB/s (%.2fs)\n", l, mb / (e - s), e - s }'
+}
+
+main() {
+  create_seed_archive
+  echo "$BENCH_MB MiB in $BENCH_BS-byte writes:"
+  run direct
+  run writeback --writeback-cache
+}
+
+main "$@"
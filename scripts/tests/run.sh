@@ -12,3 +12,4 @@ SCRIPT_DIR="$(dirname "$0")"
 "$SCRIPT_DIR/test_mount_write_encrypted.sh"
 "$SCRIPT_DIR/test_mount_write_strategy.sh"
 "$SCRIPT_DIR/test_mount_exclusive.sh"
+"$SCRIPT_DIR/test_mount_locks.sh"
@@ -11,6 +11,10 @@
 # to exercise pnafs's RwLock<FileTree> and the (nlink, open_count)
 # orphan-collection state machine.
 #
+# While fsstress runs, a probe process repeatedly reads a seed file
+# through the mount and records how long each read took, so the run
+# also reports read latency under concurrent writers (see --threads).
+#
 # Environment overrides:
 #   PNA_BIN            Path to the pna CLI       (default: pna).
 #   PNAFS_BIN          Path to the pnafs binary  (default: pnafs).
@@ -21,6 +25,9 @@
 #   FSSTRESS_NOPS      Ops per process per loop  (default: 2000).
 #   FSSTRESS_LOOPS     Outer loop count          (default: 1).
 #   FSSTRESS_SEED      RNG seed                  (default: random per run).
+#   FSSTRESS_THREADS   pnafs --threads           (default: pnafs default).
+#   FSSTRESS_MAX_LATENCY_MS
+#                      Fail if a probe read took longer (default: report only).
 #   FSSTRESS_REBUILD=1 Force a fresh fetch + rebuild even if cached.
 
 set -euo pipefail
@@ -38,15 +45,22 @@ FSSTRESS_DIR="${FSSTRESS_DIR:-$REPO_ROOT/target/secfs.test}"
 FSSTRESS_NPROC="${FSSTRESS_NPROC:-4}"
 FSSTRESS_NOPS="${FSSTRESS_NOPS:-2000}"
 FSSTRESS_LOOPS="${FSSTRESS_LOOPS:-1}"
+FSSTRESS_THREADS="${FSSTRESS_THREADS:-}"
+FSSTRESS_MAX_LATENCY_MS="${FSSTRESS_MAX_LATENCY_MS:-}"
 
 WORKDIR="$(mktemp -d)"
 chmod 0755 "$WORKDIR"
 ARCHIVE="$WORKDIR/fsstress.pna"
 MOUNTPOINT="$WORKDIR/mnt"
 TESTROOT="$MOUNTPOINT/work"
+LATENCY_LOG="$WORKDIR/latency.log"
 MOUNT_PID=""
+PROBE_PID=""
 
 cleanup() {
+  if [ -n "$PROBE_PID" ]; then
+    kill "$PROBE_PID" 2>/dev/null || true
+  fi
   if [ -n "$MOUNT_PID" ]; then
     kill "$MOUNT_PID" 2>/dev/null || true
   fi
@@ -88,7 +102,11 @@ create_seed_archive() {
 
 mount_rw() {
   mkdir -p "$MOUNTPOINT"
-  "$PNAFS_BIN" mount --write "$ARCHIVE" "$MOUNTPOINT" &
+  local thread_args=()
+  if [ -n "$FSSTRESS_THREADS" ]; then
+    thread_args=(--threads "$FSSTRESS_THREADS")
+  fi
+  "$PNAFS_BIN" mount --foreground --write "${thread_args[@]}" "$ARCHIVE" "$MOUNTPOINT" &
   MOUNT_PID=$!
   for _ in $(seq 1 20); do
     if mount | grep -q "$MOUNTPOINT"; then break; fi
@@ -97,6 +115,42 @@ mount_rw() {
   mount | grep -q "$MOUNTPOINT" || { echo "FAIL: mount did not succeed"; exit 1; }
 }
 
+# Read seed.txt in a loop, logging each read's duration in microseconds.
+start_latency_probe() {
+  : > "$LATENCY_LOG"
+  (
+    while :; do
+      local t0 t1
+      t0="$(date +%s%N)"
+      cat "$MOUNTPOINT/seed.txt" > /dev/null
+      t1="$(date +%s%N)"
+      echo $(( (t1 - t0) / 1000 )) >> "$LATENCY_LOG"
+      sleep 0.01
+    done
+  ) &
+  PROBE_PID=$!
+}
+
+# Stop the probe, print read-latency stats, and enforce the optional bound.
+report_latency() {
+  kill "$PROBE_PID" 2>/dev/null || true
+  wait "$PROBE_PID" 2>/dev/null || true
+  PROBE_PID=""
+  local stats max_us
+  stats="$(sort -n "$LATENCY_LOG" | awk '
+    { v[NR] = $1; sum += $1 }
+    END {
+      if (NR == 0) { print "0 0 0 0"; exit }
+      printf "%d %d %d %d\n", NR, sum / NR, v[int(NR * 0.99) > 0 ? int(NR * 0.99) : 1], v[NR]
+    }')"
+  read -r n avg p99 max_us <<< "$stats"
+  echo "Probe reads during fsstress: n=$n avg=${avg}us p99=${p99}us max=${max_us}us"
+  if [ -n "$FSSTRESS_MAX_LATENCY_MS" ] && [ "$max_us" -gt $(( FSSTRESS_MAX_LATENCY_MS * 1000 )) ]; then
+    echo "FAIL: read latency ${max_us}us exceeds ${FSSTRESS_MAX_LATENCY_MS}ms"
+    return 1
+  fi
+}
+
 unmount_wait() {
   fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT"
   wait "$MOUNT_PID" 2>/dev/null || true
@@ -124,6 +178,7 @@ main() {
   fi
 
   echo "Running fsstress -p $FSSTRESS_NPROC -n $FSSTRESS_NOPS -l $FSSTRESS_LOOPS against $TESTROOT ..."
+  start_latency_probe
   set +e
   "$FSSTRESS_BIN" \
     -d "$TESTROOT" \
@@ -142,6 +197,11 @@ main() {
     exit "$rc"
   fi
 
+  if ! report_latency; then
+    unmount_wait
+    exit 1
+  fi
+
   unmount_wait
   echo "fsstress run complete (no failures)."
 }
@@ -92,7 +92,7 @@ create_seed_archive() {
 
 mount_rw() {
   mkdir -p "$MOUNTPOINT"
-  "$PNAFS_BIN" mount --write "$ARCHIVE" "$MOUNTPOINT" &
+  "$PNAFS_BIN" mount --foreground --write "$ARCHIVE" "$MOUNTPOINT" &
   MOUNT_PID=$!
   for _ in $(seq 1 20); do
     if mount | grep -q "$MOUNTPOINT"; then break; fi
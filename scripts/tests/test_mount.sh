@@ -1,8 +1,8 @@
 #!/usr/bin/env bash
-# Round-trip pnafs mount tests: archive a real directory tree, mount it,
-# and `diff -r` against the original. Exercised three times — default,
-# --keep-dir, and password-encrypted — using the project's own `src/`
-# tree as the input corpus.
+# Round-trip pnafs mount tests: archive a real directory tree, mount it
+# in the background, and `diff -r` against the original. Exercised three
+# times — default, --keep-dir, and password-encrypted — using the
+# project's own `src/` tree as the input corpus.
 #
 # Archive and mountpoint live inside `mktemp -d`, so the script never
 # writes to or deletes anything in the caller's CWD. The pna `create -r`
@@ -27,29 +27,21 @@ cleanup() {
   if mount | grep -q "$MOUNTPOINT"; then
     fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT" 2>/dev/null || true
   fi
+  cat "$WORKDIR/pnafs.log" 2>/dev/null || true
   rm -rf "$WORKDIR"
   echo "Done."
 }
 
 run() {
   ( cd "$PROJECT_ROOT" && $PNA_BIN create --file "$ARCHIVE" -r src --overwrite $PNA_OPTIONS )
-  $PNAFS_BIN mount "$ARCHIVE" "$MOUNTPOINT" $PNA_FS_OPTIONS &
-  PID="$!"
-  while [ ! -e "$MOUNTPOINT/src" ]; do
-    echo "Wait while mount ..."
-    sleep 1
-    set +eu
-    ps -p "$PID" > /dev/null
-    EXIT_CODE=$?
-    set -eu
-    if [ $EXIT_CODE -ne 0 ]; then
-      echo "mount process failed"
-      break
-    fi
-  done
+  # Without --foreground, `pnafs mount` returns once the mount is usable
+  # and leaves the serving process in the background.
+  $PNAFS_BIN mount "$ARCHIVE" "$MOUNTPOINT" $PNA_FS_OPTIONS 2>>"$WORKDIR/pnafs.log"
   echo "Checking files ..."
   diff -r "$PROJECT_ROOT/src" "$MOUNTPOINT/src"
-  umount "$MOUNTPOINT"
+  # Waits for the background process to exit, so the next run can mount
+  # the archive again.
+  $PNAFS_BIN unmount "$MOUNTPOINT"
   echo "Done."
 }
 
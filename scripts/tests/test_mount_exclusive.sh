@@ -47,7 +47,7 @@ mount_bg() {
   local mp="$1" pid_var="$2"
   shift 2
   mkdir -p "$mp"
-  "$PNAFS_BIN" mount "$@" "$ARCHIVE" "$mp" &
+  "$PNAFS_BIN" mount --foreground "$@" "$ARCHIVE" "$mp" &
   printf -v "$pid_var" '%s' "$!"
   for _ in $(seq 1 20); do
     if mount | grep -qF " on $mp "; then return 0; fi
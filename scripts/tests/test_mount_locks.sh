@@ -0,0 +1,149 @@
+#!/usr/bin/env bash
+# Regression test: POSIX record locks (fcntl F_GETLK / F_SETLK /
+# F_SETLKW) taken on files inside a --write mount must be honoured
+# across processes.
+#
+# pnafs negotiates FUSE_POSIX_LOCKS and arbitrates the locks itself
+# (src/file_locks.rs). The checks drive fcntl from python3, since
+# neither pjdfstest nor fsx exercises record locks.
+#
+# Archive and mountpoint live inside `mktemp -d`; deterministic and
+# self-cleaning.
+set -euo pipefail
+
+PNA_BIN="${PNA_BIN:-pna}"
+PNAFS_BIN="${PNAFS_BIN:-pnafs}"
+PYTHON="${PYTHON:-python3}"
+
+# See test_mount_exclusive.sh for why the temp path is resolved.
+WORKDIR="$(cd "$(mktemp -d)" && pwd -P)"
+ARCHIVE="$WORKDIR/locks.pna"
+MOUNTPOINT="$WORKDIR/mnt"
+MOUNT_PID=""
+
+cleanup() {
+  [ -n "$MOUNT_PID" ] && kill "$MOUNT_PID" 2>/dev/null || true
+  if mount | grep -qF " on $MOUNTPOINT "; then
+    fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT" 2>/dev/null || true
+  fi
+  rm -rf "$WORKDIR"
+}
+trap cleanup EXIT
+
+fail() {
+  echo "FAIL: $1"
+  exit 1
+}
+
+# Seed a minimal archive and mount it read-write.
+(
+  cd "$WORKDIR"
+  echo "seed" > seed.txt
+  "$PNA_BIN" create --file "$ARCHIVE" --overwrite seed.txt
+)
+rm -f "$WORKDIR/seed.txt"
+mkdir -p "$MOUNTPOINT"
+"$PNAFS_BIN" mount --foreground --write "$ARCHIVE" "$MOUNTPOINT" &
+MOUNT_PID=$!
+for _ in $(seq 1 20); do
+  if mount | grep -qF " on $MOUNTPOINT "; then break; fi
+  sleep 0.5
+done
+mount | grep -qF " on $MOUNTPOINT " || fail "mount did not come up"
+
+FILE="$MOUNTPOINT/locked.txt"
+echo "0123456789" > "$FILE"
+
+# locker <range> <type> <hold-seconds>: lock bytes 0..range, print
+# "locked", hold the lock, then exit (closing the fd releases it).
+locker() {
+  "$PYTHON" - "$FILE" "$@" <<'EOF'
+import fcntl, sys, time
+path, length, kind, hold = sys.argv[1], int(sys.argv[2]), sys.argv[3], float(sys.argv[4])
+with open(path, "r+b") as f:
+    fcntl.lockf(f, fcntl.LOCK_EX if kind == "w" else fcntl.LOCK_SH, length, 0)
+    print("locked", flush=True)
+    time.sleep(hold)
+EOF
+}
+
+# probe <args...>: run one fcntl check against FILE from a fresh
+# process and print its verdict.
+probe() {
+  "$PYTHON" - "$FILE" "$@" <<'EOF'
+import errno, fcntl, os, struct, sys, time
+path, op = sys.argv[1], sys.argv[2]
+with open(path, "r+b") as f:
+    if op == "trylock":
+        kind = fcntl.LOCK_EX if sys.argv[3] == "w" else fcntl.LOCK_SH
+        start, length = int(sys.argv[4]), int(sys.argv[5])
+        try:
+            fcntl.lockf(f, kind | fcntl.LOCK_NB, length, start)
+            print("granted")
+        except OSError as e:
+            print("busy" if e.errno in (errno.EAGAIN, errno.EACCES) else e)
+    elif op == "getlk":
+        # struct flock: short l_type, l_whence; off_t l_start, l_len; pid_t l_pid
+        fmt = "hhqqi"
+        req = struct.pack(fmt, fcntl.F_WRLCK, os.SEEK_SET, 0, 0, 0)
+        typ, _, start, length, pid = struct.unpack(fmt, fcntl.fcntl(f, fcntl.F_GETLK, req))
+        print("unlocked" if typ == fcntl.F_UNLCK else f"held {start} {length} {pid}")
+    elif op == "waitlock":
+        began = time.monotonic()
+        fcntl.lockf(f, fcntl.LOCK_EX, 0, 0)
+        print(f"waited {time.monotonic() - began:.1f}")
+EOF
+}
+
+echo "=== write lock excludes another process's overlapping lock ==="
+coproc LOCKER { locker 4 w 3; }
+# Bash unsets LOCKER_PID once the coprocess exits; keep a copy.
+locker_pid=$LOCKER_PID
+read -r line <&"${LOCKER[0]}"
+[ "$line" = "locked" ] || fail "locker did not take its lock: $line"
+[ "$(probe trylock w 0 1)" = "busy" ] || fail "overlapping F_SETLK was granted"
+[ "$(probe trylock r 2 2)" = "busy" ] || fail "overlapping read lock was granted"
+echo "PASS"
+
+echo "=== non-overlapping range is independent ==="
+[ "$(probe trylock w 4 4)" = "granted" ] || fail "disjoint range was refused"
+echo "PASS"
+
+echo "=== F_GETLK reports the holder ==="
+out="$(probe getlk)"
+case "$out" in
+  "held 0 4 "[1-9]*) ;;
+  *) fail "F_GETLK should report the holder of bytes 0-3, got: $out" ;;
+esac
+echo "PASS"
+
+echo "=== F_SETLKW blocks until the holder releases ==="
+out="$(probe waitlock)"
+wait "$locker_pid" 2>/dev/null || true
+case "$out" in
+  "waited 0.0") fail "F_SETLKW returned without waiting" ;;
+  "waited "*) ;;
+  *) fail "F_SETLKW should have returned once the holder exited, got: $out" ;;
+esac
+echo "PASS"
+
+echo "=== closing the fd released the lock ==="
+[ "$(probe getlk)" = "unlocked" ] || fail "lock survived the holder's exit"
+[ "$(probe trylock w 0 0)" = "granted" ] || fail "whole-file lock refused after release"
+echo "PASS"
+
+echo "=== shared locks coexist ==="
+coproc READER { locker 0 r 2; }
+reader_pid=$READER_PID
+read -r line <&"${READER[0]}"
+[ "$line" = "locked" ] || fail "reader did not take its lock: $line"
+[ "$(probe trylock r 0 0)" = "granted" ] || fail "second shared lock was refused"
+[ "$(probe trylock w 0 0)" = "busy" ] || fail "write lock granted over a shared lock"
+wait "$reader_pid" 2>/dev/null || true
+echo "PASS"
+
+fusermount -u "$MOUNTPOINT" 2>/dev/null || umount "$MOUNTPOINT"
+wait "$MOUNT_PID" 2>/dev/null || true
+MOUNT_PID=""
+
+echo "All record-lock tests passed."
@@ -40,7 +40,7 @@ fail() {
 
 mount_ro() {
   mkdir -p "$MOUNTPOINT"
-  "$PNAFS_BIN" mount "$ARCHIVE" "$MOUNTPOINT" &
+  "$PNAFS_BIN" mount --foreground "$ARCHIVE" "$MOUNTPOINT" &
   MOUNT_PID=$!
   for _ in $(seq 1 20); do
     if mount | grep -qF " on $MOUNTPOINT "; then break; fi
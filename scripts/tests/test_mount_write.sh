@@ -21,7 +21,7 @@ trap cleanup EXIT
 
 mount_rw() {
   mkdir -p "$MOUNTPOINT"
-  "$PNAFS_BIN" mount --write "$ARCHIVE" "$MOUNTPOINT" &
+  "$PNAFS_BIN" mount --foreground --write "$ARCHIVE" "$MOUNTPOINT" &
   MOUNT_PID=$!
   for i in $(seq 1 10); do
     if mount | grep -q "$MOUNTPOINT"; then break; fi
@@ -137,7 +137,7 @@ echo "PASS"
 
 echo "=== Test 9: Read-only mount rejects write ==="
 mkdir -p "$MOUNTPOINT"
-"$PNAFS_BIN" mount "$ARCHIVE" "$MOUNTPOINT" &
+"$PNAFS_BIN" mount --foreground "$ARCHIVE" "$MOUNTPOINT" &
 MOUNT_PID=$!
 for i in $(seq 1 10); do
   if mount | grep -q "$MOUNTPOINT"; then break; fi
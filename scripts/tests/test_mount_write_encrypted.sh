@@ -27,7 +27,7 @@ rm -f "$WORKDIR/seed.txt"
 
 mount_enc() {
   mkdir -p "$MOUNTPOINT"
-  "$PNAFS_BIN" mount --write --password "$PASSWORD" "$ARCHIVE" "$MOUNTPOINT" &
+  "$PNAFS_BIN" mount --foreground --write --password "$PASSWORD" "$ARCHIVE" "$MOUNTPOINT" &
   MOUNT_PID=$!
   for i in $(seq 1 10); do
     if mount | grep -q "$MOUNTPOINT"; then break; fi
@@ -67,7 +67,7 @@ echo "PASS"
 echo "=== Encrypted Test 3: Mount without password rejects or fails ==="
 mkdir -p "$MOUNTPOINT"
 # Mount encrypted archive without password in background; expect failure or no mount
-"$PNAFS_BIN" mount --write "$ARCHIVE" "$MOUNTPOINT" 2>/dev/null &
+"$PNAFS_BIN" mount --foreground --write "$ARCHIVE" "$MOUNTPOINT" 2>/dev/null &
 TEST3_PID=$!
 sleep 2
 if mount | grep -q "$MOUNTPOINT"; then
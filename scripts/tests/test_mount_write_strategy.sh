@@ -27,7 +27,7 @@ rm -f "$WORKDIR/seed.txt"
 
 mount_with_strategy() {
   mkdir -p "$MOUNTPOINT"
-  "$PNAFS_BIN" mount --write --write-strategy "$1" "$ARCHIVE" "$MOUNTPOINT" &
+  "$PNAFS_BIN" mount --foreground --write --write-strategy "$1" "$ARCHIVE" "$MOUNTPOINT" &
   MOUNT_PID=$!
   for i in $(seq 1 10); do
     if mount | grep -q "$MOUNTPOINT"; then break; fi
@@ -150,7 +150,7 @@ mount_rw() {
   # actually reach the FUSE filesystem. Without it the kernel rejects every
   # access at the mount point with EACCES before we get a chance to apply
   # POSIX permission semantics.
-  "$PNAFS_BIN" mount --write --allow-other "$ARCHIVE" "$MOUNTPOINT" &
+  "$PNAFS_BIN" mount --foreground --write --allow-other "$ARCHIVE" "$MOUNTPOINT" &
   MOUNT_PID=$!
   for _ in $(seq 1 20); do
     if mount | grep -q "$MOUNTPOINT"; then break; fi
@@ -0,0 +1,866 @@
+//! `pnafs mount-dir`: a directory of archives as one read-only tree.
+//!
+//! Every `NAME.pna` in the directory appears as a directory `NAME` at the
+//! root of the mount, with the owner, times and permissions of the file.
+//! An archive is loaded, under a shared [`ArchiveLock`], the first time
+//! something inside it is needed, and unloaded again once it has been
+//! idle for a while with no open files. A loaded archive whose file
+//! changes is loaded again on the next lookup or open inside it.
+//!
+//! Each archive's [`FileTree`] numbers its inodes on its own, so the
+//! kernel sees numbers handed out here, remembered for `(archive, inode)`
+//! pairs until the kernel forgets them. Tree inode numbers are derived
+//! from paths, so they are the same when an unloaded archive is loaded
+//! again, and inodes the kernel still remembers keep working.
+
+use crate::archive_io::{self, LoadOptions};
+use crate::archive_lock::{ArchiveLock, LockMode};
+use crate::file_tree::{FileTree, FsContent, Inode, ROOT_INODE};
+use fuser::{
+    Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, INodeNo, LockOwner, OpenAccMode,
+    OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyXattr,
+    Request,
+};
+use log::info;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Extension of the files shown as directories.
+const EXTENSION: &str = "pna";
+
+/// How long the kernel may cache attributes and names. The root listing
+/// follows the directory on disk, so this stays short.
+const TTL: Duration = Duration::from_secs(1);
+
+/// `d_ino` of directory entries the kernel has not looked up yet, as
+/// libfuse reports them; numbers are only handed out by lookups, which
+/// the kernel later forgets.
+const UNKNOWN_INO: u64 = 0xffff_ffff;
+
+/// Serves a directory of archives.
+pub(crate) struct ArchiveDirFS {
+    dir: PathBuf,
+    password: Option<String>,
+    options: LoadOptions,
+    state: Arc<Mutex<State>>,
+}
+
+/// The kernel's view of the mount: which archives and inodes it knows.
+/// Held only briefly; archives load under their own locks.
+struct State {
+    archives: HashMap<usize, Slot>,
+    by_name: HashMap<OsString, usize>,
+    next_archive: usize,
+    /// Kernel inode numbers of `(archive, tree inode)` pairs, and back.
+    inodes: HashMap<(usize, Inode), u64>,
+    keys: HashMap<u64, Key>,
+    next_ino: u64,
+}
+
+/// An archive the kernel holds inodes of.
+struct Slot {
+    archive: Arc<Archive>,
+    name: OsString,
+    /// How many of `State::keys` are in this archive.
+    inodes: usize,
+}
+
+struct Key {
+    archive: usize,
+    ino: Inode,
+    /// The kernel's lookup count; the key goes when it forgets them all.
+    lookups: u64,
+}
+
+struct Archive {
+    path: PathBuf,
+    loaded: Mutex<Option<Loaded>>,
+}
+
+struct Loaded {
+    tree: FileTree,
+    _lock: ArchiveLock,
+    last_used: Instant,
+    /// When the file was last compared with the tree's origin.
+    checked: Instant,
+    open_files: usize,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            archives: HashMap::new(),
+            by_name: HashMap::new(),
+            next_archive: 0,
+            inodes: HashMap::new(),
+            keys: HashMap::new(),
+            next_ino: ROOT_INODE + 1,
+        }
+    }
+}
+
+impl State {
+    /// The kernel inode number of `ino` in archive `index`, counting a
+    /// lookup of it.
+    fn looked_up(&mut self, index: usize, ino: Inode) -> u64 {
+        let kernel = match self.inodes.get(&(index, ino)) {
+            Some(&kernel) => kernel,
+            None => {
+                let kernel = self.next_ino;
+                self.next_ino += 1;
+                self.inodes.insert((index, ino), kernel);
+                self.keys.insert(
+                    kernel,
+                    Key {
+                        archive: index,
+                        ino,
+                        lookups: 0,
+                    },
+                );
+                if let Some(slot) = self.archives.get_mut(&index) {
+                    slot.inodes += 1;
+                }
+                kernel
+            }
+        };
+        if let Some(key) = self.keys.get_mut(&kernel) {
+            key.lookups += 1;
+        }
+        kernel
+    }
+
+    /// The kernel inode number of `ino` in archive `index`, if the kernel
+    /// knows it.
+    fn known(&self, index: usize, ino: Inode) -> u64 {
+        self.inodes
+            .get(&(index, ino))
+            .copied()
+            .unwrap_or(UNKNOWN_INO)
+    }
+
+    /// Drop `nlookup` lookups of kernel inode `kernel`, and the inode once
+    /// none are left, and its archive once none of its inodes are.
+    fn forget(&mut self, kernel: u64, nlookup: u64) {
+        let Some(key) = self.keys.get_mut(&kernel) else {
+            return;
+        };
+        key.lookups = key.lookups.saturating_sub(nlookup);
+        if key.lookups > 0 {
+            return;
+        }
+        let (index, ino) = (key.archive, key.ino);
+        self.keys.remove(&kernel);
+        self.inodes.remove(&(index, ino));
+        if let Some(slot) = self.archives.get_mut(&index) {
+            slot.inodes -= 1;
+            if slot.inodes == 0 {
+                let name = self.archives.remove(&index).expect("present").name;
+                self.by_name.remove(&name);
+            }
+        }
+    }
+
+    /// The slot of the archive shown as `name`, made on first sight.
+    fn archive(&mut self, name: &OsStr, path: PathBuf) -> usize {
+        if let Some(&index) = self.by_name.get(name) {
+            return index;
+        }
+        let index = self.next_archive;
+        self.next_archive += 1;
+        self.archives.insert(
+            index,
+            Slot {
+                archive: Arc::new(Archive {
+                    path,
+                    loaded: Mutex::default(),
+                }),
+                name: name.to_owned(),
+                inodes: 0,
+            },
+        );
+        self.by_name.insert(name.to_owned(), index);
+        index
+    }
+
+    /// Resolve kernel inode `ino` to its archive and tree inode.
+    fn key(&self, ino: INodeNo) -> Result<(usize, Arc<Archive>, Inode), Errno> {
+        let key = self.keys.get(&ino.0).ok_or(Errno::ENOENT)?;
+        let slot = self.archives.get(&key.archive).ok_or(Errno::ENOENT)?;
+        Ok((key.archive, Arc::clone(&slot.archive), key.ino))
+    }
+}
+
+impl ArchiveDirFS {
+    pub(crate) fn new(dir: PathBuf, password: Option<String>, options: LoadOptions) -> Self {
+        Self {
+            dir,
+            password,
+            options,
+            state: Arc::default(),
+        }
+    }
+
+    /// Unload archives idle for `idle`, checking from a background thread
+    /// that ends with the filesystem.
+    pub(crate) fn spawn_unloader(&self, idle: Duration) -> io::Result<()> {
+        let state = Arc::downgrade(&self.state);
+        let period = (idle / 2).clamp(Duration::from_secs(1), Duration::from_secs(60));
+        thread::Builder::new()
+            .name("pnafs-unload".to_owned())
+            .spawn(move || {
+                while let Some(state) = Weak::upgrade(&state) {
+                    unload_idle(&state, idle);
+                    drop(state);
+                    thread::sleep(period);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// The archive file shown as `name` at the root, if there is one.
+    fn archive_file(&self, name: &OsStr) -> Option<(PathBuf, fs::Metadata)> {
+        let path = self.file_of(name);
+        let meta = fs::metadata(&path).ok()?;
+        meta.is_file().then_some((path, meta))
+    }
+
+    /// `DIR/NAME.pna`; not `with_extension`, which would replace any
+    /// extension `NAME` has itself.
+    fn file_of(&self, name: &OsStr) -> PathBuf {
+        let mut file_name = name.to_owned();
+        file_name.push(".");
+        file_name.push(EXTENSION);
+        self.dir.join(file_name)
+    }
+
+    /// Names shown at the root, sorted.
+    fn archive_names(&self) -> io::Result<Vec<OsString>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = Path::new(entry.file_name().as_os_str()).to_owned();
+            if path.extension() == Some(OsStr::new(EXTENSION))
+                && let Some(stem) = path.file_stem()
+                && entry.file_type()?.is_file()
+            {
+                names.push(stem.to_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn root_attr(&self) -> Result<FileAttr, Errno> {
+        let meta = fs::metadata(&self.dir)?;
+        Ok(dir_attr(&meta, ROOT_INODE, (meta.mode() & 0o7777) as u16))
+    }
+
+    /// The attributes of the directory an archive is shown as, taken from
+    /// the archive file so that nothing needs loading: its permissions,
+    /// with search permission wherever it is readable.
+    fn archive_attr(path: &Path, ino: u64) -> Result<FileAttr, Errno> {
+        let meta = fs::metadata(path)?;
+        let perm = (meta.mode() & 0o777) as u16;
+        Ok(dir_attr(&meta, ino, perm | (perm & 0o444) >> 2))
+    }
+
+    /// `f` applied to archive `archive`, loaded if it is not. Only that
+    /// archive's lock is held, so loading it holds up nothing else.
+    fn with_tree<T>(
+        &self,
+        archive: &Archive,
+        f: impl FnOnce(&mut Loaded) -> Result<T, Errno>,
+    ) -> Result<T, Errno> {
+        let mut loaded = lock(&archive.loaded);
+        if loaded.is_none() {
+            *loaded = Some(self.load(&archive.path).map_err(|e| {
+                log::warn!("could not load {}: {e}", archive.path.display());
+                match e.kind() {
+                    io::ErrorKind::ResourceBusy => Errno::EBUSY,
+                    _ => Errno::from(e),
+                }
+            })?);
+        }
+        let loaded = loaded.as_mut().expect("loaded above");
+        loaded.last_used = Instant::now();
+        f(loaded)
+    }
+
+    fn load(&self, path: &Path) -> io::Result<Loaded> {
+        // Shared, so `pnafs mount --write` of a loaded archive is refused,
+        // while the lock is only held as long as the archive is loaded.
+        let lock = ArchiveLock::acquire(path, LockMode::Shared)?;
+        let tree = archive_io::load_with(path, self.password.clone(), self.options.clone())?;
+        info!("loaded {}", path.display());
+        Ok(Loaded {
+            tree,
+            _lock: lock,
+            last_used: Instant::now(),
+            checked: Instant::now(),
+            open_files: 0,
+        })
+    }
+
+    /// Load the archive behind kernel inode `ino` again if it is loaded
+    /// but its file changed since. The file is compared at most once per
+    /// [`TTL`], as the kernel holds on to earlier answers that long anyway.
+    fn revalidate(&self, ino: INodeNo) {
+        let Ok((_, archive, _)) = lock(&self.state).key(ino) else {
+            return;
+        };
+        let mut loaded = lock(&archive.loaded);
+        let Some(current) = loaded.as_mut() else {
+            return;
+        };
+        if current.checked.elapsed() < TTL {
+            return;
+        }
+        current.checked = Instant::now();
+        let unchanged = match current.tree.origin() {
+            Some(origin) => origin.matches_path(&archive.path),
+            None => Ok(Some(true)),
+        };
+        if matches!(unchanged, Ok(Some(true))) {
+            return;
+        }
+        info!("{} changed, loading it again", archive.path.display());
+        let open_files = current.open_files;
+        // Drop the old tree, and its lock, before loading the new one.
+        *loaded = None;
+        match self.load(&archive.path) {
+            Ok(fresh) => {
+                *loaded = Some(Loaded {
+                    open_files,
+                    ..fresh
+                });
+            }
+            Err(e) => log::warn!("could not load {}: {e}", archive.path.display()),
+        }
+    }
+
+    /// `f` applied to the loaded archive and tree inode behind kernel
+    /// inode `ino`, with the index of that archive.
+    fn with_node<T>(
+        &self,
+        ino: INodeNo,
+        f: impl FnOnce(&mut Loaded, Inode) -> Result<T, Errno>,
+    ) -> Result<(usize, T), Errno> {
+        let (index, archive, local) = lock(&self.state).key(ino)?;
+        let value = self.with_tree(&archive, |loaded| f(loaded, local))?;
+        Ok((index, value))
+    }
+
+    /// Whether `ino` is the root or an archive shown in it, which have no
+    /// extended attributes and are answered without loading anything.
+    fn is_archive_or_root(&self, ino: INodeNo) -> bool {
+        ino.0 == ROOT_INODE
+            || lock(&self.state)
+                .key(ino)
+                .is_ok_and(|(_, _, local)| local == ROOT_INODE)
+    }
+
+    /// Look up `name` in `parent`, counting the lookup of the inode found.
+    fn lookup_attr(&self, parent: INodeNo, name: &OsStr) -> Result<FileAttr, Errno> {
+        if parent.0 == ROOT_INODE {
+            let (path, _) = self.archive_file(name).ok_or(Errno::ENOENT)?;
+            let mut state = lock(&self.state);
+            let index = state.archive(name, path.clone());
+            let ino = state.looked_up(index, ROOT_INODE);
+            return Self::archive_attr(&path, ino);
+        }
+        self.revalidate(parent);
+        let (index, mut attr) = self.with_node(parent, |loaded, local| {
+            loaded
+                .tree
+                .lookup_child(local, name)
+                .map(|n| n.attr)
+                .ok_or(Errno::ENOENT)
+        })?;
+        attr.ino = INodeNo(lock(&self.state).looked_up(index, attr.ino.0));
+        Ok(attr)
+    }
+
+    fn attr(&self, ino: INodeNo) -> Result<FileAttr, Errno> {
+        if ino.0 == ROOT_INODE {
+            return self.root_attr();
+        }
+        let (_, archive, local) = lock(&self.state).key(ino)?;
+        if local == ROOT_INODE {
+            return Self::archive_attr(&archive.path, ino.0);
+        }
+        let mut attr = self.with_tree(&archive, |loaded| {
+            loaded.tree.get(local).map(|n| n.attr).ok_or(Errno::ENOENT)
+        })?;
+        attr.ino = ino;
+        Ok(attr)
+    }
+
+    /// Entries of directory `ino`: name, kernel inode and type.
+    fn listing(&self, ino: INodeNo) -> Result<Vec<(OsString, u64, FileType)>, Errno> {
+        if ino.0 == ROOT_INODE {
+            let names = self.archive_names()?;
+            let state = lock(&self.state);
+            return Ok(names
+                .into_iter()
+                .map(|name| {
+                    let ino = match state.by_name.get(&name) {
+                        Some(&index) => state.known(index, ROOT_INODE),
+                        None => UNKNOWN_INO,
+                    };
+                    (name, ino, FileType::Directory)
+                })
+                .collect());
+        }
+        let (index, entries) = self.with_node(ino, |loaded, local| {
+            Ok(loaded
+                .tree
+                .children(local)
+                .ok_or(Errno::ENOTDIR)?
+                .map(|(name, node)| (name, node.attr.ino.0, node.attr.kind))
+                .collect::<Vec<_>>())
+        })?;
+        let state = lock(&self.state);
+        Ok(entries
+            .into_iter()
+            .map(|(name, child, kind)| (name, state.known(index, child), kind))
+            .collect())
+    }
+
+    fn read_data(&self, ino: INodeNo, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let (_, data) = self.with_node(ino, |loaded, local| {
+            let node = loaded.tree.get(local).ok_or(Errno::ENOENT)?;
+            let data = match &node.content {
+                FsContent::File(fd) => fd.data(),
+                FsContent::Directory(_) => return Err(Errno::EISDIR),
+                _ => return Err(Errno::EINVAL),
+            };
+            let start = data.len().min(offset as usize);
+            let end = data.len().min(start.saturating_add(size as usize));
+            Ok(data[start..end].to_vec())
+        })?;
+        Ok(data)
+    }
+
+    /// Count an open file, which keeps its archive loaded.
+    fn open_file(&self, ino: INodeNo) -> Result<(), Errno> {
+        self.revalidate(ino);
+        self.with_node(ino, |loaded, local| {
+            if loaded.tree.get(local).is_none() {
+                return Err(Errno::ENOENT);
+            }
+            loaded.open_files += 1;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn release_file(&self, ino: INodeNo) {
+        let Ok((_, archive, _)) = lock(&self.state).key(ino) else {
+            return;
+        };
+        if let Some(loaded) = lock(&archive.loaded).as_mut() {
+            loaded.open_files = loaded.open_files.saturating_sub(1);
+            loaded.last_used = Instant::now();
+        }
+    }
+}
+
+/// Attributes of a directory with the times and owner of `meta`.
+fn dir_attr(meta: &fs::Metadata, ino: u64, perm: u16) -> FileAttr {
+    let time = |secs: i64, nsecs: i64| {
+        SystemTime::UNIX_EPOCH + Duration::new(secs.max(0) as u64, nsecs as u32)
+    };
+    FileAttr {
+        ino: INodeNo(ino),
+        size: 0,
+        blocks: 0,
+        atime: time(meta.atime(), meta.atime_nsec()),
+        mtime: time(meta.mtime(), meta.mtime_nsec()),
+        ctime: time(meta.ctime(), meta.ctime_nsec()),
+        crtime: time(meta.mtime(), meta.mtime_nsec()),
+        kind: FileType::Directory,
+        perm,
+        nlink: 2,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: 0,
+        blksize: 512,
+        flags: 0,
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Every update leaves the state consistent, so a panic in another
+    // request is no reason to stop serving.
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Drop archives unused for `idle` and without open files, releasing
+/// their locks. One busy loading or serving a request is in use.
+fn unload_idle(state: &Mutex<State>, idle: Duration) {
+    let archives: Vec<_> = lock(state)
+        .archives
+        .values()
+        .map(|slot| Arc::clone(&slot.archive))
+        .collect();
+    for archive in archives {
+        let Ok(mut loaded) = archive.loaded.try_lock() else {
+            continue;
+        };
+        if loaded
+            .as_ref()
+            .is_some_and(|l| l.open_files == 0 && l.last_used.elapsed() >= idle)
+        {
+            info!("unloading idle {}", archive.path.display());
+            *loaded = None;
+        }
+    }
+}
+
+impl Filesystem for ArchiveDirFS {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        info!("[Implemented] lookup(parent: {parent:#x?}, name {name:?})");
+        match self.lookup_attr(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, fuser::Generation(0)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn forget(&self, _req: &Request, ino: INodeNo, nlookup: u64) {
+        info!("[Implemented] forget(ino: {ino:#x?}, nlookup: {nlookup})");
+        lock(&self.state).forget(ino.0, nlookup);
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        info!("[Implemented] getattr(ino: {ino:#x?})");
+        match self.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&self, _req: &Request, ino: INodeNo, reply: ReplyData) {
+        info!("[Implemented] readlink(ino: {ino:#x?})");
+        let target = self.with_node(ino, |loaded, local| {
+            match &loaded.tree.get(local).ok_or(Errno::ENOENT)?.content {
+                FsContent::Symlink(target) => Ok(target.clone()),
+                _ => Err(Errno::EINVAL),
+            }
+        });
+        match target {
+            Ok((_, target)) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        info!("[Implemented] open(ino: {ino:#x?}, flags: {flags:#x?})");
+        if flags.acc_mode() != OpenAccMode::O_RDONLY {
+            reply.error(Errno::EROFS);
+            return;
+        }
+        match self.open_file(ino) {
+            // Without `FOPEN_KEEP_CACHE`, so that pages cached before the
+            // archive was loaded again are dropped.
+            Ok(()) => reply.opened(FileHandle(0), FopenFlags::empty()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        info!("[Implemented] read(ino: {ino:#x?}, offset: {offset}, size: {size})");
+        match self.read_data(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        info!("[Implemented] release(ino: {ino:#x?})");
+        self.release_file(ino);
+        reply.ok();
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        info!("[Implemented] readdir(ino: {ino:#x?}, offset: {offset})");
+        let entries = match self.listing(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e),
+        };
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        for (i, (name, child, kind)) in entries.into_iter().enumerate().skip(start) {
+            if reply.add(INodeNo(child), i as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn getxattr(&self, _req: &Request, ino: INodeNo, name: &OsStr, size: u32, reply: ReplyXattr) {
+        info!("[Implemented] getxattr(ino: {ino:#x?}, name: {name:?}, size: {size})");
+        if self.is_archive_or_root(ino) {
+            reply.error(Errno::NO_XATTR);
+            return;
+        }
+        let value = self.with_node(ino, |loaded, local| {
+            let node = loaded.tree.get(local).ok_or(Errno::ENOENT)?;
+            name.to_str()
+                .and_then(|n| node.xattrs.get(n).cloned())
+                .ok_or(Errno::NO_XATTR)
+        });
+        match value.map(|(_, value)| value) {
+            Ok(value) if size == 0 => reply.size(value.len() as u32),
+            Ok(value) => reply.data(&value),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn listxattr(&self, _req: &Request, ino: INodeNo, size: u32, reply: ReplyXattr) {
+        info!("[Implemented] listxattr(ino: {ino:#x?}, size: {size})");
+        let keys = if self.is_archive_or_root(ino) {
+            Ok(Vec::new())
+        } else {
+            self.with_node(ino, |loaded, local| {
+                let node = loaded.tree.get(local).ok_or(Errno::ENOENT)?;
+                Ok(node
+                    .xattrs
+                    .keys()
+                    .flat_map(|key| {
+                        CString::new(key.as_bytes())
+                            .unwrap_or_default()
+                            .as_bytes_with_nul()
+                            .to_vec()
+                    })
+                    .collect())
+            })
+            .map(|(_, keys)| keys)
+        };
+        match keys {
+            Ok(keys) if size == 0 => reply.size(keys.len() as u32),
+            Ok(keys) => reply.data(&keys),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pna::{Archive as PnaArchive, Metadata, WriteOptions};
+    use std::io::Write as IoWrite;
+    use tempfile::TempDir;
+
+    fn write_archive(path: &Path, files: &[(&str, &[u8])]) {
+        let mut archive = PnaArchive::write_header(fs::File::create(path).unwrap()).unwrap();
+        for (name, data) in files {
+            archive
+                .write_file(
+                    pna::EntryName::from_lossy(name),
+                    Metadata::new(),
+                    WriteOptions::builder().build(),
+                    |w| w.write_all(data),
+                )
+                .unwrap();
+        }
+        archive.finalize().unwrap();
+    }
+
+    fn dir_fs() -> (TempDir, ArchiveDirFS) {
+        let dir = TempDir::new().unwrap();
+        write_archive(&dir.path().join("one.pna"), &[("a/f", b"first")]);
+        write_archive(&dir.path().join("two.pna"), &[("g", b"second")]);
+        fs::write(dir.path().join("notes.txt"), b"not an archive").unwrap();
+        let fs = ArchiveDirFS::new(dir.path().to_owned(), None, LoadOptions::default());
+        (dir, fs)
+    }
+
+    fn is_loaded(fs: &ArchiveDirFS, name: &str) -> bool {
+        let state = lock(&fs.state);
+        state
+            .by_name
+            .get(OsStr::new(name))
+            .is_some_and(|i| lock(&state.archives[i].archive.loaded).is_some())
+    }
+
+    #[test]
+    fn archives_are_directories_loaded_on_lookup() {
+        let (_dir, fs) = dir_fs();
+        let names: Vec<_> = fs
+            .listing(INodeNo(ROOT_INODE))
+            .unwrap()
+            .into_iter()
+            .map(|(name, _, kind)| (name, kind))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("one".into(), FileType::Directory),
+                ("two".into(), FileType::Directory)
+            ]
+        );
+        assert!(!is_loaded(&fs, "one"), "listing the root loads nothing");
+
+        let one = fs
+            .lookup_attr(INodeNo(ROOT_INODE), OsStr::new("one"))
+            .unwrap();
+        assert_eq!(one.kind, FileType::Directory);
+        assert_eq!(fs.attr(one.ino).unwrap(), one);
+        assert!(!is_loaded(&fs, "one"), "the archive file answers for it");
+        let a = fs.lookup_attr(one.ino, OsStr::new("a")).unwrap();
+        assert!(is_loaded(&fs, "one"));
+        assert!(!is_loaded(&fs, "two"));
+        let f = fs.lookup_attr(a.ino, OsStr::new("f")).unwrap();
+        assert_eq!(fs.read_data(f.ino, 1, 3).unwrap(), b"irs");
+        assert_eq!(
+            fs.lookup_attr(INodeNo(ROOT_INODE), OsStr::new("notes"))
+                .unwrap_err()
+                .code(),
+            libc::ENOENT
+        );
+    }
+
+    #[test]
+    fn idle_archives_unload_and_come_back_with_the_same_inodes() {
+        let (dir, fs) = dir_fs();
+        let one = fs
+            .lookup_attr(INodeNo(ROOT_INODE), OsStr::new("one"))
+            .unwrap();
+        let a = fs.lookup_attr(one.ino, OsStr::new("a")).unwrap();
+        let f = fs.lookup_attr(a.ino, OsStr::new("f")).unwrap();
+
+        // An open file keeps the archive, and its shared lock, around.
+        fs.open_file(f.ino).unwrap();
+        unload_idle(&fs.state, Duration::ZERO);
+        assert!(is_loaded(&fs, "one"));
+        assert!(
+            ArchiveLock::acquire(&dir.path().join("one.pna"), LockMode::Exclusive).is_err(),
+            "a loaded archive is locked against writers"
+        );
+
+        fs.release_file(f.ino);
+        unload_idle(&fs.state, Duration::ZERO);
+        assert!(!is_loaded(&fs, "one"));
+        ArchiveLock::acquire(&dir.path().join("one.pna"), LockMode::Exclusive).unwrap();
+
+        // The kernel may still ask about inodes it learned before.
+        assert_eq!(fs.read_data(f.ino, 0, 64).unwrap(), b"first");
+        assert!(is_loaded(&fs, "one"));
+        assert_eq!(fs.lookup_attr(one.ino, OsStr::new("a")).unwrap().ino, a.ino);
+    }
+
+    #[test]
+    fn changed_archives_are_loaded_again() {
+        let (dir, fs) = dir_fs();
+        let one = fs
+            .lookup_attr(INodeNo(ROOT_INODE), OsStr::new("one"))
+            .unwrap();
+        let a = fs.lookup_attr(one.ino, OsStr::new("a")).unwrap();
+        let f = fs.lookup_attr(a.ino, OsStr::new("f")).unwrap();
+        fs.open_file(f.ino).unwrap();
+        assert_eq!(fs.read_data(f.ino, 0, 64).unwrap(), b"first");
+
+        write_archive(
+            &dir.path().join("one.pna"),
+            &[("a/f", b"rewritten"), ("a/new", b"")],
+        );
+        // Within the TTL the loaded tree still answers.
+        assert_eq!(
+            fs.lookup_attr(a.ino, OsStr::new("new")).unwrap_err().code(),
+            libc::ENOENT
+        );
+        {
+            let state = lock(&fs.state);
+            let archive = &state.archives[&state.by_name[OsStr::new("one")]].archive;
+            let mut loaded = lock(&archive.loaded);
+            let loaded = loaded.as_mut().unwrap();
+            loaded.checked = loaded.checked.checked_sub(TTL).unwrap();
+        }
+        fs.lookup_attr(a.ino, OsStr::new("new")).unwrap();
+        assert_eq!(fs.lookup_attr(a.ino, OsStr::new("f")).unwrap().ino, f.ino);
+        assert_eq!(fs.read_data(f.ino, 0, 64).unwrap(), b"rewritten");
+
+        // The open file still counts against the reloaded archive.
+        unload_idle(&fs.state, Duration::ZERO);
+        assert!(is_loaded(&fs, "one"));
+        fs.release_file(f.ino);
+        unload_idle(&fs.state, Duration::ZERO);
+        assert!(!is_loaded(&fs, "one"));
+    }
+
+    #[test]
+    fn forgotten_inodes_and_archives_are_dropped() {
+        let (_dir, fs) = dir_fs();
+        let one = fs
+            .lookup_attr(INodeNo(ROOT_INODE), OsStr::new("one"))
+            .unwrap();
+        let a = fs.lookup_attr(one.ino, OsStr::new("a")).unwrap();
+        assert_eq!(fs.lookup_attr(one.ino, OsStr::new("a")).unwrap().ino, a.ino);
+        // Listing hands out no numbers of its own.
+        let listed = fs.listing(a.ino).unwrap();
+        assert_eq!(listed, [("f".into(), UNKNOWN_INO, FileType::RegularFile)]);
+
+        let sizes = |fs: &ArchiveDirFS| {
+            let state = lock(&fs.state);
+            (state.archives.len(), state.keys.len(), state.inodes.len())
+        };
+        assert_eq!(sizes(&fs), (1, 2, 2));
+        lock(&fs.state).forget(a.ino.0, 1);
+        assert_eq!(sizes(&fs), (1, 2, 2), "looked up twice");
+        lock(&fs.state).forget(a.ino.0, 1);
+        assert_eq!(sizes(&fs), (1, 1, 1));
+        assert_eq!(fs.attr(a.ino).unwrap_err().code(), libc::ENOENT);
+        lock(&fs.state).forget(one.ino.0, 1);
+        assert_eq!(sizes(&fs), (0, 0, 0));
+        assert!(lock(&fs.state).by_name.is_empty());
+    }
+
+    #[test]
+    fn a_busy_archive_holds_up_only_itself() {
+        let (_dir, fs) = dir_fs();
+        let root = INodeNo(ROOT_INODE);
+        let one = fs.lookup_attr(root, OsStr::new("one")).unwrap();
+        let two = fs.lookup_attr(root, OsStr::new("two")).unwrap();
+        let archive = lock(&fs.state).key(one.ino).unwrap().1;
+        // As while "one" is loading.
+        let _busy = lock(&archive.loaded);
+        assert_eq!(fs.attr(one.ino).unwrap().kind, FileType::Directory);
+        let g = fs.lookup_attr(two.ino, OsStr::new("g")).unwrap();
+        assert_eq!(fs.read_data(g.ino, 0, 64).unwrap(), b"second");
+        unload_idle(&fs.state, Duration::ZERO);
+        assert!(!is_loaded(&fs, "two"));
+    }
+}
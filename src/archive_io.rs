@@ -1,18 +1,23 @@
 use crate::file_tree::{
-    CipherConfig, DirContent, FileData, FileTree, FsContent, FsNode, ROOT_INODE, get_gid, get_uid,
-    make_dir_node,
+    CipherConfig, DirContent, FileData, FileTree, FsContent, FsNode, HiddenEntry, Inode, NodeRef,
+    ROOT_INODE,
 };
+use crate::holes::Holes;
+use crate::id_map::{ArchivedOwner, IdMap, OwnerResolution};
+use crate::path_filter::PathFilter;
 use fuser::{FileAttr, FileType, INodeNo};
 #[allow(deprecated)]
 use pna::Permission;
 use pna::{
-    Archive, DataKind, EntryName, EntryReference, ExtendedAttribute, HardLinkEntryBuilder,
-    HashAlgorithm, Metadata, NormalEntry, OpaqueEntryBuilder, ReadEntry, ReadOptions, WriteOptions,
-    XattrName, XattrValue,
+    Archive, Chunk, ChunkType, DataKind, EntryName, EntryReference, ExtendedAttribute,
+    HardLinkEntryBuilder, HashAlgorithm, Metadata, NormalEntry, OpaqueEntryBuilder, RawChunk,
+    ReadEntry, ReadOptions, WriteOptions, XattrName, XattrValue,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hasher};
 use std::io::{Read, Write as IoWrite};
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::atomic::AtomicU32;
 use std::time::{Duration, SystemTime};
@@ -83,20 +88,166 @@ pub(crate) fn cleanup_stale_tmp(archive_path: &Path) {
     }
 }
 
-/// Load a PNA archive from `archive_path`, optionally decrypting with `password`.
+/// Identity of an on-disk archive at a point in time: enough to tell
+/// whether some other tool (the `pna` CLI, rsync, an editor) replaced or
+/// rewrote the archive behind a `--write` mount's back.
+///
+/// `dev` / `ino` catch a rename-over, `size` / `mtime` catch the common
+/// in-place rewrite cheaply, and `hash` catches the rest — a rewrite that
+/// lands within the filesystem's mtime granularity with the same length.
+/// The hash only has to be stable within one process, so the std hasher
+/// is sufficient.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ArchiveFingerprint {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: SystemTime,
+    hash: u64,
+}
+
+impl ArchiveFingerprint {
+    fn new(metadata: &fs::Metadata, hash: u64) -> io::Result<Self> {
+        Ok(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.len(),
+            mtime: metadata.modified()?,
+            hash,
+        })
+    }
+
+    /// Fingerprint the archive currently at `path`, or `None` when there
+    /// is no file there at all.
+    #[cfg(test)]
+    pub(crate) fn of_path(path: &Path) -> io::Result<Option<Self>> {
+        let mut file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let metadata = file.metadata()?;
+        Self::new(&metadata, hash_file(&mut file)?).map(Some)
+    }
+
+    /// Whether the archive at `path` is still the one fingerprinted, or
+    /// `None` when there is no file there at all. The archive is only
+    /// read and hashed when device, inode, size and mtime all agree, so
+    /// an archive that visibly changed costs a `stat`.
+    pub(crate) fn matches_path(&self, path: &Path) -> io::Result<Option<bool>> {
+        let mut file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let current = Self::new(&file.metadata()?, self.hash)?;
+        if current != *self {
+            return Ok(Some(false));
+        }
+        Ok(Some(hash_file(&mut file)? == self.hash))
+    }
+}
+
+fn hash_file(file: &mut fs::File) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+/// `Write` adapter that hashes every byte on its way to the tmp file, so
+/// `save` can fingerprint the archive it produced without reading it back.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: DefaultHasher,
+}
+
+impl<W: IoWrite> IoWrite for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.write(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// How [`load_with`] turns archive metadata into attributes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LoadOptions {
+    /// Owner mapping, reversed again on save.
+    pub ids: IdMap,
+    /// Mode of non-directory entries without permission metadata.
+    pub file_mode: u16,
+    /// Mode of directories without permission metadata, including ones
+    /// the archive only implies through the paths of other entries.
+    pub dir_mode: u16,
+    /// Directory shown as the mount root (`--subdir`).
+    pub subdir: Option<PathBuf>,
+    /// Entries shown in the mount; the others are carried through save.
+    pub filter: PathFilter,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            ids: IdMap::default(),
+            file_mode: 0o775,
+            dir_mode: 0o775,
+            subdir: None,
+            filter: PathFilter::default(),
+        }
+    }
+}
+
+/// Load a PNA archive from `archive_path`, optionally decrypting with
+/// `password`, with default [`LoadOptions`].
+#[cfg(test)]
 pub(crate) fn load(archive_path: &Path, password: Option<String>) -> io::Result<FileTree> {
+    load_with(archive_path, password, LoadOptions::default())
+}
+
+/// Load a PNA archive from `archive_path`, optionally decrypting with
+/// `password`. The tree keeps `options` so that [`save`] can map owners
+/// back and a `--watch` reload can apply them again.
+pub(crate) fn load_with(
+    archive_path: &Path,
+    password: Option<String>,
+    options: LoadOptions,
+) -> io::Result<FileTree> {
     cleanup_stale_tmp(archive_path);
 
-    let data = fs::read(archive_path)?;
+    // Stat and read through one fd so the fingerprint describes exactly
+    // the bytes that were decoded, even if the path is replaced meanwhile.
+    let mut file = fs::File::open(archive_path)?;
+    let metadata = file.metadata()?;
+    let mut data = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut data)?;
+    drop(file);
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&data);
+    let origin = ArchiveFingerprint::new(&metadata, hasher.finish())?;
 
     // Derive password bytes before moving `password` into the tree.
     let password_bytes: Option<Vec<u8>> = password.as_deref().map(|s| s.as_bytes().to_vec());
 
     let mut archive = Archive::read_header_from_slice(&data)?;
 
+    let subdir = options.subdir.clone();
+    let filter = options.filter.clone();
     let mut tree = FileTree::new(archive_path.to_path_buf(), password);
+    tree.set_origin(origin);
+    tree.set_load_options(options);
 
-    let root = make_dir_node(ROOT_INODE, ".".into());
+    let root = tree.synthesized_dir(ROOT_INODE, ".".into());
     tree.insert_node(root, None)?;
 
     let pw = password_bytes.as_deref();
@@ -105,22 +256,35 @@ pub(crate) fn load(archive_path: &Path, password: Option<String>) -> io::Result<
     // deferred because their source path may appear later in the archive
     // (or itself be another hardlink).
     let mut pending_hardlinks: Vec<PendingHardlink> = Vec::new();
+    // Filtered-out entries, in archive order, with the shown entry each
+    // followed; they are filed once every shown entry is in the tree.
+    let mut hidden: Vec<(usize, NormalEntry<Vec<u8>>, Option<PathBuf>)> = Vec::new();
+    let mut last_shown: Option<PathBuf> = None;
+    let mut index = 0;
+    let mut add = |tree: &mut FileTree, entry: NormalEntry<Vec<u8>>| -> io::Result<()> {
+        index += 1;
+        if !shown(&filter, &entry) {
+            hidden.push((index, entry, last_shown.clone()));
+            return Ok(());
+        }
+        let path = entry.header().path().as_path().to_path_buf();
+        if let Some(mut p) = add_normal_entry(tree, entry, pw)? {
+            p.index = index;
+            p.after = last_shown.clone();
+            pending_hardlinks.push(p);
+        }
+        last_shown = Some(path);
+        Ok(())
+    };
 
     for entry in archive.entries_slice() {
         let entry = entry?;
         match entry {
-            ReadEntry::Normal(e) => {
-                let owned: NormalEntry<Vec<u8>> = e.into();
-                if let Some(p) = add_normal_entry(&mut tree, owned, pw)? {
-                    pending_hardlinks.push(p);
-                }
-            }
+            ReadEntry::Normal(e) => add(&mut tree, e.into())?,
             ReadEntry::Solid(solid) => {
                 let solid_opts = ReadOptions::with_password(pw);
                 for e in solid.entries(&solid_opts)? {
-                    if let Some(p) = add_normal_entry(&mut tree, e?, pw)? {
-                        pending_hardlinks.push(p);
-                    }
+                    add(&mut tree, e?)?;
                 }
             }
         }
@@ -138,7 +302,12 @@ pub(crate) fn load(archive_path: &Path, password: Option<String>) -> io::Result<
         }
         pending_hardlinks = still_pending;
         if pending_hardlinks.len() == before {
-            for p in &pending_hardlinks {
+            for p in pending_hardlinks {
+                // The source was filtered out, so the link is too.
+                if !filter.shows(&p.source_path, false) {
+                    hidden.push((p.index, p.entry, p.after));
+                    continue;
+                }
                 log::warn!(
                     "load: dropping hardlink '{}' -> '{}': source not found in archive",
                     p.link_path.display(),
@@ -149,16 +318,51 @@ pub(crate) fn load(archive_path: &Path, password: Option<String>) -> io::Result<
         }
     }
 
+    hidden.sort_by_key(|(index, _, _)| *index);
+    for (_, entry, after) in hidden {
+        let source = if entry.header().data_kind() == DataKind::HARD_LINK {
+            Some(link_source(&entry, pw)?)
+        } else {
+            None
+        };
+        tree.hide(entry, after.as_deref(), source.as_deref());
+    }
+
     tree.recompute_directory_nlinks();
+    if let Some(subdir) = subdir {
+        tree.reroot(&subdir)?;
+    }
     Ok(tree)
 }
 
+/// Whether `filter` shows `entry`, judged by its header alone.
+fn shown(filter: &PathFilter, entry: &NormalEntry<Vec<u8>>) -> bool {
+    let header = entry.header();
+    filter.shows(
+        header.path().as_path(),
+        header.data_kind() == DataKind::DIRECTORY,
+    )
+}
+
 /// A `DataKind::HardLink` entry deferred for resolution after pass 1.
 struct PendingHardlink {
     link_path: std::path::PathBuf,
     source_path: std::path::PathBuf,
     /// Used to bump the source inode's `ctime` (POSIX: link updates it).
     mtime: SystemTime,
+    /// The entry itself, hidden along with a filtered-out source.
+    entry: NormalEntry<Vec<u8>>,
+    /// Its position in the archive, and the shown entry before it.
+    index: usize,
+    after: Option<PathBuf>,
+}
+
+/// The archive path a `DataKind::HardLink` entry refers to.
+fn link_source(entry: &NormalEntry<Vec<u8>>, password: Option<&[u8]>) -> io::Result<PathBuf> {
+    let opts = ReadOptions::with_password(password);
+    let mut buf = Vec::new();
+    entry.reader(&opts)?.read_to_end(&mut buf)?;
+    Ok(PathBuf::from(String::from_utf8_lossy(&buf).into_owned()))
 }
 
 fn resolve_hardlink(tree: &mut FileTree, p: &PendingHardlink) -> io::Result<bool> {
@@ -170,10 +374,10 @@ fn resolve_hardlink(tree: &mut FileTree, p: &PendingHardlink) -> io::Result<bool
     // archive whose hardlink entry points at a dir path would otherwise
     // create a directory cycle on load. Drop and warn — caller treats
     // this as a non-fatal corruption signal.
-    if matches!(
-        tree.get(source_ino).map(|n| &n.content),
-        Some(FsContent::Directory(_))
-    ) {
+    if tree
+        .get(source_ino)
+        .is_some_and(|n| matches!(n.content, FsContent::Directory(_)))
+    {
         log::warn!(
             "load: refusing hardlink '{}' -> '{}': source is a directory",
             p.link_path.display(),
@@ -228,17 +432,16 @@ fn add_normal_entry(
     let entry_path = header.path().as_path().to_path_buf();
 
     if header.data_kind() == DataKind::HARD_LINK {
-        let opts = ReadOptions::with_password(password);
-        let mut buf = Vec::new();
-        entry.reader(&opts)?.read_to_end(&mut buf)?;
-        let source_str = String::from_utf8_lossy(&buf).into_owned();
         let mtime = metadata
             .modified()
             .map_or(now, |d| SystemTime::UNIX_EPOCH + d);
         return Ok(Some(PendingHardlink {
             link_path: entry_path,
-            source_path: std::path::PathBuf::from(source_str),
+            source_path: link_source(&entry, password)?,
             mtime,
+            entry,
+            index: 0,
+            after: None,
         }));
     }
 
@@ -251,8 +454,19 @@ fn add_normal_entry(
         None => return Ok(None),
     };
 
-    let ino = tree.next_inode();
+    // If a node with this name already exists under parent (e.g. incremental
+    // archives), it is updated in place below, reusing the existing inode.
+    let existing_ino = tree.lookup_child(parent_ino, &name).map(|n| n.attr.ino.0);
+    let ino = match existing_ino {
+        Some(ino) => ino,
+        None => tree.alloc_inode(parent_ino, &name),
+    };
 
+    let default_mode = if header.data_kind() == DataKind::DIRECTORY {
+        tree.load_options().dir_mode
+    } else {
+        tree.load_options().file_mode
+    };
     let mut attr = FileAttr {
         ino: INodeNo(ino),
         size: 0, // Corrected below after decoding entry data
@@ -289,21 +503,27 @@ fn add_normal_entry(
         #[allow(deprecated)]
         perm: metadata
             .permission()
-            .map_or(0o775, pna::Permission::permissions),
+            .map_or(default_mode, pna::Permission::permissions),
         nlink: 1,
         #[allow(deprecated)]
-        uid: get_uid(metadata.permission()),
+        uid: tree.ids().uid(metadata.permission()),
         #[allow(deprecated)]
-        gid: get_gid(metadata.permission()),
+        gid: tree.ids().gid(metadata.permission()),
         rdev: 0,
         blksize: 512,
         flags: 0,
     };
 
+    #[allow(deprecated)]
+    let archived_owner = metadata
+        .permission()
+        .map(|p| ArchivedOwner::new(p, attr.uid, attr.gid));
+
     let cipher = CipherConfig::from_entry_header(header);
 
     let opts = ReadOptions::with_password(password);
 
+    let mut holes = Holes::default();
     let content = match header.data_kind() {
         DataKind::DIRECTORY => FsContent::Directory(crate::file_tree::DirContent::new()),
         DataKind::SYMBOLIC_LINK => {
@@ -323,6 +543,10 @@ fn add_normal_entry(
             // source of the true file size is the decoded data itself.
             let mut buf = Vec::new();
             entry.reader(&opts)?.read_to_end(&mut buf)?;
+            if let Some(map) = entry.extra_chunks().iter().find(|c| c.ty() == SPARSE_MAP) {
+                holes = decode_sparse_map(map.data())?;
+                buf = unpack_sparse(buf, &holes)?;
+            }
             attr.size = buf.len() as u64;
             FsContent::File(FileData::Clean { data: buf, cipher })
         }
@@ -344,15 +568,11 @@ fn add_normal_entry(
         attr,
         content,
         xattrs,
+        holes,
+        archived_owner,
         open_count: AtomicU32::new(0),
     };
 
-    // If a node with this name already exists under parent (e.g. incremental
-    // archives), update it in-place reusing the existing inode.
-    let existing_ino = tree
-        .lookup_child(parent_ino, &node.name)
-        .map(|n| n.attr.ino.0);
-
     if let Some(existing) = existing_ino {
         if let Some(existing_node) = tree.get_mut(existing) {
             // When replacing an existing directory with another directory entry,
@@ -384,6 +604,8 @@ fn add_normal_entry(
                 },
                 content: preserved.unwrap_or(node.content),
                 xattrs: node.xattrs,
+                holes: node.holes,
+                archived_owner: node.archived_owner,
                 open_count: AtomicU32::new(preserved_open),
             };
         }
@@ -398,7 +620,20 @@ fn add_normal_entry(
 ///
 /// Writes to a temporary file `.{stem}.tmp.{pid}`, finalizes, calls `sync_all()`,
 /// then renames the temporary file over the original archive path.
-pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
+///
+/// Just before the rename the archive on disk is compared against
+/// [`FileTree::origin`]. If another process changed it since it was
+/// loaded (or last saved), the new archive is moved to a conflict copy
+/// next to it (see [`conflict_path`]) instead of clobbering those
+/// changes, and an error naming both files is returned.
+///
+/// On success returns the fingerprint of the archive just written; the
+/// caller records it with [`FileTree::set_origin`] so the next save
+/// compares against this one.
+///
+/// Walks the tree with [`FileTree::collect_dfs`], so the caller must hold
+/// the tree's write lock.
+pub(crate) fn save(tree: &FileTree) -> io::Result<ArchiveFingerprint> {
     let archive_path = tree.archive_path();
 
     // Password guard via collect_dfs
@@ -432,13 +667,23 @@ pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
 
     let tmp_file = fs::File::create(&tmp_path)?;
 
-    let result = (|| -> io::Result<()> {
-        let mut archive = Archive::write_header(tmp_file)?;
+    let result = (|| -> io::Result<ArchiveFingerprint> {
+        let mut archive = Archive::write_header(HashingWriter {
+            inner: tmp_file,
+            hasher: DefaultHasher::new(),
+        })?;
 
         // Track which inodes have already been written as their primary
         // (File / Symlink) entry. Subsequent occurrences of the same inode
         // are written as HardLink entries that reference the primary path.
-        let mut primary_path: HashMap<crate::file_tree::Inode, String> = HashMap::new();
+        let mut primary_path: HashMap<Inode, String> = HashMap::new();
+
+        // Filtered-out entries follow the shown entry they followed in
+        // the archive (see `place_hidden`).
+        let mut hidden = place_hidden(tree, &nodes);
+        if let Some(leading) = hidden.remove(&None) {
+            write_hidden(&mut archive, leading)?;
+        }
 
         for (ino, node, archive_path_str) in &nodes {
             let entry_name = EntryName::from_lossy(archive_path_str);
@@ -447,7 +692,7 @@ pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
                 FsContent::Directory(_) => {
                     #[allow(deprecated)]
                     let builder = OpaqueEntryBuilder::new_dir(entry_name);
-                    finalize_primary_entry(&mut archive, builder, node)?;
+                    finalize_primary_entry(&mut archive, builder, node, tree.ids())?;
                 }
                 FsContent::Symlink(target) => {
                     if let Some(original) = primary_path.get(ino) {
@@ -472,7 +717,7 @@ pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
                         let reference = EntryReference::from_path_lossy_preserve_root(&target_path);
                         #[allow(deprecated)]
                         let builder = OpaqueEntryBuilder::new_symlink(entry_name, reference)?;
-                        finalize_primary_entry(&mut archive, builder, node)?;
+                        finalize_primary_entry(&mut archive, builder, node, tree.ids())?;
                     }
                 }
                 FsContent::File(fc) => {
@@ -489,8 +734,19 @@ pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
                         let write_opts = build_write_options(fc, tree.password())?;
                         #[allow(deprecated)]
                         let mut builder = OpaqueEntryBuilder::new_file(entry_name, write_opts)?;
-                        builder.write_all(fc.data())?;
-                        finalize_primary_entry(&mut archive, builder, node)?;
+                        let data = fc.data();
+                        let mut holes = node.holes.clone();
+                        holes.truncate(data.len() as u64);
+                        if tree.stores_holes() && !holes.is_empty() {
+                            builder.add_extra_chunk(RawChunk::from_data(
+                                SPARSE_MAP,
+                                encode_sparse_map(&holes),
+                            ));
+                            builder.write_all(&pack_sparse(data, &holes))?;
+                        } else {
+                            builder.write_all(data)?;
+                        }
+                        finalize_primary_entry(&mut archive, builder, node, tree.ids())?;
                     }
                 }
                 FsContent::Special(sf) => {
@@ -521,13 +777,33 @@ pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
                     );
                 }
             }
+            if let Some(following) = hidden.remove(&Some(*ino)) {
+                write_hidden(&mut archive, following)?;
+            }
         }
 
         // Finalize returns the inner writer so we can sync before rename.
-        let inner = archive.finalize()?;
+        let HashingWriter { inner, hasher } = archive.finalize()?;
         inner.sync_all()?;
+        let written = ArchiveFingerprint::new(&inner.metadata()?, hasher.finish())?;
         drop(inner);
 
+        // Re-check as late as possible so the window in which an external
+        // write can still be lost is just this check and the rename.
+        if let Some(origin) = tree.origin()
+            && origin.matches_path(archive_path)? != Some(true)
+        {
+            let conflict = conflict_path(archive_path);
+            fs::rename(&tmp_path, &conflict)?;
+            return Err(io::Error::other(format!(
+                "archive {} was modified by another process since it was loaded; \
+                 refusing to overwrite it, changes were saved to {} instead \
+                 (rewritten with the latest state on every further save of this mount)",
+                archive_path.display(),
+                conflict.display(),
+            )));
+        }
+
         fs::rename(&tmp_path, archive_path)?;
         // The parent-dir fsync is what makes the rename durable across a
         // crash. Failing to open or sync it leaves the rename only in
@@ -545,7 +821,7 @@ pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
                 Err(e) => log::error!("save: cannot open parent dir {parent_dir:?}: {e}"),
             }
         }
-        Ok(())
+        Ok(written)
     })();
 
     if result.is_err() {
@@ -555,6 +831,121 @@ pub(crate) fn save(tree: &FileTree) -> io::Result<()> {
     result
 }
 
+/// Where `save` puts the new archive when the original changed underneath
+/// the mount: `{name}.conflict.{pid}` next to the archive. Deliberately not
+/// dot-prefixed (so the user notices it) and outside the `.{name}.tmp.`
+/// family, so `cleanup_stale_tmp` never deletes it. Repeated conflicting
+/// saves from one mount overwrite the same copy, which always holds that
+/// mount's latest state.
+fn conflict_path(archive_path: &Path) -> PathBuf {
+    let mut name = archive_path
+        .file_name()
+        .unwrap_or(archive_path.as_os_str())
+        .to_owned();
+    name.push(format!(".conflict.{}", process::id()));
+    archive_path.with_file_name(name)
+}
+
+/// A hidden entry as [`save`] writes it.
+struct PlacedHidden<'a> {
+    entry: &'a NormalEntry<Vec<u8>>,
+    /// Its archive path now.
+    path: String,
+    /// For a hard link whose source moved, the source's path now.
+    moved_source: Option<String>,
+}
+
+/// Where [`save`] writes each hidden entry, by the node it follows
+/// (`None`: the start of the archive), in archive order. An entry follows
+/// the shown entry it followed at load, or else the directory it is filed
+/// under, and takes that directory's current path. One whose path now
+/// names a shown node is superseded by it and dropped; hidden links to it
+/// are written as copies of it instead, so they keep its data rather than
+/// pick up the node's.
+fn place_hidden<'a>(
+    tree: &'a FileTree,
+    nodes: &[(Inode, NodeRef<'_>, String)],
+) -> HashMap<Option<Inode>, Vec<PlacedHidden<'a>>> {
+    let mut paths: HashMap<Inode, &str> = HashMap::new();
+    for (ino, _, path) in nodes {
+        paths.entry(*ino).or_insert(path);
+    }
+    paths.insert(tree.archive_root(), "");
+
+    let mut all = Vec::new();
+    let mut moved: HashMap<&str, String> = HashMap::new();
+    for (&dir, entries) in tree.hidden() {
+        for hidden in entries {
+            let original = hidden.entry.header().path().as_str();
+            let rel = hidden.rel.to_string_lossy();
+            let path = match paths.get(&dir) {
+                Some(&"") => rel.into_owned(),
+                Some(dir) => format!("{dir}/{rel}"),
+                None => original.to_owned(),
+            };
+            moved.insert(original, path.clone());
+            all.push((dir, hidden, path));
+        }
+    }
+    all.sort_by_key(|(_, hidden, _)| hidden.seq);
+
+    let shown: HashSet<&str> = nodes.iter().map(|(_, _, path)| path.as_str()).collect();
+    let mut superseded: HashMap<&str, &HiddenEntry> = HashMap::new();
+    let mut placed: HashMap<Option<Inode>, Vec<PlacedHidden<'a>>> = HashMap::new();
+    for (dir, hidden, path) in all {
+        // Sources precede their links, so a superseded one is known here.
+        let written = hidden
+            .source
+            .as_ref()
+            .filter(|source| source.node.is_none())
+            .and_then(|source| superseded.get(source.path.as_str()).copied())
+            .unwrap_or(hidden);
+        if shown.contains(path.as_str()) {
+            superseded.insert(hidden.entry.header().path().as_str(), written);
+            continue;
+        }
+        let moved_source = written.source.as_ref().and_then(|source| {
+            let now = match source.node {
+                Some(node) => paths.get(&node).map(ToString::to_string),
+                None => moved.get(source.path.as_str()).cloned(),
+            }?;
+            (now != source.path).then_some(now)
+        });
+        let after = match hidden.after {
+            Some(after) if paths.contains_key(&after) => Some(after),
+            _ => (dir != tree.archive_root() && paths.contains_key(&dir)).then_some(dir),
+        };
+        placed.entry(after).or_default().push(PlacedHidden {
+            entry: &written.entry,
+            path,
+            moved_source,
+        });
+    }
+    placed
+}
+
+/// Write hidden entries: verbatim, but renamed if their directory was, and
+/// a hard link rebuilt if its source moved.
+fn write_hidden<W: IoWrite>(
+    archive: &mut Archive<W>,
+    placed: Vec<PlacedHidden<'_>>,
+) -> io::Result<()> {
+    for hidden in placed {
+        let name = EntryName::from_lossy(&hidden.path);
+        if let Some(source) = hidden.moved_source {
+            let reference = EntryReference::from_path_lossy_preserve_root(Path::new(&source));
+            let mut builder = HardLinkEntryBuilder::new(name, reference)?;
+            builder.metadata(hidden.entry.metadata().clone());
+            archive.add_entry(builder.build()?)?;
+        } else if hidden.path == hidden.entry.header().path().as_str() {
+            archive.add_entry(hidden.entry.clone())?;
+        } else {
+            archive.add_entry(hidden.entry.clone().with_name(name))?;
+        }
+    }
+    Ok(())
+}
+
 /// Write a `DataKind::HardLink` entry referencing an existing primary path.
 fn write_hardlink_entry<W: IoWrite>(
     archive: &mut Archive<W>,
@@ -580,6 +971,105 @@ fn write_hardlink_entry<W: IoWrite>(
     Ok(())
 }
 
+/// Private ancillary chunk listing a sparse file's holes as big-endian
+/// `(offset, length)` `u64` pairs; the entry's data then holds only the
+/// bytes outside them. PNA has no sparse-file support of its own, and
+/// other PNA readers skip ancillary chunks they don't know, so they would
+/// see such a file with its holes cut out. That is why it is only written
+/// under `--sparse`, while `load` always honours it.
+const SPARSE_MAP: ChunkType = match ChunkType::private(*b"spAr") {
+    Ok(ty) => ty,
+    Err(_) => panic!("invalid private chunk type"),
+};
+
+/// Largest file a sparse map may expand to. pnafs keeps file contents in
+/// memory, so anything near this is already unusable; the map comes from
+/// the archive and must not be able to demand an arbitrary allocation.
+const MAX_SPARSE_SIZE: u64 = 1 << 36;
+
+fn encode_sparse_map(holes: &Holes) -> Vec<u8> {
+    holes
+        .iter()
+        .flat_map(|(start, end)| [start.to_be_bytes(), (end - start).to_be_bytes()])
+        .flatten()
+        .collect()
+}
+
+fn decode_sparse_map(bytes: &[u8]) -> io::Result<Holes> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed sparse map chunk");
+    if !bytes.len().is_multiple_of(16) {
+        return Err(invalid());
+    }
+    let mut holes = Holes::default();
+    let mut prev_end = 0;
+    for pair in bytes.chunks_exact(16) {
+        let (start, len) = pair.split_at(8);
+        let start = u64::from_be_bytes(start.try_into().map_err(|_| invalid())?);
+        let len = u64::from_be_bytes(len.try_into().map_err(|_| invalid())?);
+        let end = start.checked_add(len).ok_or_else(invalid)?;
+        // Ordered, disjoint and non-empty, as `encode_sparse_map` writes them.
+        if len == 0 || start < prev_end || end > MAX_SPARSE_SIZE {
+            return Err(invalid());
+        }
+        holes.punch(start, end);
+        prev_end = end;
+    }
+    Ok(holes)
+}
+
+/// The bytes of `data` outside `holes`, which must lie within `data`.
+fn pack_sparse(data: &[u8], holes: &Holes) -> Vec<u8> {
+    let mut packed = Vec::new();
+    let mut pos = 0;
+    for (start, end) in holes.iter() {
+        packed.extend_from_slice(&data[pos..start as usize]);
+        pos = end as usize;
+    }
+    packed.extend_from_slice(&data[pos..]);
+    packed
+}
+
+/// Inverse of [`pack_sparse`]: re-insert each hole as zeros.
+///
+/// The map comes from the archive, so it is checked against the stored
+/// bytes first: every stretch of data between two holes must be there.
+/// Only then is the file's size trusted enough to allocate it.
+fn unpack_sparse(packed: Vec<u8>, holes: &Holes) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "sparse map exceeds entry data");
+    let mut stored = 0u64;
+    let mut pos = 0u64;
+    for (start, end) in holes.iter() {
+        stored = stored
+            .checked_add(start.checked_sub(pos).ok_or_else(invalid)?)
+            .filter(|&stored| stored <= packed.len() as u64)
+            .ok_or_else(invalid)?;
+        pos = end;
+    }
+    let size = pos
+        .checked_add(packed.len() as u64 - stored)
+        .filter(|&size| size <= MAX_SPARSE_SIZE)
+        .ok_or_else(invalid)?;
+    let size = usize::try_from(size).map_err(|_| invalid())?;
+
+    let mut data = Vec::new();
+    data.try_reserve_exact(size).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!("sparse file of {size} bytes: {e}"),
+        )
+    })?;
+    let mut rest = packed.as_slice();
+    for (start, end) in holes.iter() {
+        // Both fit in `size`, checked above.
+        let (chunk, tail) = rest.split_at(start as usize - data.len());
+        data.extend_from_slice(chunk);
+        data.resize(end as usize, 0);
+        rest = tail;
+    }
+    data.extend_from_slice(rest);
+    Ok(data)
+}
+
 /// Build `WriteOptions` for `fc`, given the mount-level password.
 ///
 /// The mount-password default (`CipherConfig::default_for_password`) only
@@ -634,10 +1124,11 @@ fn finalize_primary_entry<W: IoWrite>(
     archive: &mut Archive<W>,
     mut builder: OpaqueEntryBuilder,
     node: &FsNode,
+    ids: &IdMap,
 ) -> io::Result<()> {
     builder.modified(system_time_to_pna(node.attr.mtime));
     builder.created(system_time_to_pna(node.attr.crtime));
-    builder.permission(Some(build_permission(node)));
+    builder.permission(Some(build_permission(node, ids)));
     for (name, value) in &node.xattrs {
         let xname = XattrName::try_from(name.as_str()).map_err(io::Error::other)?;
         let xvalue = XattrValue::try_from(value.as_slice()).map_err(io::Error::other)?;
@@ -647,44 +1138,82 @@ fn finalize_primary_entry<W: IoWrite>(
     Ok(())
 }
 
-#[cfg(unix)]
+/// The archive owner of `node`. A node still showing the ids it was
+/// loaded with keeps its archived ids, and under
+/// [`OwnerResolution::Archived`] or an [`IdMap`] override its archived
+/// names too, so mappings round-trip. Otherwise (new or chowned nodes)
+/// the local ids are written, reversed through `ids`' rules.
 #[allow(deprecated)]
-fn build_permission(node: &FsNode) -> Permission {
-    let uname = nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(node.attr.uid))
+fn build_permission(node: &FsNode, ids: &IdMap) -> Permission {
+    let archived = node.archived_owner.as_ref();
+    let (uid, uname) = match archived {
+        Some(a) if a.local_uid == node.attr.uid => {
+            if ids.resolution == OwnerResolution::Archived || ids.mapped_uid(Some(a.uid)).is_some()
+            {
+                (a.uid, a.uname.clone())
+            } else {
+                // Under `name` the local id is the one that belongs to
+                // the name; under `id` it is the archived id anyway.
+                (u64::from(a.local_uid), user_name(a.local_uid))
+            }
+        }
+        _ => match ids.reverse_uid(node.attr.uid) {
+            // Only the number is known on the archive's side.
+            Some(uid) => (u64::from(uid), String::new()),
+            None => (u64::from(node.attr.uid), user_name(node.attr.uid)),
+        },
+    };
+    let (gid, gname) = match archived {
+        Some(a) if a.local_gid == node.attr.gid => {
+            if ids.resolution == OwnerResolution::Archived || ids.mapped_gid(Some(a.gid)).is_some()
+            {
+                (a.gid, a.gname.clone())
+            } else {
+                (u64::from(a.local_gid), group_name(a.local_gid))
+            }
+        }
+        _ => match ids.reverse_gid(node.attr.gid) {
+            Some(gid) => (u64::from(gid), String::new()),
+            None => (u64::from(node.attr.gid), group_name(node.attr.gid)),
+        },
+    };
+    Permission::new(uid, uname, gid, gname, node.attr.perm)
+}
+
+#[cfg(unix)]
+fn user_name(uid: u32) -> String {
+    nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid))
         .ok()
         .flatten()
         .map(|u| u.name)
-        .unwrap_or_default();
-    let gname = nix::unistd::Group::from_gid(nix::unistd::Gid::from_raw(node.attr.gid))
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> String {
+    nix::unistd::Group::from_gid(nix::unistd::Gid::from_raw(gid))
         .ok()
         .flatten()
         .map(|g| g.name)
-        .unwrap_or_default();
-    Permission::new(
-        u64::from(node.attr.uid),
-        uname,
-        u64::from(node.attr.gid),
-        gname,
-        node.attr.perm,
-    )
+        .unwrap_or_default()
 }
 
 #[cfg(not(unix))]
-fn build_permission(node: &FsNode) -> Permission {
-    Permission::new(
-        node.attr.uid as u64,
-        String::new(),
-        node.attr.gid as u64,
-        String::new(),
-        node.attr.perm,
-    )
+fn user_name(_uid: u32) -> String {
+    String::new()
+}
+
+#[cfg(not(unix))]
+fn group_name(_gid: u32) -> String {
+    String::new()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::file_tree::{Owner, ROOT_INODE};
+    use crate::file_tree::{Inode, Owner, ROOT_INODE};
     use pna::{Archive, DirEntryBuilder, FileEntryBuilder, Metadata, WriteOptions};
+    use std::ffi::OsStr;
     use std::io::Write as IoWrite;
     use std::path::PathBuf;
     use tempfile::TempDir;
@@ -909,19 +1438,13 @@ mod tests {
 
         // Load: xattrs should populate FsNode.xattrs.
         let tree = load(&path, None).unwrap();
-        let (_, node) = tree
-            .children(ROOT_INODE)
-            .unwrap()
-            .find(|(n, _)| n.to_str() == Some("doc.txt"))
-            .unwrap();
-        let ino = node.attr.ino.0;
-        let xattrs = &tree.get(ino).unwrap().xattrs;
+        let ino = tree.resolve_path(Path::new("doc.txt")).unwrap();
+        let xattrs = tree.get(ino).unwrap().xattrs.clone();
         assert_eq!(xattrs.len(), 2);
         assert_eq!(xattrs.get("user.tag").unwrap(), b"red");
         assert_eq!(xattrs.get("user.note").unwrap(), b"hello");
 
         // Mark dirty and save round-trip.
-        let mut tree = tree;
         // Touch the file to flip Clean -> Dirty so save will rewrite it.
         tree.write_file(ino, 0, b"x").unwrap();
         save(&tree).unwrap();
@@ -946,13 +1469,8 @@ mod tests {
         let path = create_plain_archive(&dir, "plain.pna", &[("doc.txt", b"plaintext-bytes")]);
 
         // Mount with a password, but the archive itself is plaintext.
-        let mut tree = load(&path, Some("ignored-pw".to_string())).unwrap();
-        let (_, node) = tree
-            .children(ROOT_INODE)
-            .unwrap()
-            .find(|(n, _)| n.to_str() == Some("doc.txt"))
-            .unwrap();
-        let ino = node.attr.ino.0;
+        let tree = load(&path, Some("ignored-pw".to_string())).unwrap();
+        let ino = tree.resolve_path(Path::new("doc.txt")).unwrap();
         // Touch the file to flip Clean -> Dirty so save will rewrite it.
         tree.write_file(ino, 0, b"x").unwrap();
         save(&tree).unwrap();
@@ -989,12 +1507,7 @@ mod tests {
         let path = create_plain_archive(&dir, "stable.pna", &[("doc.txt", b"hello")]);
 
         let mut tree = load(&path, None).unwrap();
-        let (_, node) = tree
-            .children(ROOT_INODE)
-            .unwrap()
-            .find(|(n, _)| n.to_str() == Some("doc.txt"))
-            .unwrap();
-        let ino = node.attr.ino.0;
+        let ino = tree.resolve_path(Path::new("doc.txt")).unwrap();
         // Insert xattrs whose insertion order is the reverse of their
         // sort order — the difference would surface as different byte
         // output if iteration ever became insertion-order-dependent.
@@ -1058,7 +1571,7 @@ mod tests {
     fn save_loaded_modified_data() {
         let dir = TempDir::new().unwrap();
         let path = create_plain_archive(&dir, "t2.pna", &[("file.txt", b"original")]);
-        let mut tree = load(&path, None).unwrap();
+        let tree = load(&path, None).unwrap();
         let child_ino = tree
             .children(ROOT_INODE)
             .unwrap()
@@ -1082,7 +1595,7 @@ mod tests {
     fn save_modified_entries_persisted() {
         let dir = TempDir::new().unwrap();
         let path = create_plain_archive(&dir, "t3.pna", &[("a.txt", b"aaa")]);
-        let mut tree = load(&path, None).unwrap();
+        let tree = load(&path, None).unwrap();
         let child_ino = tree
             .children(ROOT_INODE)
             .unwrap()
@@ -1283,7 +1796,7 @@ mod tests {
     fn save_roundtrip_write_preserved() {
         let dir = TempDir::new().unwrap();
         let path = create_plain_archive(&dir, "rt2.pna", &[("file.txt", b"old")]);
-        let mut tree = load(&path, None).unwrap();
+        let tree = load(&path, None).unwrap();
         let child_ino = tree
             .children(ROOT_INODE)
             .unwrap()
@@ -1357,6 +1870,242 @@ mod tests {
         assert_eq!(data, b"data");
     }
 
+    #[test]
+    fn save_refuses_to_clobber_externally_modified_archive() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "ext.pna", &[("f.txt", b"mine")]);
+        let mut tree = load(&path, None).unwrap();
+        tree.create_file(
+            ROOT_INODE,
+            std::ffi::OsStr::new("new.txt"),
+            0o644,
+            Owner::new(0, 0),
+        )
+        .unwrap();
+
+        // Another tool rewrites the archive while it is mounted.
+        create_plain_archive(&dir, "ext.pna", &[("theirs.txt", b"theirs")]);
+        let external = std::fs::read(&path).unwrap();
+
+        let err = save(&tree).unwrap_err();
+        assert!(
+            err.to_string().contains("modified by another process"),
+            "error should explain the conflict, got: {err}"
+        );
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            external,
+            "the external change must not be overwritten"
+        );
+        let conflict = conflict_path(&path);
+        let saved = load(&conflict, None).unwrap();
+        assert!(
+            saved
+                .lookup_child(ROOT_INODE, std::ffi::OsStr::new("new.txt"))
+                .is_some(),
+            "the mount's changes must survive in the conflict copy"
+        );
+    }
+
+    #[test]
+    fn save_detects_deleted_archive() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "gone.pna", &[("f.txt", b"x")]);
+        let tree = load(&path, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        save(&tree).unwrap_err();
+        assert!(!path.exists());
+        assert!(conflict_path(&path).exists());
+    }
+
+    #[test]
+    fn fingerprints_compare_metadata_then_content() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "fp.pna", &[("f.txt", b"a")]);
+        let origin = ArchiveFingerprint::of_path(&path).unwrap().unwrap();
+        assert_eq!(origin.matches_path(&path).unwrap(), Some(true));
+
+        // Same inode, size and mtime, different bytes: only the hash tells.
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut &file, &bytes).unwrap();
+        file.set_modified(mtime).unwrap();
+        drop(file);
+        assert_eq!(origin.matches_path(&path).unwrap(), Some(false));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(origin.matches_path(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn recorded_origin_allows_consecutive_saves() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "twice.pna", &[("f.txt", b"a")]);
+        let mut tree = load(&path, None).unwrap();
+        let ino = tree.resolve_path(Path::new("f.txt")).unwrap();
+        tree.write_file(ino, 0, b"b").unwrap();
+        let written = save(&tree).unwrap();
+        assert_eq!(
+            ArchiveFingerprint::of_path(&path).unwrap(),
+            Some(written.clone()),
+            "the returned fingerprint must describe the archive on disk"
+        );
+        tree.set_origin(written);
+        tree.write_file(ino, 0, b"c").unwrap();
+        save(&tree).expect("our own previous save is not an external change");
+        let reloaded = load(&path, None).unwrap();
+        assert_eq!(read_first_child_data(&reloaded, ROOT_INODE), b"c");
+    }
+
+    #[test]
+    fn inode_numbers_are_stable_across_loads_and_saves() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(
+            &dir,
+            "stable.pna",
+            &[("a.txt", b"a"), ("d/b.txt", b"b"), ("d/e/c.txt", b"c")],
+        );
+        let paths = ["a.txt", "d", "d/b.txt", "d/e/c.txt"];
+        let inodes = |tree: &FileTree| -> Vec<Inode> {
+            paths
+                .iter()
+                .map(|p| tree.resolve_path(Path::new(p)).unwrap())
+                .collect()
+        };
+        let mut tree = load(&path, None).unwrap();
+        let first = inodes(&tree);
+        assert_eq!(inodes(&load(&path, None).unwrap()), first);
+
+        // A save reorders nothing that matters: same paths, same numbers,
+        // even with a newly created file in the mix.
+        tree.create_file(ROOT_INODE, OsStr::new("new.txt"), 0o644, Owner::new(0, 0))
+            .unwrap();
+        let created = tree.resolve_path(Path::new("new.txt")).unwrap();
+        save(&tree).unwrap();
+        let reloaded = load(&path, None).unwrap();
+        assert_eq!(inodes(&reloaded), first);
+        assert_eq!(reloaded.resolve_path(Path::new("new.txt")), Some(created));
+    }
+
+    #[test]
+    fn sparse_files_round_trip_without_storing_holes() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "sparse.pna", &[]);
+        let mut tree = load(&path, None).unwrap();
+        tree.set_store_holes(true);
+        let ino = tree
+            .create_file(ROOT_INODE, OsStr::new("img"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        tree.write_file(ino, 0, b"boot").unwrap();
+        tree.write_file(ino, 4 << 20, b"data").unwrap();
+        tree.set_size(ino, 8 << 20).unwrap();
+        save(&tree).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < 64 << 10);
+
+        let reloaded = load(&path, None).unwrap();
+        let ino = reloaded.resolve_path(Path::new("img")).unwrap();
+        let data = read_node_data(&reloaded, ino);
+        assert_eq!(data.len(), 8 << 20);
+        assert_eq!(&data[..4], b"boot");
+        assert_eq!(&data[4 << 20..(4 << 20) + 4], b"data");
+        assert!(data[4..4 << 20].iter().all(|&b| b == 0));
+        assert_eq!(reloaded.seek_hole_data(ino, 0, false), Ok(4));
+        assert_eq!(reloaded.seek_hole_data(ino, 4, true), Ok(4 << 20));
+        assert_eq!(
+            reloaded.seek_hole_data(ino, 4 << 20, false),
+            Ok((4 << 20) + 4)
+        );
+    }
+
+    #[test]
+    fn sparse_map_is_only_written_when_enabled() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "dense.pna", &[("f", b"x")]);
+        let tree = load(&path, None).unwrap();
+        let ino = tree.resolve_path(Path::new("f")).unwrap();
+        tree.set_size(ino, 1 << 16).unwrap();
+        save(&tree).unwrap();
+
+        // Without --sparse the zeros are stored and other readers agree.
+        let bytes = std::fs::read(&path).unwrap();
+        let mut archive = Archive::read_header_from_slice(&bytes).unwrap();
+        let entry = archive.entries_slice().next().unwrap().unwrap();
+        let ReadEntry::Normal(entry) = entry else {
+            panic!("expected a normal entry");
+        };
+        assert!(entry.extra_chunks().iter().all(|c| c.ty() != SPARSE_MAP));
+        assert_eq!(
+            read_node_data(&load(&path, None).unwrap(), ino).len(),
+            1 << 16
+        );
+    }
+
+    #[test]
+    fn sparse_map_decoding_rejects_malformed_chunks() {
+        let mut holes = Holes::default();
+        holes.punch(2, 5);
+        holes.punch(9, 10);
+        let map = encode_sparse_map(&holes);
+        assert_eq!(decode_sparse_map(&map).unwrap(), holes);
+        assert_eq!(
+            unpack_sparse(pack_sparse(b"ab___cdef_", &holes), &holes).unwrap(),
+            b"ab\0\0\0cdef\0"
+        );
+
+        assert!(decode_sparse_map(&map[..15]).is_err());
+        // Out of order.
+        let swapped = [&map[16..], &map[..16]].concat();
+        assert!(decode_sparse_map(&swapped).is_err());
+        // A hole past the stored data.
+        assert!(unpack_sparse(b"ab".to_vec(), &holes).is_err());
+    }
+
+    #[test]
+    fn sparse_map_cannot_demand_a_huge_allocation() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("evil.pna");
+        let mut archive = Archive::write_header(fs::File::create(&path).unwrap()).unwrap();
+        #[allow(deprecated)]
+        let mut builder =
+            OpaqueEntryBuilder::new_file(EntryName::from_lossy("f"), WriteOptions::store())
+                .unwrap();
+        builder.write_all(b"ab").unwrap();
+        // One exabyte-sized hole after the two stored bytes.
+        let map = [2u64.to_be_bytes(), (1u64 << 60).to_be_bytes()].concat();
+        builder.add_extra_chunk(RawChunk::from_data(SPARSE_MAP, map));
+        archive.add_entry(builder.build().unwrap()).unwrap();
+        archive.finalize().unwrap();
+
+        let Err(err) = load(&path, None) else {
+            panic!("an exabyte hole must be rejected");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{err}");
+
+        // A map that needs more stored bytes than the entry has is refused
+        // before its huge hole is allocated.
+        let mut holes = Holes::default();
+        holes.punch(16, MAX_SPARSE_SIZE);
+        assert_eq!(
+            unpack_sparse(vec![0; 2], &holes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Holes that pass the map check still cannot add up past the cap.
+        let mut holes = Holes::default();
+        holes.punch(0, MAX_SPARSE_SIZE / 2);
+        holes.punch(MAX_SPARSE_SIZE / 2 + 1, MAX_SPARSE_SIZE);
+        assert_eq!(
+            unpack_sparse(vec![0; 2], &holes).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
     /// fSIZ is only a hint and must not be trusted, so even entries created
     /// via FileEntryBuilder (which writes fSIZ) are fully decoded on load.
     #[test]
@@ -1639,4 +2388,504 @@ mod tests {
             );
         }
     }
+
+    /// Owners written by `--map-uid` / `--map-gid` mounts: untouched entries
+    /// keep their archived owner, names included; chowned ones are
+    /// mapped back.
+    #[test]
+    #[allow(deprecated)]
+    fn mapped_owners_round_trip_unless_chowned() {
+        use crate::id_map::IdPair;
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("owners.pna");
+        let mut archive = Archive::write_header(std::fs::File::create(&path).unwrap()).unwrap();
+        for (name, uid, gid) in [("kept", 1500, 1600), ("chowned", 1501, 1600)] {
+            let permission = Permission::new(
+                uid,
+                "pnafs-no-such-user".into(),
+                gid,
+                "pnafs-no-such-group".into(),
+                0o644,
+            );
+            archive
+                .write_file(
+                    EntryName::from_lossy(name),
+                    Metadata::new().with_permission(Some(permission)),
+                    WriteOptions::builder().build(),
+                    |w| w.write_all(b"x"),
+                )
+                .unwrap();
+        }
+        archive.finalize().unwrap();
+
+        let ids = IdMap {
+            map_uid: vec![IdPair {
+                from: 1500,
+                to: 4242,
+            }],
+            map_gid: vec![IdPair {
+                from: 1600,
+                to: 4343,
+            }],
+            ..IdMap::default()
+        };
+        let mut tree = load_with(
+            &path,
+            None,
+            LoadOptions {
+                ids,
+                ..LoadOptions::default()
+            },
+        )
+        .unwrap();
+        let ino = |tree: &FileTree, name: &str| {
+            tree.lookup_child(ROOT_INODE, OsStr::new(name))
+                .unwrap()
+                .attr
+                .ino
+                .0
+        };
+        let kept = tree.get(ino(&tree, "kept")).unwrap().attr;
+        assert_eq!((kept.uid, kept.gid), (4242, 4343));
+        let chowned = ino(&tree, "chowned");
+        assert_eq!(tree.get(chowned).unwrap().attr.uid, 1501);
+        tree.get_mut(chowned).unwrap().attr.uid = 4242;
+        save(&tree).unwrap();
+
+        let tree = load(&path, None).unwrap();
+        let owner = |name: &str| {
+            let archived = tree
+                .get(ino(&tree, name))
+                .unwrap()
+                .archived_owner
+                .clone()
+                .unwrap();
+            (archived.uid, archived.uname, archived.gid, archived.gname)
+        };
+        let group = "pnafs-no-such-group".to_owned();
+        assert_eq!(
+            owner("kept"),
+            (1500, "pnafs-no-such-user".to_owned(), 1600, group.clone())
+        );
+        assert_eq!(owner("chowned"), (1500, String::new(), 1600, group));
+    }
+
+    /// What each `--owner-resolution` saves for an untouched entry whose
+    /// archived name and id disagree locally.
+    #[test]
+    #[allow(deprecated)]
+    fn owner_resolution_decides_what_untouched_owners_save_as() {
+        let dir = TempDir::new().unwrap();
+        for (resolution, expected) in [
+            (OwnerResolution::Archived, (4321, "root")),
+            (OwnerResolution::Name, (0, "root")),
+            (OwnerResolution::Id, (4321, "")),
+        ] {
+            let path = dir.path().join(format!("{resolution:?}.pna"));
+            let mut archive = Archive::write_header(std::fs::File::create(&path).unwrap()).unwrap();
+            let permission = Permission::new(4321, "root".into(), 0, "".into(), 0o644);
+            archive
+                .write_file(
+                    EntryName::from_lossy("f"),
+                    Metadata::new().with_permission(Some(permission)),
+                    WriteOptions::builder().build(),
+                    |w| w.write_all(b"x"),
+                )
+                .unwrap();
+            archive.finalize().unwrap();
+
+            let ids = IdMap {
+                resolution,
+                ..IdMap::default()
+            };
+            let tree = load_with(
+                &path,
+                None,
+                LoadOptions {
+                    ids,
+                    ..LoadOptions::default()
+                },
+            )
+            .unwrap();
+            save(&tree).unwrap();
+            let tree = load(&path, None).unwrap();
+            let f = tree.lookup_child(ROOT_INODE, OsStr::new("f")).unwrap();
+            let archived = f.archived_owner.as_ref().unwrap();
+            assert_eq!(
+                (archived.uid, archived.uname.as_str()),
+                expected,
+                "{resolution:?}"
+            );
+        }
+    }
+
+    /// `--file-mode` / `--dir-mode` fill in for missing permission
+    /// metadata, including on directories implied only by paths; an
+    /// archived mode still wins.
+    #[test]
+    #[allow(deprecated)]
+    fn default_modes_apply_only_without_permission_metadata() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("modes.pna");
+        let mut archive = Archive::write_header(std::fs::File::create(&path).unwrap()).unwrap();
+        for (name, permission) in [
+            ("d/bare", None),
+            (
+                "d/kept",
+                Some(Permission::new(0, "".into(), 0, "".into(), 0o600)),
+            ),
+        ] {
+            archive
+                .write_file(
+                    EntryName::from_lossy(name),
+                    Metadata::new().with_permission(permission),
+                    WriteOptions::builder().build(),
+                    |w| w.write_all(b"x"),
+                )
+                .unwrap();
+        }
+        archive.finalize().unwrap();
+
+        let options = LoadOptions {
+            file_mode: 0o644,
+            dir_mode: 0o750,
+            ..LoadOptions::default()
+        };
+        let tree = load_with(&path, None, options).unwrap();
+        let perm = |path: &str| {
+            let ino = tree.resolve_path(Path::new(path)).unwrap();
+            tree.get(ino).unwrap().attr.perm
+        };
+        assert_eq!(perm("d/bare"), 0o644);
+        assert_eq!(perm("d/kept"), 0o600);
+        assert_eq!(perm("d"), 0o750);
+        assert_eq!(tree.get(ROOT_INODE).unwrap().attr.perm, 0o750);
+    }
+
+    #[test]
+    fn subdir_mounts_save_inside_it_and_keep_the_rest() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(
+            &dir,
+            "a.pna",
+            &[
+                ("project-1/src/a.txt", b"a"),
+                ("project-1/README", b"r"),
+                ("outside.txt", b"o"),
+            ],
+        );
+        let options = LoadOptions {
+            subdir: Some("project-1".into()),
+            ..LoadOptions::default()
+        };
+        let mut tree = load_with(&path, None, options).unwrap();
+        let names: Vec<_> = tree.children(ROOT_INODE).unwrap().map(|(n, _)| n).collect();
+        assert_eq!(names, ["README", "src"]);
+
+        let new = tree
+            .create_file(ROOT_INODE, OsStr::new("new.txt"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        tree.write_file(new, 0, b"n").unwrap();
+        tree.unlink(ROOT_INODE, OsStr::new("README")).unwrap();
+        save(&tree).unwrap();
+
+        let reloaded = load(&path, None).unwrap();
+        let paths: Vec<_> = reloaded
+            .collect_dfs()
+            .into_iter()
+            .map(|(_, _, path)| path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "outside.txt",
+                "project-1",
+                "project-1/new.txt",
+                "project-1/src",
+                "project-1/src/a.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn subdir_must_be_an_archive_directory() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "a.pna", &[("d/f", b"x")]);
+        let load_subdir = |subdir: &str| {
+            let options = LoadOptions {
+                subdir: Some(subdir.into()),
+                ..LoadOptions::default()
+            };
+            load_with(&path, None, options).map(|_| ())
+        };
+        assert_eq!(
+            load_subdir("missing").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            load_subdir("d/f").unwrap_err().kind(),
+            io::ErrorKind::NotADirectory
+        );
+        assert!(load_subdir("d/").is_ok());
+    }
+
+    #[test]
+    fn filtered_entries_are_hidden_and_saved_verbatim() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(
+            &dir,
+            "a.pna",
+            &[
+                ("inc/a.h", b"h"),
+                ("inc/internal/b.h", b"i"),
+                ("src/a.c", b"c"),
+            ],
+        );
+        {
+            // A link to a hidden file is hidden with it.
+            let mut archive = Archive::read_header(std::fs::File::open(&path).unwrap()).unwrap();
+            let mut entries: Vec<_> = archive.entries().collect::<Result<_, _>>().unwrap();
+            drop(archive);
+            let mut out = Archive::write_header(std::fs::File::create(&path).unwrap()).unwrap();
+            for entry in entries.drain(..) {
+                out.add_entry(entry).unwrap();
+            }
+            write_hardlink_entry(
+                &mut out,
+                EntryName::from_lossy("inc/c.h"),
+                "src/a.c",
+                SystemTime::UNIX_EPOCH,
+                SystemTime::UNIX_EPOCH,
+            )
+            .unwrap();
+            out.finalize().unwrap();
+        }
+        let options = LoadOptions {
+            filter: PathFilter {
+                include: vec!["*.h".parse().unwrap()],
+                exclude: vec!["internal".parse().unwrap()],
+            },
+            ..LoadOptions::default()
+        };
+        let mut tree = load_with(&path, None, options).unwrap();
+        let paths = |tree: &FileTree| -> Vec<String> {
+            tree.collect_dfs()
+                .into_iter()
+                .map(|(_, _, path)| path)
+                .collect()
+        };
+        // `src` is only implied by the hidden `src/a.c`.
+        assert_eq!(paths(&tree), ["inc", "inc/a.h"]);
+
+        let src = tree
+            .make_dir(ROOT_INODE, OsStr::new("src"), 0o755, 0, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        let new = tree
+            .create_file(src, OsStr::new("a.c"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        tree.write_file(new, 0, b"mine").unwrap();
+        save(&tree).unwrap();
+
+        let reloaded = load(&path, None).unwrap();
+        assert_eq!(
+            paths(&reloaded),
+            [
+                "inc",
+                "inc/a.h",
+                "inc/c.h",
+                "inc/internal",
+                "inc/internal/b.h",
+                "src",
+                "src/a.c"
+            ]
+        );
+        let data = |path: &str| {
+            let ino = reloaded.resolve_path(Path::new(path)).unwrap();
+            match &reloaded.get(ino).unwrap().content {
+                FsContent::File(fd) => fd.data().to_vec(),
+                _ => panic!("{path} is not a file"),
+            }
+        };
+        assert_eq!(data("inc/internal/b.h"), b"i");
+        // The file created in the mount supersedes the hidden one, and
+        // the carried link keeps the data it had rather than follow the path.
+        assert_eq!(data("src/a.c"), b"mine");
+        assert_eq!(data("inc/c.h"), b"c");
+    }
+
+    #[test]
+    fn links_to_a_superseded_hidden_link_follow_its_source() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "a.pna", &[("t.txt", b"t")]);
+        {
+            let mut archive = Archive::read_header(std::fs::File::open(&path).unwrap()).unwrap();
+            let mut entries: Vec<_> = archive.entries().collect::<Result<_, _>>().unwrap();
+            drop(archive);
+            let mut out = Archive::write_header(std::fs::File::create(&path).unwrap()).unwrap();
+            for entry in entries.drain(..) {
+                out.add_entry(entry).unwrap();
+            }
+            let t = SystemTime::UNIX_EPOCH;
+            write_hardlink_entry(&mut out, EntryName::from_lossy("h/s.o"), "t.txt", t, t).unwrap();
+            write_hardlink_entry(&mut out, EntryName::from_lossy("h/l.o"), "h/s.o", t, t).unwrap();
+            out.finalize().unwrap();
+        }
+        let options = LoadOptions {
+            filter: PathFilter {
+                include: Vec::new(),
+                exclude: vec!["*.o".parse().unwrap()],
+            },
+            ..LoadOptions::default()
+        };
+        let mut tree = load_with(&path, None, options).unwrap();
+        let h = tree
+            .make_dir(ROOT_INODE, OsStr::new("h"), 0o755, 0, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        let new = tree
+            .create_file(h, OsStr::new("s.o"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        tree.write_file(new, 0, b"mine").unwrap();
+        tree.rename(
+            ROOT_INODE,
+            OsStr::new("t.txt"),
+            ROOT_INODE,
+            OsStr::new("u.txt"),
+            fuser::RenameFlags::empty(),
+        )
+        .unwrap();
+        save(&tree).unwrap();
+
+        // `h/l.o` stands in for the superseded `h/s.o`: a link to the
+        // file that one named, wherever it went.
+        let reloaded = load(&path, None).unwrap();
+        let ino = |path: &str| reloaded.resolve_path(Path::new(path)).unwrap();
+        assert_eq!(ino("h/l.o"), ino("u.txt"));
+        assert_ne!(ino("h/s.o"), ino("u.txt"));
+        match &reloaded.get(ino("h/s.o")).unwrap().content {
+            FsContent::File(fd) => assert_eq!(fd.data(), b"mine"),
+            _ => panic!("h/s.o is not a file"),
+        }
+    }
+
+    #[test]
+    fn hidden_entries_keep_their_directory_and_position() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.pna");
+        {
+            let mut out = Archive::write_header(std::fs::File::create(&path).unwrap()).unwrap();
+            let entries = [
+                ("d/a.txt", None),
+                ("d/x.o", None),
+                ("d/b.txt", None),
+                ("d/z.o", Some("d/x.o")),
+                ("w.o", Some("d/a.txt")),
+                ("e/keep.txt", None),
+                ("e/y.o", None),
+                ("k/only.o", None),
+            ];
+            for (name, source) in entries {
+                let entry_name = EntryName::from_lossy(name);
+                let t = SystemTime::UNIX_EPOCH;
+                match source {
+                    Some(source) => write_hardlink_entry(&mut out, entry_name, source, t, t),
+                    None => out
+                        .write_file(
+                            entry_name,
+                            Metadata::new(),
+                            WriteOptions::builder().build(),
+                            |w| w.write_all(name.as_bytes()),
+                        )
+                        .map(drop),
+                }
+                .unwrap();
+            }
+            out.finalize().unwrap();
+        }
+        let options = LoadOptions {
+            filter: PathFilter {
+                include: Vec::new(),
+                exclude: vec!["*.o".parse().unwrap()],
+            },
+            ..LoadOptions::default()
+        };
+        let mut tree = load_with(&path, None, options).unwrap();
+        let owner = Owner::new(0, 0);
+
+        // `e` only holds hidden entries now: it can neither be removed
+        // nor replaced.
+        let e = tree.resolve_path(Path::new("e")).unwrap();
+        tree.unlink(e, OsStr::new("keep.txt")).unwrap();
+        assert_eq!(
+            tree.rmdir(ROOT_INODE, OsStr::new("e")),
+            Err(fuser::Errno::ENOTEMPTY)
+        );
+        tree.make_dir(ROOT_INODE, OsStr::new("f"), 0o755, 0, owner)
+            .unwrap();
+        let flags = fuser::RenameFlags::empty();
+        assert_eq!(
+            tree.rename(
+                ROOT_INODE,
+                OsStr::new("f"),
+                ROOT_INODE,
+                OsStr::new("e"),
+                flags
+            ),
+            Err(fuser::Errno::ENOTEMPTY)
+        );
+        tree.rmdir(ROOT_INODE, OsStr::new("f")).unwrap();
+
+        // Renaming `d` carries its hidden entries, and the links into it.
+        tree.rename(
+            ROOT_INODE,
+            OsStr::new("d"),
+            ROOT_INODE,
+            OsStr::new("D"),
+            flags,
+        )
+        .unwrap();
+        save(&tree).unwrap();
+
+        let mut archive = Archive::read_header(std::fs::File::open(&path).unwrap()).unwrap();
+        let names: Vec<String> = archive
+            .entries()
+            .map(|e| match e.unwrap() {
+                ReadEntry::Normal(e) => e.header().path().to_string(),
+                ReadEntry::Solid(_) => panic!("save writes no solid entries"),
+            })
+            .collect();
+        // Each hidden entry follows the shown entry it followed before,
+        // or, once that is gone, its directory or the start of the archive.
+        assert_eq!(
+            names,
+            [
+                "k/only.o", "D", "D/a.txt", "D/x.o", "D/b.txt", "D/z.o", "w.o", "e", "e/y.o"
+            ]
+        );
+        let reloaded = load(&path, None).unwrap();
+        let data = |path: &str| {
+            let ino = reloaded.resolve_path(Path::new(path)).unwrap();
+            match &reloaded.get(ino).unwrap().content {
+                FsContent::File(fd) => fd.data().to_vec(),
+                _ => panic!("{path} is not a file"),
+            }
+        };
+        assert_eq!(data("D/z.o"), b"d/x.o");
+        assert_eq!(data("w.o"), b"d/a.txt");
+        assert!(reloaded.resolve_path(Path::new("d")).is_none());
+    }
 }
@@ -1,8 +1,10 @@
 use crate::command::{
     Command, bugreport::BugReportCommand, complete::CompleteArgs, mount::MountArgs,
+    mount_dir::MountDirArgs, unmount::UnmountArgs,
 };
 use clap::{Parser, Subcommand};
 use std::io;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(
@@ -24,6 +26,8 @@ impl Command for Cli {
     fn execute(self) -> io::Result<()> {
         match self.subcommand {
             SubCommand::Mount(args) => args.execute(),
+            SubCommand::MountDir(args) => args.execute(),
+            SubCommand::Unmount(args) => args.execute(),
             SubCommand::Complete(args) => args.execute(),
             SubCommand::BugReport(cmd) => cmd.execute(),
         }
@@ -32,8 +36,21 @@ impl Command for Cli {
 
 #[derive(Subcommand)]
 pub(crate) enum SubCommand {
-    #[command(about = "Mount archive")]
-    Mount(MountArgs),
+    #[command(
+        about = "Mount archive",
+        after_help = "Options can also be given mount(8)-style, as `-o opt[,opt=value...]` with the long option names above (`-o allow_other,uid=1000`). Invoked as `mount.pnafs`, pnafs takes the arguments of a mount helper: `mount.pnafs ARCHIVE MOUNT_POINT -o OPTIONS`."
+    )]
+    Mount(Box<MountArgs>),
+    #[command(
+        about = "Mount a directory of archives, each shown as a directory",
+        long_about = "Mount a directory of archives read-only. Every NAME.pna in DIR is shown as a directory NAME, loaded when first accessed and unloaded again after --idle-timeout seconds without use."
+    )]
+    MountDir(MountDirArgs),
+    #[command(
+        about = "Unmount a mounted archive and wait until it is saved",
+        long_about = "Unmount a mounted archive and wait until it is saved. Exits with an error if the pnafs process serving the mount could not save the archive."
+    )]
+    Unmount(UnmountArgs),
     #[command(about = "Generate shell auto complete")]
     Complete(CompleteArgs),
     #[command(about = "Generate bug report template")]
@@ -47,4 +64,11 @@ pub(crate) struct PasswordArgs {
         help = "Password of archive. If password is not given it's asked from the tty"
     )]
     pub(crate) password: Option<Option<String>>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with = "password",
+        help = "Read the password of archive from the first line of FILE"
+    )]
+    pub(crate) password_file: Option<PathBuf>,
 }
@@ -1,15 +1,21 @@
 pub(crate) mod bugreport;
 pub(crate) mod complete;
 pub(crate) mod mount;
+pub(crate) mod mount_dir;
+pub(crate) mod unmount;
 
 use crate::cli::PasswordArgs;
-use std::io;
+use std::{fs, io};
 
 pub(crate) trait Command {
     fn execute(self) -> io::Result<()>;
 }
 
 fn ask_password(args: PasswordArgs) -> io::Result<Option<String>> {
+    if let Some(path) = args.password_file {
+        let contents = fs::read_to_string(path)?;
+        return Ok(Some(contents.lines().next().unwrap_or_default().to_owned()));
+    }
     Ok(match args.password {
         Some(password @ Some(_)) => {
             eprintln!("warning: Using a password on the command line interface can be insecure.");
@@ -1,14 +1,25 @@
 use crate::{
+    archive_io::LoadOptions,
     archive_lock::{ArchiveLock, LockMode},
     cli::PasswordArgs,
     command::{Command, ask_password},
-    filesystem::{PnaFS, WriteStrategy},
+    control::ControlSocket,
+    daemon::{self, Fork, PidFile},
+    filesystem::{CachePolicy, PnaFS, WriteStrategy},
+    id_map::{IdMap, IdPair, OwnerResolution},
+    path_filter::{Glob, PathFilter},
+    permissions::PermissionMode,
+    watch,
 };
 use clap::{Args, ValueHint};
-use fuser::{Config, MountOption, SessionACL, mount};
+use fuser::{Config, Filesystem, MountOption, Session, SessionACL};
 use std::fs::create_dir_all;
 use std::io;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Args)]
 pub(crate) struct MountArgs {
@@ -40,9 +51,278 @@ struct MountOptions {
         long,
         default_value = "lazy",
         requires = "write",
-        help = "When to flush: lazy (on unmount) or immediate (on file close)"
+        help = "When to flush: lazy (on unmount) or immediate (on closing a modified file)"
     )]
     write_strategy: WriteStrategy,
+    #[arg(
+        long,
+        requires = "write",
+        help = "Store sparse files without their holes, in a pnafs-specific chunk that other PNA tools ignore (they would read such files with the holes removed)"
+    )]
+    sparse: bool,
+    #[arg(
+        long,
+        conflicts_with = "write",
+        help = "Reload the archive when it is replaced or rewritten on disk (read-only mounts only)"
+    )]
+    watch: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Mount the archive directory PATH instead of the whole archive; entries outside it are saved unchanged"
+    )]
+    subdir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Only show files matching GLOB (repeatable); a GLOB without / matches file names, and a matching directory includes its contents"
+    )]
+    include: Vec<Glob>,
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Hide entries matching GLOB (repeatable), even if included; hidden entries are saved unchanged"
+    )]
+    exclude: Vec<Glob>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Number of threads serving filesystem requests [default: number of CPUs, at most 8; more than 1 is Linux only]"
+    )]
+    threads: Option<NonZeroUsize>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = parse_timeout,
+        help = "How long the kernel caches file attributes [default: 3600 read-only, 1 with --write]"
+    )]
+    attr_timeout: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = parse_timeout,
+        help = "How long the kernel caches name lookups [default: 3600 read-only, 1 with --write, 0 with --permissions pnafs]"
+    )]
+    entry_timeout: Option<Duration>,
+    #[arg(
+        long,
+        help = "Keep cached file contents across opens instead of re-reading them on every open"
+    )]
+    kernel_cache: bool,
+    #[arg(
+        long,
+        requires = "write",
+        help = "Let the kernel buffer writes in its page cache and send them in batches"
+    )]
+    writeback_cache: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "kernel",
+        help = "Who checks file permissions: kernel (against the archive's owners and modes), ignore (nobody; anyone admitted to the mount may do anything), or pnafs (pnafs itself, including supplementary groups)"
+    )]
+    permissions: PermissionMode,
+    #[arg(
+        long,
+        value_name = "UID",
+        help = "Show every entry as owned by this uid"
+    )]
+    uid: Option<u32>,
+    #[arg(
+        long,
+        value_name = "GID",
+        help = "Show every entry as owned by this gid"
+    )]
+    gid: Option<u32>,
+    #[arg(
+        long,
+        value_name = "FROM:TO",
+        help = "Show entries the archive records as owned by uid FROM as owned by TO (repeatable)"
+    )]
+    map_uid: Vec<IdPair>,
+    #[arg(
+        long,
+        value_name = "FROM:TO",
+        help = "Show entries the archive records as owned by gid FROM as owned by TO (repeatable)"
+    )]
+    map_gid: Vec<IdPair>,
+    #[arg(
+        long,
+        conflicts_with_all = ["uid", "gid"],
+        help = "Show every entry as owned by the user and group running pnafs"
+    )]
+    squash_owner: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "archived",
+        help = "What identifies an owner: name (user and group names, saved as the local ids and names), id (numeric ids, saved with their local names), or archived (names, but saved exactly as archived unless chowned)"
+    )]
+    owner_resolution: OwnerResolution,
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_parser = parse_mode,
+        default_value = "0775",
+        help = "Octal mode of files without permission metadata in the archive"
+    )]
+    file_mode: u16,
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_parser = parse_mode,
+        default_value = "0775",
+        help = "Octal mode of directories without permission metadata in the archive"
+    )]
+    dir_mode: u16,
+    #[arg(
+        long,
+        value_name = "MASK",
+        value_parser = parse_mode,
+        default_value = "0",
+        help = "Octal bits to clear from --file-mode and --dir-mode"
+    )]
+    umask: u16,
+    #[arg(
+        long,
+        help = "Ignore setuid and setgid bits, and hide them from file modes"
+    )]
+    nosuid: bool,
+    #[arg(long, help = "Do not interpret device nodes")]
+    nodev: bool,
+    #[arg(long, help = "Do not allow executing files")]
+    noexec: bool,
+    #[arg(long, help = "Do not update access times")]
+    noatime: bool,
+    #[arg(long, help = "Do all I/O to the filesystem synchronously")]
+    sync: bool,
+    #[arg(long, help = "Make directory changes synchronous")]
+    dirsync: bool,
+    #[arg(
+        short,
+        long,
+        help = "Stay in the foreground until unmounted instead of returning once mounted",
+        long_help = "Stay in the foreground until unmounted instead of returning once mounted. Only a foreground mount reports readiness to a service manager through NOTIFY_SOCKET, so use it in systemd units of Type=notify. In the background, stdout is closed once mounted, and so is stderr if it is a terminal: redirect stderr to a file to keep the warnings logged while serving."
+    )]
+    foreground: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        help = "Write the pid of the mount process to FILE, removing it on unmount"
+    )]
+    pidfile: Option<PathBuf>,
+}
+
+/// Default attribute / entry TTL of read-only mounts. Their contents only
+/// change through `--watch`, which invalidates what it replaces.
+const READ_ONLY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Default TTL of `--write` mounts, short enough that the kernel soon
+/// notices size changes it did not make itself.
+const WRITE_TTL: Duration = Duration::from_secs(1);
+
+/// Parse a non-negative, possibly fractional number of seconds.
+pub(super) fn parse_timeout(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("{s} is not a valid number of seconds"))
+}
+
+/// Parse an octal permission mode such as `644` or `0755`.
+fn parse_mode(s: &str) -> Result<u16, String> {
+    match u16::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{s} is not an octal mode between 0 and 7777")),
+    }
+}
+
+impl MountOptions {
+    fn cache_policy(&self) -> CachePolicy {
+        let default = if self.write { WRITE_TTL } else { READ_ONLY_TTL };
+        // pnafs checks search permission in `lookup`, which the kernel
+        // skips for as long as it caches an entry.
+        let entry_default = if self.permissions == PermissionMode::Pnafs {
+            Duration::ZERO
+        } else {
+            default
+        };
+        CachePolicy {
+            attr_ttl: self.attr_timeout.unwrap_or(default),
+            entry_ttl: self.entry_timeout.unwrap_or(entry_default),
+            keep_cache: self.kernel_cache,
+            writeback: self.writeback_cache,
+        }
+    }
+
+    /// Owner mapping; stored owners are kept on save unless chowned.
+    fn id_map(&self) -> IdMap {
+        let (uid, gid) = if self.squash_owner {
+            (
+                Some(nix::unistd::getuid().as_raw()),
+                Some(nix::unistd::getgid().as_raw()),
+            )
+        } else {
+            (self.uid, self.gid)
+        };
+        IdMap {
+            resolution: self.owner_resolution,
+            uid,
+            gid,
+            map_uid: self.map_uid.clone(),
+            map_gid: self.map_gid.clone(),
+        }
+    }
+
+    fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            ids: self.id_map(),
+            file_mode: self.file_mode & !self.umask,
+            dir_mode: self.dir_mode & !self.umask,
+            subdir: self.subdir.clone(),
+            filter: PathFilter {
+                include: self.include.clone(),
+                exclude: self.exclude.clone(),
+            },
+        }
+    }
+
+    /// The standard mount flags requested on the command line.
+    fn flags(&self) -> Vec<MountOption> {
+        [
+            (self.nosuid, MountOption::NoSuid),
+            (self.nodev, MountOption::NoDev),
+            (self.noexec, MountOption::NoExec),
+            (self.noatime, MountOption::NoAtime),
+            (self.sync, MountOption::Sync),
+            (self.dirsync, MountOption::DirSync),
+        ]
+        .into_iter()
+        .filter_map(|(set, option)| set.then_some(option))
+        .collect()
+    }
+}
+
+/// Upper bound for the default `--threads`. Past this, extra workers
+/// mostly wait on the tree lock rather than add throughput.
+const MAX_DEFAULT_THREADS: usize = 8;
+
+/// FUSE worker thread count: `--threads` if given, otherwise one per CPU
+/// up to [`MAX_DEFAULT_THREADS`]. fuser only supports several workers on
+/// Linux, so elsewhere the default is a single thread and an explicit
+/// `--threads` above one is rejected before anything is mounted.
+fn worker_threads(requested: Option<NonZeroUsize>) -> io::Result<usize> {
+    let multi = cfg!(target_os = "linux");
+    match requested {
+        Some(n) if n.get() > 1 && !multi => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--threads above 1 is only supported on Linux",
+        )),
+        Some(n) => Ok(n.get()),
+        None if multi => Ok(std::thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(MAX_DEFAULT_THREADS)),
+        None => Ok(1),
+    }
 }
 
 impl Command for MountArgs {
@@ -59,20 +339,139 @@ fn mount_archive(
     password: Option<String>,
     mount_options: MountOptions,
 ) -> io::Result<()> {
+    // The background process runs from `/`.
+    let mount_point = std::path::absolute(mount_point)?;
+    let archive = std::path::absolute(archive.into())?;
+    let pidfile = mount_options
+        .pidfile
+        .as_deref()
+        .map(std::path::absolute)
+        .transpose()?;
+    let foreground = mount_options.foreground;
+    serve(foreground, pidfile, || {
+        start_session(&mount_point, archive, password, &mount_options)
+    })
+}
+
+/// Start a mount with `start`, in a background process unless
+/// `foreground`, and serve it until it is unmounted. Paths must be
+/// absolute by now, as the background process runs from `/`.
+pub(super) fn serve<FS: Filesystem>(
+    foreground: bool,
+    pidfile: Option<PathBuf>,
+    start: impl FnOnce() -> io::Result<Mounted<FS>>,
+) -> io::Result<()> {
+    let daemon = if foreground {
+        None
+    } else {
+        match daemon::fork()? {
+            Fork::Parent(result) => return result,
+            Fork::Child(daemon) => Some(daemon),
+        }
+    };
+
+    let started = start().and_then(|mounted| {
+        let pidfile = pidfile.as_deref().map(PidFile::create).transpose()?;
+        Ok((mounted, pidfile))
+    });
+    let (mounted, pidfile) = match (started, daemon) {
+        (Ok(started), Some(daemon)) => {
+            daemon.ready()?;
+            started
+        }
+        (Ok(started), None) => {
+            if let Err(e) = daemon::notify(&format!("READY=1\nMAINPID={}", process::id())) {
+                log::warn!("could not notify the service manager: {e}");
+            }
+            started
+        }
+        (Err(e), Some(daemon)) => {
+            // The waiting parent reports the error.
+            daemon.failed(&e);
+            process::exit(1);
+        }
+        (Err(e), None) => return Err(e),
+    };
+    let Mounted {
+        session,
+        lock,
+        control,
+        unmount_error,
+    } = mounted;
+    let outcome = session
+        .run()
+        .and_then(|()| match unmount_error.lock().map(|mut e| e.take()) {
+            Ok(Some(e)) => Err(e),
+            _ => Ok(()),
+        });
+    // `pnafs unmount` returns once told the outcome; by then the archive
+    // must be free for the next mount.
+    drop(lock);
+    drop(pidfile);
+    if let Some(control) = control {
+        control.finish(&outcome);
+    }
+    outcome
+}
+
+/// A mounted filesystem, ready for [`Session::run`].
+pub(super) struct Mounted<FS: Filesystem> {
+    session: Session<FS>,
+    /// Held until the session ends.
+    lock: Option<ArchiveLock>,
+    /// Serves `pnafs unmount`, if the socket could be set up.
+    control: Option<ControlSocket>,
+    /// Why the save on unmount failed, filled in as the session ends.
+    unmount_error: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl<FS: Filesystem> Mounted<FS> {
+    /// Take over `session`, mounted at `mount_point`, and listen for
+    /// `pnafs unmount` requests for it.
+    pub(super) fn new(
+        mut session: Session<FS>,
+        mount_point: &Path,
+        lock: Option<ArchiveLock>,
+        unmount_error: Arc<Mutex<Option<io::Error>>>,
+    ) -> Self {
+        let control = match ControlSocket::listen(mount_point, unmounter(&mut session, mount_point))
+        {
+            Ok(control) => Some(control),
+            Err(e) => {
+                log::warn!("pnafs unmount will not work for this mount: {e}");
+                None
+            }
+        };
+        Self {
+            session,
+            lock,
+            control,
+            unmount_error,
+        }
+    }
+}
+
+/// Load the archive and mount it.
+fn start_session(
+    mount_point: &Path,
+    archive: PathBuf,
+    password: Option<String>,
+    mount_options: &MountOptions,
+) -> io::Result<Mounted<PnaFS>> {
+    let threads = worker_threads(mount_options.threads)?;
     let write_strategy = if mount_options.write {
         Some(mount_options.write_strategy)
     } else {
         None
     };
 
-    let archive = archive.into();
     // Mount-lifetime archive lock: shared for read-only mounts (they can
     // coexist), exclusive for --write mounts. Taken before the archive
     // is even read so a conflicting mount can never observe (or race)
-    // the load/save cycle. Released on drop at the end of this function
-    // — i.e. after mount returns on unmount — or by the kernel if the
-    // process dies.
-    let _lock = ArchiveLock::acquire(
+    // the load/save cycle. Released on drop at the end of
+    // `serve` — i.e. after the session ends on unmount — or by
+    // the kernel if the process dies.
+    let lock = ArchiveLock::acquire(
         &archive,
         if write_strategy.is_some() {
             LockMode::Exclusive
@@ -81,8 +480,19 @@ fn mount_archive(
         },
     )?;
 
-    let fs = PnaFS::new(archive, password, write_strategy)?;
-    create_dir_all(&mount_point)?;
+    let fs = PnaFS::new(
+        archive.clone(),
+        password,
+        write_strategy,
+        mount_options.load_options(),
+    )?
+    .with_cache_policy(mount_options.cache_policy())
+    .with_permissions(mount_options.permissions)
+    .with_nosuid(mount_options.nosuid);
+    fs.set_store_holes(mount_options.sparse);
+    let tree = fs.shared_tree();
+    let unmount_error = fs.shared_unmount_error();
+    create_dir_all(mount_point)?;
 
     let acl = if mount_options.allow_other {
         SessionACL::All
@@ -93,28 +503,70 @@ fn mount_archive(
     };
 
     let mut config = Config::default();
+    config.mount_options = vec![MountOption::FSName("pnafs".to_owned())];
     // DefaultPermissions makes the kernel enforce standard UNIX permission
-    // checks against attr.uid/gid/mode before forwarding ops to FUSE. Without
-    // it, pnafs would silently honour writes from any uid, which breaks every
-    // test that expects EACCES on an unprivileged operation.
-    config.mount_options = vec![
-        MountOption::FSName("pnafs".to_owned()),
-        MountOption::DefaultPermissions,
-    ];
+    // checks against attr.uid/gid/mode before forwarding ops to FUSE.
+    // Without it the checks are pnafs' business: none under `ignore`, its
+    // own (see `permissions`) under `pnafs`.
+    if mount_options.permissions == PermissionMode::Kernel {
+        config.mount_options.push(MountOption::DefaultPermissions);
+    }
     if write_strategy.is_none() {
         config.mount_options.push(MountOption::RO);
     }
+    config.mount_options.extend(mount_options.flags());
     config.acl = acl;
+    // Handlers take `&self` and lock the tree themselves, so requests can
+    // be served from several threads. With more than one, give each its
+    // own /dev/fuse fd (FUSE_DEV_IOC_CLONE) so they don't contend on a
+    // single channel.
+    config.n_threads = Some(threads);
+    config.clone_fd = threads > 1;
+
+    let session = Session::new(fs, mount_point, &config)?;
+    if mount_options.watch {
+        watch::spawn(&archive, tree, session.notifier())?;
+    }
+    Ok(Mounted::new(
+        session,
+        mount_point,
+        Some(lock),
+        unmount_error,
+    ))
+}
 
-    mount(fs, mount_point, &config)?;
-    Ok(())
+/// Unmount callback for the control socket. fuser gives up its handle on
+/// the mount after the first attempt, even a failed one (a busy mount
+/// point when running as root), so later attempts unmount directly.
+fn unmounter<FS: Filesystem>(
+    session: &mut Session<FS>,
+    mount_point: &Path,
+) -> impl FnMut() -> io::Result<()> + Send + 'static {
+    let mut first = Some(session.unmount_callable());
+    let mount_point = mount_point.to_path_buf();
+    move || match first.take() {
+        Some(mut unmounter) => unmounter.unmount(),
+        #[cfg(target_os = "linux")]
+        None => Ok(nix::mount::umount(&mount_point)?),
+        #[cfg(not(target_os = "linux"))]
+        None => Ok(nix::mount::unmount(
+            &mount_point,
+            nix::mount::MntFlags::empty(),
+        )?),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::WriteStrategy;
+    use crate::archive_io::LoadOptions;
     use crate::cli::{Cli, SubCommand};
+    use crate::id_map::{IdMap, IdPair, OwnerResolution};
+    use crate::permissions::PermissionMode;
     use clap::Parser;
+    use fuser::MountOption;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
 
     fn parse_mount(args: &[&str]) -> Result<super::MountOptions, clap::Error> {
         let argv = ["pnafs", "mount"]
@@ -162,6 +614,146 @@ mod tests {
         assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
     }
 
+    #[test]
+    fn cache_timeouts_default_by_mode_and_parse_fractions() {
+        let ro = parse_mount(&[]).unwrap().cache_policy();
+        assert_eq!(ro.attr_ttl, super::READ_ONLY_TTL);
+        assert_eq!(ro.entry_ttl, super::READ_ONLY_TTL);
+        assert!(!ro.keep_cache);
+        let rw = parse_mount(&["--write"]).unwrap().cache_policy();
+        assert_eq!(rw.attr_ttl, super::WRITE_TTL);
+
+        let opts = parse_mount(&[
+            "--attr-timeout",
+            "0.5",
+            "--entry-timeout",
+            "0",
+            "--kernel-cache",
+        ])
+        .unwrap()
+        .cache_policy();
+        assert_eq!(opts.attr_ttl, Duration::from_millis(500));
+        assert_eq!(opts.entry_ttl, Duration::ZERO);
+        assert!(opts.keep_cache);
+        assert!(parse_mount(&["--attr-timeout", "-1"]).is_err());
+        assert!(parse_mount(&["--entry-timeout", "soon"]).is_err());
+    }
+
+    #[test]
+    fn writeback_cache_requires_write() {
+        let opts = parse_mount(&["--write", "--writeback-cache"]).unwrap();
+        assert!(opts.cache_policy().writeback);
+        assert!(!parse_mount(&["--write"]).unwrap().cache_policy().writeback);
+        let err = match parse_mount(&["--writeback-cache"]) {
+            Err(e) => e,
+            Ok(_) => panic!("--writeback-cache without --write should be a parse error"),
+        };
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+    }
+
+    #[test]
+    fn permissions_default_to_kernel() {
+        assert_eq!(
+            parse_mount(&[]).unwrap().permissions,
+            PermissionMode::Kernel
+        );
+        for (arg, mode) in [
+            ("ignore", PermissionMode::Ignore),
+            ("pnafs", PermissionMode::Pnafs),
+        ] {
+            assert_eq!(
+                parse_mount(&["--permissions", arg]).unwrap().permissions,
+                mode
+            );
+        }
+        assert!(parse_mount(&["--permissions", "none"]).is_err());
+        // pnafs mode re-checks search permission on every path walk
+        // unless told otherwise.
+        let pnafs = parse_mount(&["--permissions", "pnafs"]).unwrap();
+        assert_eq!(pnafs.cache_policy().entry_ttl, Duration::ZERO);
+        let pnafs = parse_mount(&["--permissions", "pnafs", "--entry-timeout", "5"]).unwrap();
+        assert_eq!(pnafs.cache_policy().entry_ttl, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn owner_options_build_the_id_map() {
+        assert_eq!(parse_mount(&[]).unwrap().id_map(), IdMap::default());
+        let ids = parse_mount(&[
+            "--uid",
+            "1000",
+            "--map-uid",
+            "1500:1000",
+            "--map-uid",
+            "0:1000",
+            "--map-gid",
+            "20:100",
+        ])
+        .unwrap()
+        .id_map();
+        assert_eq!(ids.uid, Some(1000));
+        assert_eq!(ids.gid, None);
+        assert_eq!(ids.map_uid.len(), 2);
+        assert_eq!(ids.map_gid, [IdPair { from: 20, to: 100 }]);
+        assert!(parse_mount(&["--map-uid", "1500"]).is_err());
+
+        let squashed = parse_mount(&["--squash-owner"]).unwrap().id_map();
+        assert_eq!(squashed.uid, Some(nix::unistd::getuid().as_raw()));
+        assert_eq!(squashed.gid, Some(nix::unistd::getgid().as_raw()));
+        assert!(parse_mount(&["--squash-owner", "--uid", "0"]).is_err());
+
+        for (arg, resolution) in [
+            ("name", OwnerResolution::Name),
+            ("id", OwnerResolution::Id),
+            ("archived", OwnerResolution::Archived),
+        ] {
+            let ids = parse_mount(&["--owner-resolution", arg]).unwrap().id_map();
+            assert_eq!(ids.resolution, resolution);
+        }
+    }
+
+    #[test]
+    fn default_modes_are_octal_and_masked_by_umask() {
+        assert_eq!(
+            parse_mount(&[]).unwrap().load_options(),
+            LoadOptions::default()
+        );
+        let options = parse_mount(&["--file-mode", "0644", "--umask", "027"])
+            .unwrap()
+            .load_options();
+        assert_eq!((options.file_mode, options.dir_mode), (0o640, 0o750));
+        let options = parse_mount(&["--dir-mode", "1777"]).unwrap().load_options();
+        assert_eq!((options.file_mode, options.dir_mode), (0o775, 0o1777));
+        assert!(parse_mount(&["--file-mode", "0x1ff"]).is_err());
+        assert!(parse_mount(&["--file-mode", "17777"]).is_err());
+        assert!(parse_mount(&["--umask", "9"]).is_err());
+    }
+
+    #[test]
+    fn standard_flags_map_to_mount_options() {
+        assert!(parse_mount(&[]).unwrap().flags().is_empty());
+        let flags = parse_mount(&["--nosuid", "--noexec", "--dirsync"])
+            .unwrap()
+            .flags();
+        assert_eq!(
+            flags,
+            [
+                MountOption::NoSuid,
+                MountOption::NoExec,
+                MountOption::DirSync
+            ]
+        );
+    }
+
+    #[test]
+    fn sparse_requires_write() {
+        assert!(parse_mount(&["--write", "--sparse"]).unwrap().sparse);
+        let err = match parse_mount(&["--sparse"]) {
+            Err(e) => e,
+            Ok(_) => panic!("--sparse without --write should be a parse error"),
+        };
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+    }
+
     #[test]
     fn allow_root_parses() {
         let opts = parse_mount(&["--allow-root"]).unwrap();
@@ -169,6 +761,35 @@ mod tests {
         assert!(!opts.allow_other);
     }
 
+    #[test]
+    fn watch_parses_for_read_only_mounts() {
+        let opts = parse_mount(&["--watch"]).unwrap();
+        assert!(opts.watch);
+    }
+
+    #[test]
+    fn watch_conflicts_with_write() {
+        let err = match parse_mount(&["--write", "--watch"]) {
+            Err(e) => e,
+            Ok(_) => panic!("--watch with --write should be a parse error"),
+        };
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn threads_parses_and_rejects_zero() {
+        let opts = parse_mount(&["--threads", "4"]).unwrap();
+        assert_eq!(opts.threads.map(NonZeroUsize::get), Some(4));
+        assert!(parse_mount(&["--threads", "0"]).is_err());
+    }
+
+    #[test]
+    fn default_threads_is_bounded() {
+        let n = super::worker_threads(None).unwrap();
+        assert!((1..=super::MAX_DEFAULT_THREADS).contains(&n));
+        assert_eq!(super::worker_threads(NonZeroUsize::new(1)).unwrap(), 1);
+    }
+
     #[test]
     fn allow_other_parses() {
         let opts = parse_mount(&["--allow-other"]).unwrap();
@@ -0,0 +1,100 @@
+use crate::{
+    archive_dir::ArchiveDirFS,
+    archive_io::LoadOptions,
+    cli::PasswordArgs,
+    command::{
+        Command, ask_password,
+        mount::{self, Mounted, parse_timeout},
+    },
+};
+use clap::{Args, ValueHint};
+use fuser::{Config, MountOption, Session, SessionACL};
+use std::fs::create_dir_all;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Args)]
+pub(crate) struct MountDirArgs {
+    #[command(flatten)]
+    password: PasswordArgs,
+    #[arg(
+        long,
+        help = "Allow the root user to access this filesystem, in addition to the user who mounted it"
+    )]
+    allow_root: bool,
+    #[arg(
+        long,
+        help = "Allow all users to access files on this filesystem. By default access is restricted to the user who mounted it"
+    )]
+    allow_other: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value = "60",
+        value_parser = parse_timeout,
+        help = "Unload an archive once it has been unused this long, with no files open in it"
+    )]
+    idle_timeout: Duration,
+    #[arg(
+        short,
+        long,
+        help = "Stay in the foreground until unmounted instead of returning once mounted"
+    )]
+    foreground: bool,
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_hint = ValueHint::FilePath,
+        help = "Write the pid of the mount process to FILE, removing it on unmount"
+    )]
+    pidfile: Option<PathBuf>,
+    #[arg(value_hint = ValueHint::DirPath)]
+    dir: PathBuf,
+    #[arg(value_hint = ValueHint::DirPath)]
+    mount_point: PathBuf,
+}
+
+impl Command for MountDirArgs {
+    #[inline]
+    fn execute(self) -> io::Result<()> {
+        let password = ask_password(self.password)?;
+        // The background process runs from `/`.
+        let dir = std::path::absolute(&self.dir)?;
+        let mount_point = std::path::absolute(&self.mount_point)?;
+        let pidfile = self
+            .pidfile
+            .as_deref()
+            .map(std::path::absolute)
+            .transpose()?;
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", dir.display()),
+            ));
+        }
+        let acl = if self.allow_other {
+            SessionACL::All
+        } else if self.allow_root {
+            SessionACL::RootAndOwner
+        } else {
+            SessionACL::Owner
+        };
+        let idle_timeout = self.idle_timeout;
+        mount::serve(self.foreground, pidfile, || {
+            let fs = ArchiveDirFS::new(dir, password, LoadOptions::default());
+            fs.spawn_unloader(idle_timeout)?;
+            create_dir_all(&mount_point)?;
+            let mut config = Config::default();
+            config.mount_options = vec![
+                MountOption::FSName("pnafs".to_owned()),
+                MountOption::DefaultPermissions,
+                MountOption::RO,
+            ];
+            config.acl = acl;
+            let session = Session::new(fs, &mount_point, &config)?;
+            Ok(Mounted::new(session, &mount_point, None, Arc::default()))
+        })
+    }
+}
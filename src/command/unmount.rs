@@ -0,0 +1,17 @@
+use crate::{command::Command, control};
+use clap::{Args, ValueHint};
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub(crate) struct UnmountArgs {
+    #[arg(value_hint = ValueHint::DirPath)]
+    mount_point: PathBuf,
+}
+
+impl Command for UnmountArgs {
+    #[inline]
+    fn execute(self) -> io::Result<()> {
+        control::request_unmount(&self.mount_point)
+    }
+}
@@ -0,0 +1,285 @@
+//! The control socket of a mount, through which `pnafs unmount` ends the
+//! mount and learns whether the final save succeeded.
+//!
+//! A lazy `--write` mount saves in `destroy`, while the session ends, so
+//! `fusermount -u` returns before the save is done and its errors only
+//! reach the log of a process that may well be detached. Every mount
+//! therefore listens on a Unix socket named after its mount point, in a
+//! directory private to the user. A client sends `unmount` and, once the
+//! session has ended and the lock on the archive is released, receives
+//! one status line: `ok`, or `error: ` followed by the reason.
+
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+const UNMOUNT: &str = "unmount";
+const OK: &str = "ok";
+const ERROR: &str = "error: ";
+
+/// The listening side, owned by the mount process.
+pub(crate) struct ControlSocket {
+    path: Option<PathBuf>,
+    state: Arc<Mutex<State>>,
+}
+
+enum State {
+    /// Clients waiting for the session to end.
+    Running(Vec<UnixStream>),
+    /// The session ended; the status line every later client gets.
+    Finished(String),
+}
+
+impl ControlSocket {
+    /// Listen for the mount at `mount_point`, calling `unmount` for every
+    /// unmount request.
+    pub(crate) fn listen<U>(mount_point: &Path, unmount: U) -> io::Result<Self>
+    where
+        U: FnMut() -> io::Result<()> + Send + 'static,
+    {
+        let dir = private_dir();
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        check_private(&dir)?;
+        Self::listen_at(socket_name(&dir, mount_point)?, unmount)
+    }
+
+    fn listen_at<U>(path: PathBuf, mut unmount: U) -> io::Result<Self>
+    where
+        U: FnMut() -> io::Result<()> + Send + 'static,
+    {
+        let listener = bind(&path)?;
+        let state = Arc::new(Mutex::new(State::Running(Vec::new())));
+        let shared = Arc::clone(&state);
+        thread::Builder::new()
+            .name("pnafs-control".to_owned())
+            .spawn(move || {
+                for client in listener.incoming().flatten() {
+                    if let Err(e) = serve(client, &shared, &mut unmount) {
+                        log::warn!("control socket: {e}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            path: Some(path),
+            state,
+        })
+    }
+
+    /// Report how the session ended to every waiting client, and to any
+    /// that asks later, and stop listening.
+    pub(crate) fn finish(mut self, outcome: &io::Result<()>) {
+        self.remove();
+        let status = match outcome {
+            Ok(()) => OK.to_owned(),
+            Err(e) => format!("{ERROR}{e}"),
+        };
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let State::Running(waiting) = &mut *state {
+            for mut client in waiting.drain(..) {
+                let _ = writeln!(client, "{status}");
+            }
+        }
+        *state = State::Finished(status);
+    }
+
+    fn remove(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Bind `path`, replacing a socket left behind by a process that died.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another pnafs process", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+fn serve<U>(client: UnixStream, state: &Mutex<State>, unmount: &mut U) -> io::Result<()>
+where
+    U: FnMut() -> io::Result<()>,
+{
+    let mut request = String::new();
+    BufReader::new(&client).read_line(&mut request)?;
+    let mut client = client;
+    if request.trim_end() != UNMOUNT {
+        return writeln!(client, "{ERROR}unknown request {:?}", request.trim_end());
+    }
+    // Hold the state across the unmount, so the session cannot finish
+    // between the unmount and registering the client.
+    let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+    match &mut *state {
+        State::Finished(status) => writeln!(client, "{status}"),
+        State::Running(waiting) => match unmount() {
+            Ok(()) => {
+                waiting.push(client);
+                Ok(())
+            }
+            Err(e) => writeln!(client, "{ERROR}{e}"),
+        },
+    }
+}
+
+/// Unmount the filesystem at `mount_point` through the pnafs process
+/// serving it, and wait until that process has saved and let go of the
+/// archive. Fails with the reason if the save did.
+pub(crate) fn request_unmount(mount_point: &Path) -> io::Result<()> {
+    let dir = private_dir();
+    if dir.exists() {
+        check_private(&dir)?;
+    }
+    request_unmount_at(&socket_name(&dir, mount_point)?).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => io::Error::new(
+            e.kind(),
+            format!(
+                "no pnafs process of this user serves {}",
+                mount_point.display()
+            ),
+        ),
+        _ => e,
+    })
+}
+
+fn request_unmount_at(path: &Path) -> io::Result<()> {
+    let mut server = UnixStream::connect(path)?;
+    writeln!(server, "{UNMOUNT}")?;
+    let mut status = String::new();
+    BufReader::new(server).read_line(&mut status)?;
+    match status.trim_end() {
+        OK => Ok(()),
+        "" => Err(io::Error::other(
+            "pnafs exited without reporting how the unmount went",
+        )),
+        status => Err(io::Error::other(
+            status.strip_prefix(ERROR).unwrap_or(status).to_owned(),
+        )),
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/pnafs`, or a per-user directory in the temporary
+/// directory.
+fn private_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => Path::new(&runtime).join("pnafs"),
+        _ => std::env::temp_dir().join(format!("pnafs-{}", nix::unistd::getuid())),
+    }
+}
+
+/// Refuse a socket directory that someone else could have planted.
+fn check_private(dir: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir()
+        || meta.uid() != nix::unistd::getuid().as_raw()
+        || meta.permissions().mode() & 0o077 != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must be a directory accessible only to its owner",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The socket for `mount_point` in `dir`. The mount point is hashed, as
+/// socket paths are limited to about 100 bytes; [`name_hash`] keeps
+/// the name the same across pnafs builds. The directory holding the
+/// mount point is resolved, but not the mount point itself, whose
+/// lookup would have to be served by the very mount it names.
+fn socket_name(dir: &Path, mount_point: &Path) -> io::Result<PathBuf> {
+    let mount_point = std::path::absolute(mount_point)?;
+    let mount_point = match (mount_point.parent(), mount_point.file_name()) {
+        (Some(parent), Some(name)) => fs::canonicalize(parent)?.join(name),
+        _ => mount_point,
+    };
+    let hash = name_hash(mount_point.as_os_str().as_bytes());
+    Ok(dir.join(format!("{hash:016x}.sock")))
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is fixed by its definition.
+fn name_hash(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes
+        .iter()
+        .fold(OFFSET_BASIS, |h, &b| (h ^ u64::from(b)).wrapping_mul(PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn unmount_waits_for_the_outcome() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ctl.sock");
+        let (tx, rx) = mpsc::channel();
+        let control = ControlSocket::listen_at(path.clone(), move || {
+            tx.send(()).unwrap();
+            Ok(())
+        })
+        .unwrap();
+
+        let client = {
+            let path = path.clone();
+            thread::spawn(move || request_unmount_at(&path))
+        };
+        rx.recv().unwrap();
+        control.finish(&Err(io::Error::other("archive a.pna was modified")));
+        let error = client.join().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "archive a.pna was modified");
+        assert!(!path.exists(), "the socket goes away with the session");
+    }
+
+    #[test]
+    fn failed_unmounts_are_reported_at_once() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ctl.sock");
+        let control = ControlSocket::listen_at(path.clone(), || {
+            Err(io::Error::from_raw_os_error(libc::EBUSY))
+        })
+        .unwrap();
+        let error = request_unmount_at(&path).unwrap_err();
+        assert!(error.to_string().contains("os error 16"), "{error}");
+        control.finish(&Ok(()));
+    }
+
+    #[test]
+    fn socket_names_resolve_the_parent_only() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("mnt")).unwrap();
+        let direct = socket_name(dir.path(), &dir.path().join("mnt")).unwrap();
+        let dotted = socket_name(dir.path(), &dir.path().join("mnt/../mnt")).unwrap();
+        assert_eq!(direct, dotted);
+        assert_ne!(
+            direct,
+            socket_name(dir.path(), &dir.path().join("other")).unwrap()
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Running the mount in the background (`--foreground`, `--pidfile`) and
+//! readiness notification.
+//!
+//! `pnafs mount` forks before touching the archive. The parent waits on a
+//! pipe until the child reports that the filesystem is mounted, or why
+//! it could not be, and exits with that outcome, so `pnafs mount` only
+//! returns once the mount point is usable and still fails like a
+//! foreground mount would. A `--foreground` mount instead announces
+//! readiness to a service manager through `NOTIFY_SOCKET` (see
+//! `sd_notify(3)`); a forked child is not the process it supervises.
+
+use nix::unistd::{ForkResult, Pid};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// Status byte for a mount that came up.
+const READY: u8 = b'R';
+/// Status byte for a failure; the error follows.
+const FAILED: u8 = b'E';
+
+/// Which side of [`fork`] the caller is on.
+pub(crate) enum Fork {
+    /// The original process, with the background mount's outcome.
+    Parent(io::Result<()>),
+    /// The background process, which must report through the [`Daemon`].
+    Child(Daemon),
+}
+
+/// The background process's channel to the waiting parent.
+pub(crate) struct Daemon {
+    status: File,
+}
+
+/// Fork into the background, detached from the terminal. The parent
+/// returns once the child called [`Daemon::ready`] or [`Daemon::failed`],
+/// or exited without doing either.
+///
+/// Must be called while the process is still single-threaded.
+pub(crate) fn fork() -> io::Result<Fork> {
+    let (read, write) = nix::unistd::pipe()?;
+    // SAFETY: pnafs starts no threads before mounting, so the child is a
+    // complete copy of the process rather than one thread of it.
+    match unsafe { nix::unistd::fork() }? {
+        ForkResult::Parent { .. } => {
+            drop(write);
+            Ok(Fork::Parent(wait_for_child(File::from(read))))
+        }
+        ForkResult::Child => {
+            drop(read);
+            nix::unistd::setsid()?;
+            nix::unistd::chdir("/")?;
+            Ok(Fork::Child(Daemon {
+                status: File::from(write),
+            }))
+        }
+    }
+}
+
+fn wait_for_child(mut status: File) -> io::Result<()> {
+    let mut report = Vec::new();
+    status.read_to_end(&mut report)?;
+    match report.split_first() {
+        Some((&READY, _)) => Ok(()),
+        Some((&FAILED, error)) => Err(decode_error(error)),
+        _ => Err(io::Error::other(
+            "pnafs exited before the archive was mounted",
+        )),
+    }
+}
+
+impl Daemon {
+    /// Tell the parent the mount is live, and detach from its standard
+    /// streams so nothing keeps the caller's pipes or terminal open.
+    ///
+    /// Stderr is kept unless it is a terminal: when it was redirected to
+    /// a file or a journal, warnings logged while serving still reach it.
+    pub(crate) fn ready(mut self) -> io::Result<()> {
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        nix::unistd::dup2_stdin(&null)?;
+        nix::unistd::dup2_stdout(&null)?;
+        if io::stderr().is_terminal() {
+            nix::unistd::dup2_stderr(&null)?;
+        }
+        self.status.write_all(&[READY])
+    }
+
+    /// Hand `error` to the parent, which reports it as its own.
+    pub(crate) fn failed(mut self, error: &io::Error) {
+        let mut report = vec![FAILED];
+        report.extend(encode_error(error));
+        // The parent is gone if this fails; nobody is left to tell.
+        let _ = self.status.write_all(&report);
+    }
+}
+
+/// OS errors travel as their code, so the parent reproduces them exactly;
+/// anything else as its message.
+fn encode_error(error: &io::Error) -> Vec<u8> {
+    match error.raw_os_error() {
+        Some(code) => format!("os:{code}:{error}").into_bytes(),
+        None => format!("msg:{error}").into_bytes(),
+    }
+}
+
+fn decode_error(report: &[u8]) -> io::Error {
+    let report = String::from_utf8_lossy(report);
+    let os = report
+        .strip_prefix("os:")
+        .and_then(|rest| rest.split_once(':'))
+        .and_then(|(code, msg)| Some((code.parse().ok()?, msg)));
+    if let Some((code, msg)) = os {
+        io::Error::new(io::Error::from_raw_os_error(code).kind(), msg.to_owned())
+    } else {
+        io::Error::other(report.strip_prefix("msg:").unwrap_or(&report).to_owned())
+    }
+}
+
+/// A file holding the pid of the mount process, removed again on drop.
+pub(crate) struct PidFile(PathBuf);
+
+impl PidFile {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", Pid::this()))?;
+        Ok(Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Send `state` to the service manager named by `NOTIFY_SOCKET`, if any.
+pub(crate) fn notify(state: &str) -> io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send_state(&path, state),
+        None => Ok(()),
+    }
+}
+
+/// Send `state` to the datagram socket at `path`. A leading `@` names a
+/// socket in the Linux abstract namespace.
+fn send_state(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn errors_survive_the_trip_to_the_parent() {
+        let os = io::Error::from_raw_os_error(libc::ENOENT);
+        let back = decode_error(&encode_error(&os));
+        assert_eq!(back.kind(), io::ErrorKind::NotFound);
+        assert_eq!(back.to_string(), os.to_string());
+
+        let custom = io::Error::other("archive a.pna is already mounted");
+        let back = decode_error(&encode_error(&custom));
+        assert_eq!(back.to_string(), "archive a.pna is already mounted");
+    }
+
+    #[test]
+    fn state_is_sent_to_the_socket() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let listener = UnixDatagram::bind(&path).unwrap();
+        send_state(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
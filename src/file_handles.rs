@@ -0,0 +1,246 @@
+//! Per-open file handles.
+//!
+//! Every successful `open`/`create` gets its own [`FileHandle`] number,
+//! which the kernel passes back on each `read`, `write`, `flush` and
+//! `release` for that open file description. The [`HandleTable`] maps it
+//! to an [`OpenFile`] recording how the file was opened, so handlers can
+//! enforce the access mode themselves and `release` knows whether the
+//! closing handle changed anything.
+//!
+//! Directories opened with `opendir` get handles from a separate
+//! [`DirHandleTable`], which keeps a snapshot of the listing so `readdir`
+//! offsets index a fixed sequence even while the directory changes.
+
+use crate::file_tree::Inode;
+use fuser::{Errno, FileHandle, OpenAccMode, OpenFlags};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// State of one open file description.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OpenFile {
+    pub ino: Inode,
+    /// `open(2)` flags as the kernel forwarded them (`O_CREAT`, `O_EXCL`
+    /// and `O_NOCTTY` are already consumed by the kernel).
+    pub flags: OpenFlags,
+    /// The POSIX lock owner of the process using the handle, once a
+    /// request has told us (`open` itself does not carry one).
+    pub lock_owner: Option<u64>,
+    /// Whether the file has been modified through this handle.
+    pub dirtied: bool,
+}
+
+impl OpenFile {
+    pub(crate) fn access(&self) -> OpenAccMode {
+        self.flags.acc_mode()
+    }
+
+    pub(crate) fn writable(&self) -> bool {
+        self.access() != OpenAccMode::O_RDONLY
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct HandleTable {
+    /// Last handle number issued. Numbers start at 1 and are never
+    /// reused, so a stale handle can't alias a newer open.
+    last: AtomicU64,
+    open: Mutex<HashMap<u64, OpenFile>>,
+}
+
+impl HandleTable {
+    /// The open handles, recovering from poisoning: every mutation below
+    /// is a single map operation that leaves the table consistent.
+    fn open(&self) -> MutexGuard<'_, HashMap<u64, OpenFile>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register a new open of `ino` and return its handle.
+    pub(crate) fn insert(&self, ino: Inode, flags: OpenFlags, dirtied: bool) -> FileHandle {
+        let fh = self.last.fetch_add(1, Ordering::Relaxed) + 1;
+        self.open().insert(
+            fh,
+            OpenFile {
+                ino,
+                flags,
+                lock_owner: None,
+                dirtied,
+            },
+        );
+        FileHandle(fh)
+    }
+
+    pub(crate) fn get(&self, fh: FileHandle) -> Option<OpenFile> {
+        self.open().get(&fh.0).copied()
+    }
+
+    /// `EBADF` unless `fh` is an open handle of `ino` that allows writing,
+    /// like `write(2)` on a descriptor opened `O_RDONLY`.
+    pub(crate) fn check_writable(&self, fh: FileHandle, ino: Inode) -> Result<(), Errno> {
+        match self.get(fh) {
+            Some(h) if h.ino == ino && h.writable() => Ok(()),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    /// Record that `fh` modified its file. Unknown handles are ignored:
+    /// `setattr` may name one the kernel has already released.
+    pub(crate) fn mark_dirty(&self, fh: FileHandle) {
+        if let Some(h) = self.open().get_mut(&fh.0) {
+            h.dirtied = true;
+        }
+    }
+
+    /// Remember the lock owner using `fh`.
+    pub(crate) fn note_owner(&self, fh: FileHandle, owner: u64) {
+        if let Some(h) = self.open().get_mut(&fh.0) {
+            h.lock_owner = Some(owner);
+        }
+    }
+
+    /// Unregister `fh`, returning its final state.
+    pub(crate) fn remove(&self, fh: FileHandle) -> Option<OpenFile> {
+        self.open().remove(&fh.0)
+    }
+}
+
+/// A directory's `(name, inode)` entries at the time a listing started.
+pub(crate) type Listing = Arc<[(OsString, Inode)]>;
+
+#[derive(Clone, Debug)]
+struct OpenDir {
+    ino: Inode,
+    /// Taken by the first `readdir` and retaken on every rewind.
+    listing: Option<Listing>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct DirHandleTable {
+    /// Same numbering scheme as [`HandleTable::last`]; the kernel never
+    /// mixes the two kinds of handle.
+    last: AtomicU64,
+    open: Mutex<HashMap<u64, OpenDir>>,
+}
+
+impl DirHandleTable {
+    /// See [`HandleTable::open`].
+    fn open(&self) -> MutexGuard<'_, HashMap<u64, OpenDir>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register a new open of directory `ino` and return its handle.
+    pub(crate) fn insert(&self, ino: Inode) -> FileHandle {
+        let fh = self.last.fetch_add(1, Ordering::Relaxed) + 1;
+        self.open().insert(fh, OpenDir { ino, listing: None });
+        FileHandle(fh)
+    }
+
+    /// The listing `readdir` at `offset` pages through. Offset 0 starts
+    /// (or, after `rewinddir`, restarts) the listing, so `snapshot` is
+    /// called to take a fresh one; later offsets reuse it, which keeps
+    /// entries from being skipped or repeated when the directory changes
+    /// in between. `EBADF` unless `fh` is an open handle of `ino`.
+    pub(crate) fn listing(
+        &self,
+        fh: FileHandle,
+        ino: Inode,
+        offset: u64,
+        snapshot: impl FnOnce() -> Result<Vec<(OsString, Inode)>, Errno>,
+    ) -> Result<Listing, Errno> {
+        let mut open = self.open();
+        let dir = match open.get_mut(&fh.0) {
+            Some(dir) if dir.ino == ino => dir,
+            _ => return Err(Errno::EBADF),
+        };
+        match &dir.listing {
+            Some(listing) if offset != 0 => Ok(Arc::clone(listing)),
+            _ => {
+                let listing: Listing = snapshot()?.into();
+                dir.listing = Some(Arc::clone(&listing));
+                Ok(listing)
+            }
+        }
+    }
+
+    /// Unregister `fh`, dropping its snapshot.
+    pub(crate) fn remove(&self, fh: FileHandle) {
+        self.open().remove(&fh.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RDONLY: OpenFlags = OpenFlags(libc::O_RDONLY);
+    const RDWR: OpenFlags = OpenFlags(libc::O_RDWR);
+
+    #[test]
+    fn handles_are_distinct_and_never_zero() {
+        let table = HandleTable::default();
+        let a = table.insert(5, RDONLY, false);
+        let b = table.insert(5, RDONLY, false);
+        assert_ne!(a, b);
+        assert_ne!(a.0, 0);
+        table.remove(a);
+        assert_ne!(table.insert(5, RDONLY, false), a);
+    }
+
+    #[test]
+    fn check_writable_follows_the_access_mode() {
+        let table = HandleTable::default();
+        let ro = table.insert(5, RDONLY, false);
+        let rw = table.insert(5, RDWR, false);
+        let wo = table.insert(5, OpenFlags(libc::O_WRONLY | libc::O_APPEND), false);
+        assert_eq!(table.check_writable(ro, 5), Err(Errno::EBADF));
+        assert_eq!(table.check_writable(rw, 5), Ok(()));
+        assert_eq!(table.check_writable(wo, 5), Ok(()));
+        // Wrong inode or an unknown handle.
+        assert_eq!(table.check_writable(rw, 6), Err(Errno::EBADF));
+        assert_eq!(table.check_writable(FileHandle(999), 5), Err(Errno::EBADF));
+    }
+
+    #[test]
+    fn dirty_mark_and_owner_stick_to_one_handle() {
+        let table = HandleTable::default();
+        let a = table.insert(5, RDWR, false);
+        let b = table.insert(5, RDWR, false);
+        table.mark_dirty(a);
+        table.note_owner(b, 42);
+        assert!(table.get(a).unwrap().dirtied);
+        assert!(!table.get(b).unwrap().dirtied);
+        assert_eq!(table.get(b).unwrap().lock_owner, Some(42));
+        assert!(table.remove(a).unwrap().dirtied);
+        assert!(table.get(a).is_none());
+        // Marking a released handle is a no-op.
+        table.mark_dirty(a);
+        assert!(table.get(a).is_none());
+    }
+
+    #[test]
+    fn dir_listing_is_snapshotted_until_rewind() {
+        let table = DirHandleTable::default();
+        let fh = table.insert(1);
+        let entries = |names: &[&str]| -> Vec<(OsString, Inode)> {
+            names.iter().map(|n| (OsString::from(n), 9)).collect()
+        };
+        let first = table
+            .listing(fh, 1, 0, || Ok(entries(&["a", "b"])))
+            .unwrap();
+        let later = table.listing(fh, 1, 1, || Ok(entries(&["b"]))).unwrap();
+        assert!(Arc::ptr_eq(&first, &later));
+        let rewound = table.listing(fh, 1, 0, || Ok(entries(&["b"]))).unwrap();
+        assert_eq!(rewound.len(), 1);
+        assert_eq!(
+            table.listing(fh, 2, 1, || Ok(Vec::new())).unwrap_err(),
+            Errno::EBADF
+        );
+        table.remove(fh);
+        assert_eq!(
+            table.listing(fh, 1, 1, || Ok(Vec::new())).unwrap_err(),
+            Errno::EBADF
+        );
+    }
+}
@@ -0,0 +1,544 @@
+//! POSIX record locks (`fcntl(F_GETLK / F_SETLK / F_SETLKW)`) on files
+//! inside the mount.
+//!
+//! Once pnafs negotiates `FUSE_POSIX_LOCKS` the kernel stops arbitrating
+//! record locks itself and forwards every request here, keyed by inode and
+//! the kernel's lock owner (one per process for POSIX locks). The
+//! [`LockTable`] implements the POSIX rules on top of that:
+//!
+//! - Shared (`F_RDLCK`) locks of different owners may overlap; an
+//!   exclusive (`F_WRLCK`) lock conflicts with any other owner's lock on
+//!   an overlapping range. An owner never conflicts with itself.
+//! - Setting a lock replaces whatever the owner held on that range,
+//!   splitting its existing locks as needed; `F_UNLCK` just removes the
+//!   range. Adjacent or overlapping locks of the same owner and type are
+//!   coalesced, as the kernel does.
+//! - A blocking request waits until it no longer conflicts, and fails
+//!   with `EDEADLK` if granting it would require waiting on an owner that
+//!   is itself (transitively) waiting on this one.
+//! - Closing any fd for the file drops all of that owner's locks on it
+//!   (`flush`), and a freed inode loses every lock. Either also cancels
+//!   the owner's pending requests on the file with `EINTR`: fuser does not
+//!   pass `FUSE_INTERRUPT` on, so a waiter killed by a signal is only
+//!   noticed when its fds are closed, and must not take the lock then.
+//! - At most [`MAX_WAITERS`] blocking requests wait at once, each on its
+//!   own thread; further ones fail with `ENOLCK`.
+//!
+//! `flock(2)` locks are left to the kernel, which arbitrates them locally
+//! without `FUSE_FLOCK_LOCKS`: fuser does not pass the request flag that
+//! tells a `flock` apart from a whole-file record lock, and merging the
+//! two would make a process conflict with its own locks.
+//!
+//! Ranges are inclusive on both ends, as FUSE sends them; `end ==
+//! OFFSET_MAX` means "to end of file and beyond".
+
+use crate::file_tree::Inode;
+use fuser::Errno;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// Most `F_SETLKW` requests that may wait at the same time.
+pub(crate) const MAX_WAITERS: usize = 256;
+
+/// Shared or exclusive, i.e. `F_RDLCK` or `F_WRLCK`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LockKind {
+    Read,
+    Write,
+}
+
+impl LockKind {
+    /// Decode an `l_type`. `Ok(None)` is `F_UNLCK`.
+    pub(crate) fn from_raw(typ: i32) -> Result<Option<Self>, Errno> {
+        match typ {
+            libc::F_RDLCK => Ok(Some(Self::Read)),
+            libc::F_WRLCK => Ok(Some(Self::Write)),
+            libc::F_UNLCK => Ok(None),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    pub(crate) fn to_raw(self) -> i32 {
+        match self {
+            Self::Read => libc::F_RDLCK,
+            Self::Write => libc::F_WRLCK,
+        }
+    }
+}
+
+/// One held lock on `start..=end`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RangeLock {
+    pub owner: u64,
+    /// Reported back through `F_GETLK`; never used for ownership.
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub kind: LockKind,
+}
+
+impl RangeLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, owner: u64, start: u64, end: u64, kind: LockKind) -> bool {
+        self.owner != owner
+            && self.overlaps(start, end)
+            && (self.kind == LockKind::Write || kind == LockKind::Write)
+    }
+}
+
+/// A lock request as recorded while its owner waits for it.
+#[derive(Copy, Clone, Debug)]
+struct Pending {
+    owner: u64,
+    ino: Inode,
+    start: u64,
+    end: u64,
+    kind: LockKind,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    locks: HashMap<Inode, Vec<RangeLock>>,
+    /// Requests currently blocked in [`LockTable::lock_wait`], by ticket,
+    /// for deadlock detection. A request whose entry disappears was
+    /// cancelled.
+    waiting: HashMap<u64, Pending>,
+    next_ticket: u64,
+}
+
+impl State {
+    fn conflicts(
+        &self,
+        ino: Inode,
+        owner: u64,
+        start: u64,
+        end: u64,
+        kind: LockKind,
+    ) -> impl Iterator<Item = &RangeLock> {
+        self.locks
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .filter(move |l| l.conflicts(owner, start, end, kind))
+    }
+
+    /// Cancel the pending requests on `ino` that `cancel` selects.
+    fn cancel_waiting(&mut self, ino: Inode, cancel: impl Fn(&Pending) -> bool) -> bool {
+        let before = self.waiting.len();
+        self.waiting.retain(|_, p| p.ino != ino || !cancel(p));
+        self.waiting.len() != before
+    }
+
+    /// Would `owner` waiting for `want` close a cycle in the wait-for
+    /// graph, i.e. does some holder it waits on (transitively) wait on
+    /// `owner`?
+    fn would_deadlock(&self, owner: u64, want: Pending) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<u64> = self
+            .conflicts(want.ino, owner, want.start, want.end, want.kind)
+            .map(|l| l.owner)
+            .collect();
+        while let Some(holder) = stack.pop() {
+            if holder == owner {
+                return true;
+            }
+            if !seen.insert(holder) {
+                continue;
+            }
+            for p in self.waiting.values().filter(|p| p.owner == holder) {
+                stack.extend(
+                    self.conflicts(p.ino, holder, p.start, p.end, p.kind)
+                        .map(|l| l.owner),
+                );
+            }
+        }
+        false
+    }
+
+    /// Make `owner`'s locks on `start..=end` exactly `kind` (or nothing).
+    fn set(
+        &mut self,
+        ino: Inode,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: Option<LockKind>,
+    ) {
+        let locks = self.locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(locks.len() + 2);
+        for l in locks.drain(..) {
+            if l.owner != owner || !l.overlaps(start, end) {
+                kept.push(l);
+                continue;
+            }
+            // Keep the parts of the old lock outside the new range.
+            if l.start < start {
+                kept.push(RangeLock {
+                    end: start - 1,
+                    ..l
+                });
+            }
+            if l.end > end {
+                kept.push(RangeLock {
+                    start: end + 1,
+                    ..l
+                });
+            }
+        }
+        if let Some(kind) = kind {
+            let mut new = RangeLock {
+                owner,
+                pid,
+                start,
+                end,
+                kind,
+            };
+            // Coalesce with touching locks of the same owner and kind.
+            kept.retain(|l| {
+                let touches = l.owner == owner
+                    && l.kind == kind
+                    && l.start <= new.end.saturating_add(1)
+                    && new.start <= l.end.saturating_add(1);
+                if touches {
+                    new.start = new.start.min(l.start);
+                    new.end = new.end.max(l.end);
+                }
+                !touches
+            });
+            kept.push(new);
+        }
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+    }
+}
+
+/// Record locks of every inode in the mount.
+#[derive(Debug, Default)]
+pub(crate) struct LockTable {
+    state: Mutex<State>,
+    /// Signalled whenever locks are removed or requests cancelled, waking
+    /// blocked requests.
+    released: Condvar,
+    /// Blocking requests admitted by [`Self::reserve_waiter`].
+    waiters: AtomicUsize,
+}
+
+/// Admission of one blocking request; dropping it frees the place.
+pub(crate) struct WaiterSlot(Arc<LockTable>);
+
+impl Drop for WaiterSlot {
+    fn drop(&mut self) {
+        self.0.waiters.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl LockTable {
+    /// The table's state, recovering from poisoning: every mutation below
+    /// is a single `Vec` rebuild that leaves the state consistent.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// `F_GETLK`: the first lock that would block `owner` from taking
+    /// `kind` on `start..=end`, if any.
+    pub(crate) fn test(
+        &self,
+        ino: Inode,
+        owner: u64,
+        start: u64,
+        end: u64,
+        kind: LockKind,
+    ) -> Option<RangeLock> {
+        self.state()
+            .conflicts(ino, owner, start, end, kind)
+            .next()
+            .copied()
+    }
+
+    /// `F_SETLK`: take, change or drop (`kind == None`) a lock without
+    /// waiting. Fails with `EAGAIN` on a conflict.
+    pub(crate) fn lock(
+        &self,
+        ino: Inode,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: Option<LockKind>,
+    ) -> Result<(), Errno> {
+        let mut state = self.state();
+        if let Some(kind) = kind
+            && state
+                .conflicts(ino, owner, start, end, kind)
+                .next()
+                .is_some()
+        {
+            return Err(Errno::EAGAIN);
+        }
+        state.set(ino, owner, pid, start, end, kind);
+        drop(state);
+        // Converting a write lock to a read lock or shrinking a range can
+        // unblock waiters too.
+        self.released.notify_all();
+        Ok(())
+    }
+
+    /// A place for one more blocking request, or `None` if
+    /// [`MAX_WAITERS`] already wait.
+    pub(crate) fn reserve_waiter(self: &Arc<Self>) -> Option<WaiterSlot> {
+        self.waiters
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_WAITERS).then_some(n + 1)
+            })
+            .ok()?;
+        Some(WaiterSlot(Arc::clone(self)))
+    }
+
+    /// `F_SETLKW`: like [`Self::lock`], but wait for conflicting locks to
+    /// go away. Fails with `EDEADLK` instead of waiting forever, and with
+    /// `EINTR` if the owner closes the file meanwhile.
+    pub(crate) fn lock_wait(
+        &self,
+        ino: Inode,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: Option<LockKind>,
+    ) -> Result<(), Errno> {
+        let Some(kind) = kind else {
+            return self.lock(ino, owner, pid, start, end, None);
+        };
+        let want = Pending {
+            owner,
+            ino,
+            start,
+            end,
+            kind,
+        };
+        let mut state = self.state();
+        let mut ticket = None;
+        while state
+            .conflicts(ino, owner, start, end, kind)
+            .next()
+            .is_some()
+        {
+            let id = match ticket {
+                Some(id) if !state.waiting.contains_key(&id) => return Err(Errno::EINTR),
+                Some(id) => id,
+                None => {
+                    let id = state.next_ticket;
+                    state.next_ticket += 1;
+                    ticket = Some(id);
+                    id
+                }
+            };
+            if state.would_deadlock(owner, want) {
+                state.waiting.remove(&id);
+                return Err(Errno::EDEADLK);
+            }
+            state.waiting.insert(id, want);
+            state = self
+                .released
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if let Some(id) = ticket
+            && state.waiting.remove(&id).is_none()
+        {
+            return Err(Errno::EINTR);
+        }
+        state.set(ino, owner, pid, start, end, Some(kind));
+        drop(state);
+        self.released.notify_all();
+        Ok(())
+    }
+
+    /// Drop every lock `owner` holds on `ino`, and cancel its pending
+    /// requests there (close of any of its fds).
+    pub(crate) fn unlock_owner(&self, ino: Inode, owner: u64) {
+        self.state().cancel_waiting(ino, |p| p.owner == owner);
+        self.lock(ino, owner, 0, 0, u64::MAX, None)
+            .expect("unlocking never conflicts");
+    }
+
+    /// Drop every lock and pending request on `ino` (the inode was freed).
+    pub(crate) fn forget_inode(&self, ino: Inode) {
+        let mut state = self.state();
+        let cancelled = state.cancel_waiting(ino, |_| true);
+        if state.locks.remove(&ino).is_some() || cancelled {
+            drop(state);
+            self.released.notify_all();
+        }
+    }
+
+    #[cfg(test)]
+    fn held(&self, ino: Inode) -> Vec<RangeLock> {
+        let mut locks = self.state().locks.get(&ino).cloned().unwrap_or_default();
+        locks.sort_by_key(|l| (l.owner, l.start));
+        locks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    const INO: Inode = 7;
+    const EOF: u64 = i64::MAX as u64;
+    use LockKind::{Read, Write};
+
+    #[test]
+    fn readers_share_writers_exclude() {
+        let t = LockTable::default();
+        t.lock(INO, 1, 100, 0, 9, Some(Read)).unwrap();
+        t.lock(INO, 2, 200, 5, 20, Some(Read)).unwrap();
+        assert_eq!(t.lock(INO, 3, 300, 9, 9, Some(Write)), Err(Errno::EAGAIN));
+        // Outside every held range a writer is fine.
+        t.lock(INO, 3, 300, 21, EOF, Some(Write)).unwrap();
+        let blocker = t.test(INO, 1, 30, 30, Read).unwrap();
+        assert_eq!((blocker.owner, blocker.pid, blocker.kind), (3, 300, Write));
+        assert!(t.test(INO, 3, 0, EOF, Write).is_some());
+        assert!(t.test(8, 3, 0, EOF, Write).is_none(), "locks are per inode");
+    }
+
+    #[test]
+    fn owner_never_conflicts_with_itself_and_upgrades_in_place() {
+        let t = LockTable::default();
+        t.lock(INO, 1, 100, 0, 99, Some(Read)).unwrap();
+        t.lock(INO, 1, 100, 0, 99, Some(Write)).unwrap();
+        assert_eq!(t.held(INO).len(), 1);
+        assert_eq!(t.held(INO)[0].kind, Write);
+    }
+
+    #[test]
+    fn partial_unlock_splits_and_relock_coalesces() {
+        let t = LockTable::default();
+        t.lock(INO, 1, 100, 0, 99, Some(Write)).unwrap();
+        t.lock(INO, 1, 100, 40, 59, None).unwrap();
+        let ranges: Vec<_> = t.held(INO).iter().map(|l| (l.start, l.end)).collect();
+        assert_eq!(ranges, [(0, 39), (60, 99)]);
+        t.lock(INO, 2, 200, 40, 59, Some(Write)).unwrap();
+        t.unlock_owner(INO, 2);
+        t.lock(INO, 1, 100, 40, 59, Some(Write)).unwrap();
+        let ranges: Vec<_> = t.held(INO).iter().map(|l| (l.start, l.end)).collect();
+        assert_eq!(ranges, [(0, 99)]);
+    }
+
+    #[test]
+    fn unlock_owner_leaves_other_owners_alone() {
+        let t = LockTable::default();
+        t.lock(INO, 1, 100, 0, 9, Some(Read)).unwrap();
+        t.lock(INO, 2, 200, 0, 9, Some(Read)).unwrap();
+        t.unlock_owner(INO, 1);
+        assert_eq!(t.held(INO).len(), 1);
+        assert_eq!(t.held(INO)[0].owner, 2);
+        t.forget_inode(INO);
+        assert!(t.held(INO).is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_lock_type() {
+        assert_eq!(LockKind::from_raw(42), Err(Errno::EINVAL));
+        assert_eq!(LockKind::from_raw(libc::F_UNLCK), Ok(None));
+        assert_eq!(LockKind::from_raw(Write.to_raw()), Ok(Some(Write)));
+    }
+
+    #[test]
+    fn blocking_lock_waits_for_release() {
+        let t = Arc::new(LockTable::default());
+        t.lock(INO, 1, 100, 0, EOF, Some(Write)).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let t = Arc::clone(&t);
+            thread::spawn(move || {
+                t.lock_wait(INO, 2, 200, 0, 0, Some(Write)).unwrap();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(
+            rx.recv_timeout(Duration::from_millis(100)).is_err(),
+            "must block while owner 1 holds the lock"
+        );
+        t.unlock_owner(INO, 1);
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("release must wake the waiter");
+        waiter.join().unwrap();
+        assert_eq!(t.held(INO)[0].owner, 2);
+    }
+
+    #[test]
+    fn blocking_lock_detects_deadlock() {
+        let t = Arc::new(LockTable::default());
+        t.lock(INO, 1, 100, 0, 0, Some(Write)).unwrap();
+        t.lock(INO, 2, 200, 1, 1, Some(Write)).unwrap();
+        // Owner 1 waits for owner 2's byte...
+        let waiter = {
+            let t = Arc::clone(&t);
+            thread::spawn(move || t.lock_wait(INO, 1, 100, 1, 1, Some(Write)))
+        };
+        while !t.state().waiting.values().any(|p| p.owner == 1) {
+            thread::yield_now();
+        }
+        // ...so owner 2 waiting for owner 1's byte would never finish.
+        assert_eq!(
+            t.lock_wait(INO, 2, 200, 0, 0, Some(Write)),
+            Err(Errno::EDEADLK)
+        );
+        t.unlock_owner(INO, 2);
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn closing_the_file_cancels_the_owners_wait() {
+        let t = Arc::new(LockTable::default());
+        t.lock(INO, 1, 100, 0, EOF, Some(Write)).unwrap();
+        let waiter = {
+            let t = Arc::clone(&t);
+            thread::spawn(move || t.lock_wait(INO, 2, 200, 0, 0, Some(Write)))
+        };
+        while t.state().waiting.is_empty() {
+            thread::yield_now();
+        }
+        // Owner 2 was killed while waiting: its fds are flushed first.
+        t.unlock_owner(INO, 2);
+        assert_eq!(waiter.join().unwrap(), Err(Errno::EINTR));
+        t.unlock_owner(INO, 1);
+        assert!(t.held(INO).is_empty(), "a dead owner must not get the lock");
+        assert!(t.state().waiting.is_empty());
+
+        t.lock(INO, 1, 100, 0, EOF, Some(Write)).unwrap();
+        let waiter = {
+            let t = Arc::clone(&t);
+            thread::spawn(move || t.lock_wait(INO, 2, 200, 0, 0, Some(Write)))
+        };
+        while t.state().waiting.is_empty() {
+            thread::yield_now();
+        }
+        t.forget_inode(INO);
+        assert_eq!(waiter.join().unwrap(), Err(Errno::EINTR));
+    }
+
+    #[test]
+    fn blocking_requests_are_bounded() {
+        let t = Arc::new(LockTable::default());
+        let slots: Vec<_> = (0..MAX_WAITERS)
+            .map(|_| t.reserve_waiter().unwrap())
+            .collect();
+        assert!(t.reserve_waiter().is_none());
+        drop(slots);
+        assert!(t.reserve_waiter().is_some());
+    }
+}
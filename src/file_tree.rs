@@ -1,13 +1,17 @@
+use crate::archive_io::{ArchiveFingerprint, LoadOptions};
+use crate::holes::Holes;
+use crate::id_map::{ArchivedOwner, IdMap};
 use fuser::{Errno, FileAttr, FileType, INodeNo, TimeOrNow};
 #[cfg(unix)]
 use nix::unistd::{Gid, Group, Uid, User};
 #[allow(deprecated)]
 use pna::Permission;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::SystemTime;
 
 pub(crate) type Inode = u64;
@@ -242,6 +246,12 @@ pub(crate) struct FsNode {
     /// property test (`plain_save_is_byte_identical_when_replayed`)
     /// caught.
     pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Hole extents of a regular file's data (see [`Holes`]); empty for
+    /// every other kind.
+    pub holes: Holes,
+    /// Owner recorded in the archive, for nodes loaded with one; save
+    /// writes it back unless the node has been chowned since.
+    pub archived_owner: Option<ArchivedOwner>,
     /// Live count of file descriptors held by clients for this inode.
     /// `(attr.nlink, open_count)` drives the inode lifecycle:
     ///
@@ -278,6 +288,8 @@ impl FsNode {
             name,
             parent: None,
             xattrs: BTreeMap::new(),
+            holes: Holes::default(),
+            archived_owner: None,
             open_count: AtomicU32::new(0),
             content,
             attr: FileAttr {
@@ -310,30 +322,138 @@ impl std::fmt::Debug for FsNode {
     }
 }
 
+/// Kernel caches to invalidate after a live reload swapped in a new tree;
+/// produced by [`FileTree::adopt_inodes`].
+#[derive(Debug, Default)]
+pub(crate) struct ReloadChanges {
+    /// Inodes whose attributes or content differ from before the reload.
+    pub inodes: Vec<Inode>,
+    /// `(parent, name)` directory entries that were removed, added, or now
+    /// resolve to a different inode.
+    pub entries: Vec<(Inode, OsString)>,
+}
+
+/// An entry left out by `--include` / `--exclude`, kept for save to
+/// write back. It is filed under the deepest directory of its path that
+/// is shown, so it moves along when that directory is renamed.
+pub(crate) struct HiddenEntry {
+    /// The entry exactly as read.
+    pub entry: pna::NormalEntry<Vec<u8>>,
+    /// Its path below the directory it is filed under.
+    pub rel: PathBuf,
+    /// Position among all hidden entries, in archive order.
+    pub seq: usize,
+    /// The shown entry it followed in the archive, if any.
+    pub after: Option<Inode>,
+    /// For a hard link, the entry its reference named at load.
+    pub source: Option<LinkSource>,
+}
+
+/// What a hidden hard link refers to.
+pub(crate) struct LinkSource {
+    /// The archive path its reference named at load.
+    pub path: String,
+    /// The shown node at that path, which it follows wherever it moves;
+    /// `None` if the source is hidden too.
+    pub node: Option<Inode>,
+}
+
+/// Shared view of one node, holding its per-inode read lock.
+pub(crate) type NodeRef<'a> = RwLockReadGuard<'a, FsNode>;
+
+/// Exclusive view of one node, holding its per-inode write lock.
+pub(crate) type NodeMut<'a> = RwLockWriteGuard<'a, FsNode>;
+
+/// The in-memory inode table.
+///
+/// Locking is two-level. The caller wraps the whole tree in a `RwLock`:
+/// structural operations (create, link, rename, unlink, save) take it for
+/// writing and see every node without further locking (`get_mut`).
+/// Per-file data operations — [`Self::write_file`], [`Self::set_size`],
+/// [`Self::fallocate`], [`Self::copy_file_range`] and reads — take `&self`,
+/// so they only need the tree's *read* lock plus the per-inode lock of the
+/// node they touch, and writers to unrelated files run in parallel. Only
+/// [`Self::copy_file_range`] holds two node locks at once, and it takes
+/// them in ascending inode order; anything else that ever needs two must
+/// do the same.
 pub(crate) struct FileTree {
-    inodes: HashMap<Inode, FsNode>,
-    next_inode: Inode,
+    inodes: HashMap<Inode, RwLock<FsNode>>,
+    /// Inode numbers in use, which a new entry must not collide with.
+    /// Numbers are released when their inode is freed, so a path deleted
+    /// and recreated gets its path-derived number back, under the next
+    /// generation (see [`Self::generation`]).
+    issued: HashSet<Inode>,
+    /// How often each number was released. The kernel may still cache the
+    /// freed inode, so a (number, generation) pair must never be reused.
+    generations: HashMap<Inode, u64>,
     password: Option<String>,
     archive_path: PathBuf,
-    dirty: bool,
+    /// Identity of the on-disk archive this tree was loaded from (or last
+    /// saved to). `None` for trees that were never backed by an existing
+    /// archive, which skips the external-modification check on save.
+    origin: Option<ArchiveFingerprint>,
+    /// Atomic so data operations can set it under the tree's read lock.
+    dirty: AtomicBool,
+    /// Whether the unsaved changes go beyond data written through open
+    /// files: structure, metadata or sizes. `--write-strategy immediate`
+    /// saves those on the next close of any file.
+    unsaved_metadata: AtomicBool,
+    /// Save sparse files with their holes cut out (`--sparse`).
+    store_holes: bool,
+    /// How the archive was loaded; owner mappings are reversed on save.
+    load_options: LoadOptions,
+    /// The archive's top-level directory. [`ROOT_INODE`], the mount root,
+    /// unless [`Self::reroot`] made a subdirectory the mount root.
+    archive_root: Inode,
+    /// Entries left out by `--include` / `--exclude`, by the directory
+    /// they are filed under. Such a directory is never empty.
+    hidden: HashMap<Inode, Vec<HiddenEntry>>,
 }
 
-// Static assertion: FileTree must be Send so it can live in RwLock<FileTree>.
+// Static assertion: FileTree must be Send + Sync so it can live in
+// RwLock<FileTree> and serve data operations from several threads.
 const _: fn() = || {
-    fn assert_send<T: Send>() {}
-    assert_send::<FileTree>();
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FileTree>();
 };
 
+/// Node locks recover from poisoning. A node lock only ever covers updates
+/// to that one node's bytes and attributes, which leave no cross-node
+/// invariant half-applied; the fail-stop policy for panics lives on the
+/// tree-level lock (see `PnaFS::read_tree`).
+fn read_node(cell: &RwLock<FsNode>) -> NodeRef<'_> {
+    cell.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_node(cell: &RwLock<FsNode>) -> NodeMut<'_> {
+    cell.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn node_mut(cell: &mut RwLock<FsNode>) -> &mut FsNode {
+    cell.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn into_node(cell: RwLock<FsNode>) -> FsNode {
+    cell.into_inner().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl FileTree {
     /// Constructs a bare empty tree (no root node). Used by `archive_io::load`
     /// which inserts the root node itself after calling this.
     pub(crate) fn new(archive_path: PathBuf, password: Option<String>) -> Self {
         Self {
             inodes: HashMap::new(),
-            next_inode: ROOT_INODE,
+            issued: HashSet::from([ROOT_INODE]),
+            generations: HashMap::new(),
             password,
             archive_path,
-            dirty: false,
+            origin: None,
+            dirty: AtomicBool::new(false),
+            unsaved_metadata: AtomicBool::new(false),
+            store_holes: false,
+            load_options: LoadOptions::default(),
+            archive_root: ROOT_INODE,
+            hidden: HashMap::new(),
         }
     }
 
@@ -341,6 +461,105 @@ impl FileTree {
         &self.archive_path
     }
 
+    pub(crate) fn origin(&self) -> Option<&ArchiveFingerprint> {
+        self.origin.as_ref()
+    }
+
+    pub(crate) fn set_origin(&mut self, origin: ArchiveFingerprint) {
+        self.origin = Some(origin);
+    }
+
+    pub(crate) fn stores_holes(&self) -> bool {
+        self.store_holes
+    }
+
+    pub(crate) fn set_store_holes(&mut self, store_holes: bool) {
+        self.store_holes = store_holes;
+    }
+
+    pub(crate) fn load_options(&self) -> &LoadOptions {
+        &self.load_options
+    }
+
+    pub(crate) fn set_load_options(&mut self, load_options: LoadOptions) {
+        self.load_options = load_options;
+    }
+
+    /// Keep `entry` out of the mount but in the archive. `after` is the
+    /// path of the shown entry it followed, and `source` a hard link's
+    /// reference. Call once the shown entries are all in the tree.
+    pub(crate) fn hide(
+        &mut self,
+        entry: pna::NormalEntry<Vec<u8>>,
+        after: Option<&Path>,
+        source: Option<&Path>,
+    ) {
+        let path = entry.header().path().as_path().to_path_buf();
+        let mut dir = ROOT_INODE;
+        let mut rel = path.as_path();
+        while let Some((first, rest)) = split_first(rel)
+            && rest.components().next().is_some()
+            && let Some(child) = self.lookup_child(dir, first.as_os_str())
+            && child.attr.kind == FileType::Directory
+        {
+            dir = child.attr.ino.0;
+            rel = rest;
+        }
+        let hidden = HiddenEntry {
+            rel: rel.to_path_buf(),
+            seq: self.hidden.values().map(Vec::len).sum(),
+            after: after.and_then(|p| self.resolve_path(p)),
+            source: source.map(|s| LinkSource {
+                path: s.to_string_lossy().into_owned(),
+                node: self.resolve_path(s),
+            }),
+            entry,
+        };
+        self.hidden.entry(dir).or_default().push(hidden);
+    }
+
+    /// Hidden entries by the directory they are filed under.
+    pub(crate) fn hidden(&self) -> &HashMap<Inode, Vec<HiddenEntry>> {
+        &self.hidden
+    }
+
+    /// The archive's top-level directory, which has no entry of its own.
+    pub(crate) fn archive_root(&self) -> Inode {
+        self.archive_root
+    }
+
+    fn has_hidden(&self, dir: Inode) -> bool {
+        self.hidden.contains_key(&dir)
+    }
+
+    /// Renumber the inodes hidden entries refer to, after [`Self::reroot`]
+    /// or [`Self::adopt_inodes`] renumbered the nodes.
+    fn renumber_hidden(&mut self, map: impl Fn(Inode) -> Inode) {
+        self.hidden = std::mem::take(&mut self.hidden)
+            .into_iter()
+            .map(|(dir, mut entries)| {
+                for hidden in &mut entries {
+                    hidden.after = hidden.after.map(&map);
+                    if let Some(source) = &mut hidden.source {
+                        source.node = source.node.map(&map);
+                    }
+                }
+                (map(dir), entries)
+            })
+            .collect();
+    }
+
+    pub(crate) fn ids(&self) -> &IdMap {
+        &self.load_options.ids
+    }
+
+    /// A directory that has no archive entry of its own: the root, or a
+    /// parent synthesized at load.
+    pub(crate) fn synthesized_dir(&self, ino: Inode, name: OsString) -> FsNode {
+        let owner = Owner::new(self.ids().uid(None), self.ids().gid(None));
+        make_dir_node(ino, name, self.load_options.dir_mode, owner)
+    }
+
     pub(crate) fn password(&self) -> Option<&str> {
         self.password.as_deref()
     }
@@ -351,15 +570,39 @@ impl FileTree {
     }
 
     pub(crate) fn is_dirty(&self) -> bool {
-        self.dirty
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// Whether there are unsaved changes other than data written through
+    /// open files.
+    pub(crate) fn has_unsaved_metadata(&self) -> bool {
+        self.unsaved_metadata.load(Ordering::Acquire)
     }
 
-    pub(crate) fn get(&self, ino: Inode) -> Option<&FsNode> {
-        self.inodes.get(&ino)
+    fn set_dirty(&self) {
+        self.unsaved_metadata.store(true, Ordering::Release);
+        self.dirty.store(true, Ordering::Release);
     }
 
+    /// Like [`Self::set_dirty`], for data written through an open file,
+    /// which is saved when that file is closed.
+    fn set_data_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Shared access to `ino`, taking its node read lock.
+    pub(crate) fn get(&self, ino: Inode) -> Option<NodeRef<'_>> {
+        self.inodes.get(&ino).map(read_node)
+    }
+
+    /// Exclusive access to `ino` for data operations under a shared tree.
+    pub(crate) fn lock(&self, ino: Inode) -> Option<NodeMut<'_>> {
+        self.inodes.get(&ino).map(write_node)
+    }
+
+    /// Exclusive access to `ino` through an exclusive tree; no locking.
     pub(crate) fn get_mut(&mut self, ino: Inode) -> Option<&mut FsNode> {
-        self.inodes.get_mut(&ino)
+        self.inodes.get_mut(&ino).map(node_mut)
     }
 
     /// Walk the directory tree starting from root and resolve `path` to its
@@ -378,13 +621,15 @@ impl FileTree {
         Some(current)
     }
 
-    pub(crate) fn lookup_child(&self, parent: Inode, name: &OsStr) -> Option<&FsNode> {
-        let parent_node = self.inodes.get(&parent)?;
-        match &parent_node.content {
-            FsContent::Directory(dir) => {
-                let child_ino = dir.get(name)?;
-                self.inodes.get(&child_ino)
-            }
+    pub(crate) fn lookup_child(&self, parent: Inode, name: &OsStr) -> Option<NodeRef<'_>> {
+        self.get(self.child_ino(parent, name)?)
+    }
+
+    /// The inode `name` currently links to under `parent`, without taking
+    /// the child's lock.
+    pub(crate) fn child_ino(&self, parent: Inode, name: &OsStr) -> Option<Inode> {
+        match &self.get(parent)?.content {
+            FsContent::Directory(dir) => dir.get(name),
             _ => None,
         }
     }
@@ -395,22 +640,63 @@ impl FileTree {
     pub(crate) fn children(
         &self,
         parent: Inode,
-    ) -> Option<impl Iterator<Item = (&OsStr, &FsNode)>> {
-        let parent_node = self.inodes.get(&parent)?;
-        match &parent_node.content {
-            FsContent::Directory(dir) => {
-                let inodes = &self.inodes;
-                Some(dir.iter().filter_map(move |(name, &ino)| {
-                    inodes.get(&ino).map(|n| (name.as_os_str(), n))
-                }))
-            }
+    ) -> Option<impl Iterator<Item = (OsString, NodeRef<'_>)>> {
+        // Snapshot the listing so the directory's own lock is not held
+        // while each child's lock is taken.
+        let entries = self.listing(parent)?;
+        Some(
+            entries
+                .into_iter()
+                .filter_map(move |(name, ino)| self.get(ino).map(|n| (name, n))),
+        )
+    }
+
+    /// A copy of a directory's `(name, inode)` entries, in name order.
+    pub(crate) fn listing(&self, parent: Inode) -> Option<Vec<(OsString, Inode)>> {
+        match &self.get(parent)?.content {
+            FsContent::Directory(dir) => Some(dir.iter().map(|(n, &i)| (n.clone(), i)).collect()),
             _ => None,
         }
     }
 
-    pub(crate) fn next_inode(&mut self) -> Inode {
-        self.next_inode += 1;
-        self.next_inode
+    /// Allocate the inode number for a new entry `name` under `parent`.
+    ///
+    /// Numbers are a hash of the entry's path ([`stable_inode`]) rather
+    /// than a counter, so the same archive path gets the same `st_ino` on
+    /// every mount and reload — tools like rsync `--inplace` and backup
+    /// software key on it. On a collision with a number this tree has
+    /// already issued, the hash is re-derived with an attempt counter,
+    /// which keeps the outcome deterministic for a given archive.
+    pub(crate) fn alloc_inode(&mut self, parent: Inode, name: &OsStr) -> Inode {
+        let mut path = self.path_bytes(parent);
+        if !path.is_empty() {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name.as_encoded_bytes());
+        let issued = &self.issued;
+        let ino = probe_inode(&path, |ino| issued.contains(&ino));
+        self.issued.insert(ino);
+        ino
+    }
+
+    /// Archive-style path of `ino` (`"dir/sub"`; empty for the archive
+    /// root), following the `parent` pointers, i.e. each inode's primary
+    /// link.
+    fn path_bytes(&self, ino: Inode) -> Vec<u8> {
+        let mut names = Vec::new();
+        let mut cur = ino;
+        while cur != self.archive_root {
+            let Some(node) = self.get(cur) else {
+                break;
+            };
+            names.push(node.name.as_encoded_bytes().to_vec());
+            match node.parent {
+                Some(p) => cur = p,
+                None => break,
+            }
+        }
+        names.reverse();
+        names.join(&b'/')
     }
 
     /// Insert a node into the tree under `parent` (or as root when `parent` is `None`).
@@ -431,8 +717,7 @@ impl FileTree {
 
         if let Some(p) = parent {
             let parent_node = self
-                .inodes
-                .get_mut(&p)
+                .get_mut(p)
                 .ok_or_else(|| io::Error::other(format!("parent inode {p} not found")))?;
             match &mut parent_node.content {
                 FsContent::Directory(dir) => {
@@ -446,7 +731,7 @@ impl FileTree {
             }
         }
 
-        self.inodes.insert(ino, node);
+        self.inodes.insert(ino, RwLock::new(node));
         Ok(ino)
     }
 
@@ -455,7 +740,7 @@ impl FileTree {
     /// Validate that `parent` exists, is a directory, and has no child named
     /// `name`. Used at the start of every create_* path.
     fn validate_parent_for_create(&self, parent: Inode, name: &OsStr) -> Result<(), Errno> {
-        let parent_node = self.inodes.get(&parent).ok_or(Errno::ENOENT)?;
+        let parent_node = self.get(parent).ok_or(Errno::ENOENT)?;
         if !matches!(parent_node.content, FsContent::Directory(_)) {
             return Err(Errno::ENOTDIR);
         }
@@ -467,7 +752,7 @@ impl FileTree {
 
     /// Touch parent timestamps (mtime + ctime) after structural changes.
     fn touch_parent(&mut self, parent: Inode, now: SystemTime) {
-        if let Some(p) = self.inodes.get_mut(&parent) {
+        if let Some(p) = self.get_mut(parent) {
             p.attr.mtime = now;
             p.attr.ctime = now;
         }
@@ -478,7 +763,7 @@ impl FileTree {
     /// freed iff no fd is currently open against it (otherwise it lives on
     /// as an orphan, freed by the matching `release_open`).
     fn drop_link(&mut self, ino: Inode, now: SystemTime) {
-        if let Some(node) = self.inodes.get_mut(&ino) {
+        if let Some(node) = self.get_mut(ino) {
             if node.attr.nlink > 1 {
                 node.attr.nlink -= 1;
                 node.attr.ctime = now;
@@ -495,19 +780,31 @@ impl FileTree {
     /// the write lock after decrementing `nlink`; `release_open` schedules
     /// a write-locked call when its decrement transitions to zero.
     fn maybe_free_inode(&mut self, ino: Inode) {
-        if let Some(node) = self.inodes.get(&ino)
+        if let Some(node) = self.get_mut(ino)
             && node.attr.nlink == 0
             && node.open_count.load(Ordering::Acquire) == 0
         {
-            self.inodes.remove(&ino);
+            self.free(ino);
         }
     }
 
+    /// Drop `ino` and release its number for reuse under a new generation.
+    fn free(&mut self, ino: Inode) {
+        self.inodes.remove(&ino);
+        self.issued.remove(&ino);
+        *self.generations.entry(ino).or_default() += 1;
+    }
+
+    /// The generation of inode number `ino`, to report with it.
+    pub(crate) fn generation(&self, ino: Inode) -> u64 {
+        self.generations.get(&ino).copied().unwrap_or(0)
+    }
+
     /// Bump the open-fd counter for `ino`. Takes `&self` so callers can hold
     /// the tree's read lock; the counter is atomic. Pairs 1:1 with
     /// `release_open`.
     pub(crate) fn bump_open(&self, ino: Inode) -> Result<(), Errno> {
-        let node = self.inodes.get(&ino).ok_or(Errno::ENOENT)?;
+        let node = self.get(ino).ok_or(Errno::ENOENT)?;
         node.open_count.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
@@ -518,7 +815,7 @@ impl FileTree {
     /// against unknown inodes (no-op + `false`).
     #[must_use]
     pub(crate) fn release_open(&self, ino: Inode) -> bool {
-        let Some(node) = self.inodes.get(&ino) else {
+        let Some(node) = self.get(ino) else {
             return false;
         };
         let prev = node.open_count.fetch_sub(1, Ordering::AcqRel);
@@ -540,7 +837,7 @@ impl FileTree {
         owner: Owner,
     ) -> Result<&FsNode, Errno> {
         self.validate_parent_for_create(parent, name)?;
-        let ino = self.next_inode();
+        let ino = self.alloc_inode(parent, name);
         let node = FsNode::new_node(
             ino,
             name.to_owned(),
@@ -555,22 +852,39 @@ impl FileTree {
         self.insert_node(node, Some(parent))
             .map_err(|_| Errno::EIO)?;
         self.touch_parent(parent, SystemTime::now());
-        self.dirty = true;
-        Ok(self.inodes.get(&ino).unwrap())
+        self.set_dirty();
+        Ok(self.get_mut(ino).unwrap())
     }
 
-    pub(crate) fn write_file(
-        &mut self,
+    pub(crate) fn write_file(&self, ino: Inode, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        self.write_with(ino, data, |_| offset)
+    }
+
+    /// `O_APPEND` write: lands at the file's size as of taking the node
+    /// lock, rather than wherever the kernel's cached size says, so
+    /// concurrent appenders never overwrite each other.
+    pub(crate) fn append_file(&self, ino: Inode, data: &[u8]) -> Result<usize, Errno> {
+        self.write_with(ino, data, |node| node.attr.size)
+    }
+
+    /// Write `data` at the offset `at` picks while the node is locked.
+    fn write_with(
+        &self,
         ino: Inode,
-        offset: u64,
         data: &[u8],
+        at: impl FnOnce(&FsNode) -> u64,
     ) -> Result<usize, Errno> {
         if data.is_empty() {
             return Ok(0);
         }
-        let offset = usize::try_from(offset).map_err(|_| Errno::EFBIG)?;
+        let mut node = self.lock(ino).ok_or(Errno::ENOENT)?;
+        let offset = at(&node);
+        self.write_locked(&mut node, offset, data)
+    }
 
-        let node = self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)?;
+    /// Write `data` at `offset` into `node`, whose lock the caller holds.
+    fn write_locked(&self, node: &mut FsNode, offset: u64, data: &[u8]) -> Result<usize, Errno> {
+        let offset = usize::try_from(offset).map_err(|_| Errno::EFBIG)?;
         let file_data = match &mut node.content {
             FsContent::Directory(_) => return Err(Errno::EISDIR),
             FsContent::Symlink(_) | FsContent::Special(_) => return Err(Errno::EINVAL),
@@ -579,9 +893,12 @@ impl FileTree {
         file_data.promote_to_dirty();
         let buf = file_data.data_mut();
         if offset > buf.len() {
+            // Writing past the end leaves a hole behind.
+            node.holes.punch(buf.len() as u64, offset as u64);
             buf.resize(offset, 0);
         }
         let end = offset.checked_add(data.len()).ok_or(Errno::EFBIG)?;
+        node.holes.fill(offset as u64, end as u64);
         if end > buf.len() {
             buf.resize(end, 0);
         }
@@ -590,7 +907,7 @@ impl FileTree {
         let now = SystemTime::now();
         node.attr.mtime = now;
         node.attr.ctime = now;
-        self.dirty = true;
+        self.set_data_dirty();
         Ok(data.len())
     }
 
@@ -605,7 +922,7 @@ impl FileTree {
     /// Anything else (collapse / insert range, or `PUNCH_HOLE` without
     /// `KEEP_SIZE`) returns the matching errno so callers can fall back.
     pub(crate) fn fallocate(
-        &mut self,
+        &self,
         ino: Inode,
         offset: u64,
         length: u64,
@@ -629,7 +946,8 @@ impl FileTree {
             return Err(Errno::EINVAL);
         }
 
-        let node = self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)?;
+        let mut node = self.lock(ino).ok_or(Errno::ENOENT)?;
+        let node = &mut *node;
         let file_data = match &mut node.content {
             FsContent::Directory(_) => return Err(Errno::EISDIR),
             FsContent::Symlink(_) | FsContent::Special(_) => return Err(Errno::EINVAL),
@@ -648,25 +966,35 @@ impl FileTree {
             let zero_end = end.min(logical_size).min(buf.len());
             if offset < zero_end {
                 buf[offset..zero_end].fill(0);
+                node.holes.punch(offset as u64, zero_end as u64);
             }
         } else if zero_range {
             // Grow first, then zero the whole range — `resize` only zeros
             // the *new* tail, so any pre-existing bytes inside [offset..end)
             // need an explicit fill.
+            if offset > buf.len() {
+                node.holes.punch(buf.len() as u64, offset as u64);
+            }
             if end > buf.len() {
                 buf.resize(end, 0);
             }
             if offset < end {
                 buf[offset..end].fill(0);
             }
+            node.holes.fill(offset as u64, end as u64);
             if !keep_size && end as u64 > node.attr.size {
                 node.attr.size = end as u64;
             }
         } else {
-            // Plain fallocate: grow buffer, optionally update size.
+            // Plain fallocate: grow buffer, optionally update size. The
+            // range is allocated, so it is data, not a hole.
+            if offset > buf.len() {
+                node.holes.punch(buf.len() as u64, offset as u64);
+            }
             if end > buf.len() {
                 buf.resize(end, 0);
             }
+            node.holes.fill(offset as u64, end as u64);
             if !keep_size && end as u64 > node.attr.size {
                 node.attr.size = end as u64;
             }
@@ -675,7 +1003,7 @@ impl FileTree {
         let now = SystemTime::now();
         node.attr.mtime = now;
         node.attr.ctime = now;
-        self.dirty = true;
+        self.set_data_dirty();
         Ok(())
     }
 
@@ -684,8 +1012,12 @@ impl FileTree {
     /// zero (if `src_offset` is at or past EOF). Source and destination
     /// may be the same inode, including overlapping ranges — we read into
     /// an owned buffer first, so any overlap resolves cleanly.
+    ///
+    /// Both node locks are held for the whole copy, so it is atomic with
+    /// respect to writes to either file. They are taken in inode order,
+    /// the one order any operation holding two node locks must use.
     pub(crate) fn copy_file_range(
-        &mut self,
+        &self,
         src_ino: Inode,
         src_offset: u64,
         dst_ino: Inode,
@@ -693,44 +1025,53 @@ impl FileTree {
         len: u64,
     ) -> Result<usize, Errno> {
         let src_offset = usize::try_from(src_offset).map_err(|_| Errno::EFBIG)?;
-        let dst_offset_u64 = dst_offset;
         let len = usize::try_from(len).map_err(|_| Errno::EFBIG)?;
         if len == 0 {
             return Ok(0);
         }
 
-        let chunk: Vec<u8> = {
-            let src_node = self.inodes.get(&src_ino).ok_or(Errno::ENOENT)?;
-            let src_data = match &src_node.content {
-                FsContent::File(fd) => fd.data(),
-                FsContent::Directory(_) => return Err(Errno::EISDIR),
-                FsContent::Symlink(_) | FsContent::Special(_) => return Err(Errno::EINVAL),
-            };
-            // Validate dst before touching anything.
-            let dst_node = self.inodes.get(&dst_ino).ok_or(Errno::ENOENT)?;
-            match &dst_node.content {
-                FsContent::File(_) => {}
-                FsContent::Directory(_) => return Err(Errno::EISDIR),
-                FsContent::Symlink(_) | FsContent::Special(_) => return Err(Errno::EINVAL),
+        let check = |node: &FsNode| match &node.content {
+            FsContent::File(_) => Ok(()),
+            FsContent::Directory(_) => Err(Errno::EISDIR),
+            FsContent::Symlink(_) | FsContent::Special(_) => Err(Errno::EINVAL),
+        };
+        let chunk = |src: &FsNode| match &src.content {
+            FsContent::File(fd) if src_offset < fd.data().len() => {
+                let data = fd.data();
+                data[src_offset..src_offset.saturating_add(len).min(data.len())].to_vec()
             }
+            _ => Vec::new(),
+        };
 
-            let avail = src_data.len();
-            if src_offset >= avail {
+        if src_ino == dst_ino {
+            let mut node = self.lock(src_ino).ok_or(Errno::ENOENT)?;
+            check(&node)?;
+            let chunk = chunk(&node);
+            if chunk.is_empty() {
                 return Ok(0);
             }
-            let copy_end = (src_offset + len).min(avail);
-            src_data[src_offset..copy_end].to_vec()
+            return self.write_locked(&mut node, dst_offset, &chunk);
+        }
+        let (src, mut dst) = if src_ino < dst_ino {
+            let src = self.get(src_ino).ok_or(Errno::ENOENT)?;
+            (src, self.lock(dst_ino).ok_or(Errno::ENOENT)?)
+        } else {
+            let dst = self.lock(dst_ino).ok_or(Errno::ENOENT)?;
+            (self.get(src_ino).ok_or(Errno::ENOENT)?, dst)
         };
-
+        check(&src)?;
+        check(&dst)?;
+        let chunk = chunk(&src);
         if chunk.is_empty() {
             return Ok(0);
         }
-        // write_file does the sparse-grow + dirty bookkeeping for us.
-        self.write_file(dst_ino, dst_offset_u64, &chunk)
+        // write_locked does the sparse-grow + dirty bookkeeping for us.
+        self.write_locked(&mut dst, dst_offset, &chunk)
     }
 
-    pub(crate) fn set_size(&mut self, ino: Inode, size: u64) -> Result<(), Errno> {
-        let node = self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)?;
+    pub(crate) fn set_size(&self, ino: Inode, size: u64) -> Result<(), Errno> {
+        let mut node = self.lock(ino).ok_or(Errno::ENOENT)?;
+        let node = &mut *node;
         if size == node.attr.size {
             return Ok(());
         }
@@ -742,22 +1083,71 @@ impl FileTree {
         file_data.promote_to_dirty();
         let size_usize = usize::try_from(size).map_err(|_| Errno::EFBIG)?;
         let buf = file_data.data_mut();
+        if size_usize > buf.len() {
+            // Growing by truncate leaves a hole, as on any sparse-capable fs.
+            node.holes.punch(buf.len() as u64, size);
+        } else {
+            node.holes.truncate(size);
+        }
         buf.resize(size_usize, 0);
         node.attr.size = size;
         let now = SystemTime::now();
         node.attr.mtime = now;
         node.attr.ctime = now;
-        self.dirty = true;
+        self.set_dirty();
         Ok(())
     }
 
+    /// `lseek(SEEK_DATA)` (`data`) or `lseek(SEEK_HOLE)`: the next data or
+    /// hole offset at or after `offset`. `ENXIO` past the last one.
+    pub(crate) fn seek_hole_data(&self, ino: Inode, offset: u64, data: bool) -> Result<u64, Errno> {
+        let node = self.get(ino).ok_or(Errno::ENOENT)?;
+        match &node.content {
+            FsContent::File(_) => {}
+            FsContent::Directory(_) => return Err(Errno::EISDIR),
+            FsContent::Symlink(_) | FsContent::Special(_) => return Err(Errno::EINVAL),
+        }
+        let size = node.attr.size;
+        let found = if data {
+            node.holes.seek_data(offset, size)
+        } else {
+            node.holes.seek_hole(offset, size)
+        };
+        found.ok_or(Errno::ENXIO)
+    }
+
+    /// `open(O_TRUNC)`: empty the file, bumping mtime and ctime. A file
+    /// that is already empty is left alone, so that it does not make the
+    /// archive need saving. Returns whether the file changed.
+    pub(crate) fn truncate_on_open(&self, ino: Inode) -> Result<bool, Errno> {
+        let mut node = self.lock(ino).ok_or(Errno::ENOENT)?;
+        let node = &mut *node;
+        let file_data = match &mut node.content {
+            FsContent::Directory(_) => return Err(Errno::EISDIR),
+            FsContent::Symlink(_) | FsContent::Special(_) => return Err(Errno::EINVAL),
+            FsContent::File(fd) => fd,
+        };
+        if node.attr.size == 0 && file_data.data().is_empty() {
+            return Ok(false);
+        }
+        file_data.promote_to_dirty();
+        file_data.data_mut().clear();
+        node.attr.size = 0;
+        node.holes = Holes::default();
+        let now = SystemTime::now();
+        node.attr.mtime = now;
+        node.attr.ctime = now;
+        self.set_data_dirty();
+        Ok(true)
+    }
+
     pub(crate) fn set_times(
         &mut self,
         ino: Inode,
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
     ) -> Result<(), Errno> {
-        let node = self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)?;
+        let node = self.get_mut(ino).ok_or(Errno::ENOENT)?;
         let mut changed = false;
         match atime {
             Some(TimeOrNow::SpecificTime(t)) => {
@@ -784,7 +1174,7 @@ impl FileTree {
         if changed {
             // Per POSIX, modifying atime/mtime updates ctime too.
             node.attr.ctime = SystemTime::now();
-            self.dirty = true;
+            self.set_dirty();
         }
         Ok(())
     }
@@ -798,7 +1188,7 @@ impl FileTree {
         owner: Owner,
     ) -> Result<&FsNode, Errno> {
         self.validate_parent_for_create(parent, name)?;
-        let ino = self.next_inode();
+        let ino = self.alloc_inode(parent, name);
         let effective_mode = (mode & !umask) as u16;
         let node = FsNode::new_node(
             ino,
@@ -813,39 +1203,41 @@ impl FileTree {
         );
         self.insert_node(node, Some(parent))
             .map_err(|_| Errno::EIO)?;
-        if let Some(parent_node) = self.inodes.get_mut(&parent) {
+        if let Some(parent_node) = self.get_mut(parent) {
             parent_node.attr.nlink += 1;
         }
         self.touch_parent(parent, SystemTime::now());
-        self.dirty = true;
-        Ok(self.inodes.get(&ino).unwrap())
+        self.set_dirty();
+        Ok(self.get_mut(ino).unwrap())
     }
 
     pub(crate) fn unlink(&mut self, parent: Inode, name: &OsStr) -> Result<(), Errno> {
         let target_ino = {
-            let parent_node = self.inodes.get(&parent).ok_or(Errno::ENOENT)?;
+            let parent_node = self.get(parent).ok_or(Errno::ENOENT)?;
             let dir = match &parent_node.content {
                 FsContent::Directory(d) => d,
                 _ => return Err(Errno::ENOTDIR),
             };
             dir.get(name).ok_or(Errno::ENOENT)?
         };
-        let target = self.inodes.get(&target_ino).ok_or(Errno::ENOENT)?;
-        if matches!(target.content, FsContent::Directory(_)) {
+        if matches!(
+            self.get(target_ino).ok_or(Errno::ENOENT)?.content,
+            FsContent::Directory(_)
+        ) {
             #[cfg(target_os = "macos")]
             return Err(Errno::EPERM);
             #[cfg(not(target_os = "macos"))]
             return Err(Errno::EISDIR);
         }
         let now = SystemTime::now();
-        if let Some(parent_node) = self.inodes.get_mut(&parent)
+        if let Some(parent_node) = self.get_mut(parent)
             && let FsContent::Directory(dir) = &mut parent_node.content
         {
             dir.remove(name);
         }
         self.touch_parent(parent, now);
         self.drop_link(target_ino, now);
-        self.dirty = true;
+        self.set_dirty();
         Ok(())
     }
 
@@ -858,17 +1250,18 @@ impl FileTree {
     /// - `Clean` -> unchanged
     pub(crate) fn mark_clean(&mut self) {
         let has_password = self.password.is_some();
-        for node in self.inodes.values_mut() {
+        for node in self.inodes.values_mut().map(node_mut) {
             if let FsContent::File(ref mut file_data) = node.content {
                 file_data.make_clean(has_password);
             }
         }
-        self.dirty = false;
+        self.unsaved_metadata.store(false, Ordering::Release);
+        self.dirty.store(false, Ordering::Release);
     }
 
     pub(crate) fn rmdir(&mut self, parent: Inode, name: &OsStr) -> Result<(), Errno> {
         let target_ino = {
-            let parent_node = self.inodes.get(&parent).ok_or(Errno::ENOENT)?;
+            let parent_node = self.get(parent).ok_or(Errno::ENOENT)?;
             let dir = match &parent_node.content {
                 FsContent::Directory(d) => d,
                 _ => return Err(Errno::ENOTDIR),
@@ -878,16 +1271,14 @@ impl FileTree {
         if target_ino == ROOT_INODE {
             return Err(Errno::EBUSY);
         }
-        let target = self.inodes.get(&target_ino).ok_or(Errno::ENOENT)?;
-        let target_dir = match &target.content {
-            FsContent::Directory(d) => d,
+        match &self.get(target_ino).ok_or(Errno::ENOENT)?.content {
+            FsContent::Directory(d) if d.iter().next().is_some() => return Err(Errno::ENOTEMPTY),
+            FsContent::Directory(_) if self.has_hidden(target_ino) => return Err(Errno::ENOTEMPTY),
+            FsContent::Directory(_) => {}
             _ => return Err(Errno::ENOTDIR),
-        };
-        if target_dir.iter().next().is_some() {
-            return Err(Errno::ENOTEMPTY);
         }
         let now = SystemTime::now();
-        if let Some(parent_node) = self.inodes.get_mut(&parent) {
+        if let Some(parent_node) = self.get_mut(parent) {
             if let FsContent::Directory(dir) = &mut parent_node.content {
                 dir.remove(name);
             }
@@ -896,8 +1287,8 @@ impl FileTree {
             }
         }
         self.touch_parent(parent, now);
-        self.inodes.remove(&target_ino);
-        self.dirty = true;
+        self.free(target_ino);
+        self.set_dirty();
         Ok(())
     }
 
@@ -922,7 +1313,7 @@ impl FileTree {
         }
 
         let source_ino = {
-            let p = self.inodes.get(&old_parent).ok_or(Errno::ENOENT)?;
+            let p = self.get(old_parent).ok_or(Errno::ENOENT)?;
             let dir = match &p.content {
                 FsContent::Directory(d) => d,
                 _ => return Err(Errno::ENOTDIR),
@@ -930,7 +1321,7 @@ impl FileTree {
             dir.get(old_name).ok_or(Errno::ENOENT)?
         };
         {
-            let np = self.inodes.get(&new_parent).ok_or(Errno::ENOENT)?;
+            let np = self.get(new_parent).ok_or(Errno::ENOENT)?;
             if !matches!(np.content, FsContent::Directory(_)) {
                 return Err(Errno::ENOTDIR);
             }
@@ -942,7 +1333,7 @@ impl FileTree {
         }
 
         let dest_existing = {
-            let np = self.inodes.get(&new_parent).unwrap();
+            let np = self.get(new_parent).unwrap();
             if let FsContent::Directory(d) = &np.content {
                 d.get(new_name)
             } else {
@@ -961,10 +1352,9 @@ impl FileTree {
             );
         }
 
-        let source_is_dir = matches!(
-            self.inodes.get(&source_ino).map(|n| &n.content),
-            Some(FsContent::Directory(_))
-        );
+        let source_is_dir = self
+            .get(source_ino)
+            .is_some_and(|n| matches!(n.content, FsContent::Directory(_)));
 
         // If renaming a directory across parents, refuse to move it into its
         // own descendant subtree (would create a cycle).
@@ -974,7 +1364,7 @@ impl FileTree {
                 if cur == source_ino {
                     return Err(Errno::EINVAL);
                 }
-                walker = self.inodes.get(&cur).and_then(|n| n.parent);
+                walker = self.get(cur).and_then(|n| n.parent);
             }
         }
 
@@ -985,22 +1375,26 @@ impl FileTree {
             if noreplace {
                 return Err(Errno::EEXIST);
             }
-            let dest_node = self.inodes.get(&dest_ino).ok_or(Errno::ENOENT)?;
-            let dest_is_dir = matches!(dest_node.content, FsContent::Directory(_));
-            match (source_is_dir, dest_is_dir) {
-                (false, true) => return Err(Errno::EISDIR),
-                (true, false) => return Err(Errno::ENOTDIR),
-                (true, true) => {
-                    // Destination directory must be empty to be replaced.
-                    if let FsContent::Directory(d) = &dest_node.content
-                        && d.iter().next().is_some()
-                    {
-                        return Err(Errno::ENOTEMPTY);
+            let dest_is_dir = {
+                let dest_node = self.get(dest_ino).ok_or(Errno::ENOENT)?;
+                let dest_is_dir = matches!(dest_node.content, FsContent::Directory(_));
+                match (source_is_dir, dest_is_dir) {
+                    (false, true) => return Err(Errno::EISDIR),
+                    (true, false) => return Err(Errno::ENOTDIR),
+                    (true, true) => {
+                        // Destination directory must be empty to be replaced,
+                        // hidden entries included.
+                        if let FsContent::Directory(d) = &dest_node.content
+                            && (d.iter().next().is_some() || self.has_hidden(dest_ino))
+                        {
+                            return Err(Errno::ENOTEMPTY);
+                        }
                     }
+                    (false, false) => {}
                 }
-                (false, false) => {}
-            }
-            let np = self.inodes.get_mut(&new_parent).unwrap();
+                dest_is_dir
+            };
+            let np = self.get_mut(new_parent).unwrap();
             if let FsContent::Directory(d) = &mut np.content {
                 d.remove(new_name);
             }
@@ -1011,7 +1405,7 @@ impl FileTree {
             // a single parent and net out to zero, so they need no change.
             if dest_is_dir
                 && old_parent != new_parent
-                && let Some(np) = self.inodes.get_mut(&new_parent)
+                && let Some(np) = self.get_mut(new_parent)
                 && np.attr.nlink > 0
             {
                 np.attr.nlink -= 1;
@@ -1020,36 +1414,36 @@ impl FileTree {
         }
 
         {
-            let op = self.inodes.get_mut(&old_parent).unwrap();
+            let op = self.get_mut(old_parent).unwrap();
             if let FsContent::Directory(d) = &mut op.content {
                 d.remove(old_name);
             }
         }
         {
-            let np = self.inodes.get_mut(&new_parent).unwrap();
+            let np = self.get_mut(new_parent).unwrap();
             if let FsContent::Directory(d) = &mut np.content {
                 d.insert(new_name.to_owned(), source_ino);
             }
         }
         let now = SystemTime::now();
-        if let Some(node) = self.inodes.get_mut(&source_ino) {
+        if let Some(node) = self.get_mut(source_ino) {
             node.name = new_name.to_owned();
             node.parent = Some(new_parent);
             node.attr.ctime = now;
         }
         if source_is_dir && old_parent != new_parent {
-            if let Some(op) = self.inodes.get_mut(&old_parent)
+            if let Some(op) = self.get_mut(old_parent)
                 && op.attr.nlink > 0
             {
                 op.attr.nlink -= 1;
             }
-            if let Some(np) = self.inodes.get_mut(&new_parent) {
+            if let Some(np) = self.get_mut(new_parent) {
                 np.attr.nlink += 1;
             }
         }
         self.touch_parent(old_parent, now);
         self.touch_parent(new_parent, now);
-        self.dirty = true;
+        self.set_dirty();
         Ok(())
     }
 
@@ -1067,10 +1461,9 @@ impl FileTree {
     ) -> Result<(), Errno> {
         // Forbid moving a directory into its own subtree on either side.
         for (mover, into) in [(source_ino, new_parent), (dest_ino, old_parent)] {
-            let is_dir = matches!(
-                self.inodes.get(&mover).map(|n| &n.content),
-                Some(FsContent::Directory(_))
-            );
+            let is_dir = self
+                .get(mover)
+                .is_some_and(|n| matches!(n.content, FsContent::Directory(_)));
             if !is_dir {
                 continue;
             }
@@ -1079,26 +1472,26 @@ impl FileTree {
                 if cur == mover {
                     return Err(Errno::EINVAL);
                 }
-                walker = self.inodes.get(&cur).and_then(|n| n.parent);
+                walker = self.get(cur).and_then(|n| n.parent);
             }
         }
-        if let Some(op) = self.inodes.get_mut(&old_parent)
+        if let Some(op) = self.get_mut(old_parent)
             && let FsContent::Directory(d) = &mut op.content
         {
             d.insert(old_name.to_owned(), dest_ino);
         }
-        if let Some(np) = self.inodes.get_mut(&new_parent)
+        if let Some(np) = self.get_mut(new_parent)
             && let FsContent::Directory(d) = &mut np.content
         {
             d.insert(new_name.to_owned(), source_ino);
         }
         let now = SystemTime::now();
-        if let Some(n) = self.inodes.get_mut(&source_ino) {
+        if let Some(n) = self.get_mut(source_ino) {
             n.name = new_name.to_owned();
             n.parent = Some(new_parent);
             n.attr.ctime = now;
         }
-        if let Some(n) = self.inodes.get_mut(&dest_ino) {
+        if let Some(n) = self.get_mut(dest_ino) {
             n.name = old_name.to_owned();
             n.parent = Some(old_parent);
             n.attr.ctime = now;
@@ -1107,7 +1500,7 @@ impl FileTree {
         if old_parent != new_parent {
             self.touch_parent(new_parent, now);
         }
-        self.dirty = true;
+        self.set_dirty();
         Ok(())
     }
 
@@ -1118,7 +1511,7 @@ impl FileTree {
         uid: Option<u32>,
         gid: Option<u32>,
     ) -> Result<(), Errno> {
-        let node = self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)?;
+        let node = self.get_mut(ino).ok_or(Errno::ENOENT)?;
         let mut changed = false;
         if let Some(m) = mode {
             node.attr.perm = (m & 0o7777) as u16;
@@ -1134,7 +1527,7 @@ impl FileTree {
         }
         if changed {
             node.attr.ctime = SystemTime::now();
-            self.dirty = true;
+            self.set_dirty();
         }
         Ok(())
     }
@@ -1156,7 +1549,7 @@ impl FileTree {
         if flags & libc::XATTR_CREATE != 0 && flags & libc::XATTR_REPLACE != 0 {
             return Err(Errno::EINVAL);
         }
-        let node = self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)?;
+        let node = self.get_mut(ino).ok_or(Errno::ENOENT)?;
         let exists = node.xattrs.contains_key(name);
         if flags & libc::XATTR_CREATE != 0 && exists {
             return Err(Errno::EEXIST);
@@ -1166,19 +1559,19 @@ impl FileTree {
         }
         node.xattrs.insert(name.to_owned(), value.to_vec());
         node.attr.ctime = SystemTime::now();
-        self.dirty = true;
+        self.set_dirty();
         Ok(())
     }
 
     /// Remove an extended attribute. Returns `ENODATA` if the attribute is
     /// not set; `ENOENT` if the inode does not exist.
     pub(crate) fn removexattr(&mut self, ino: Inode, name: &str) -> Result<(), Errno> {
-        let node = self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)?;
+        let node = self.get_mut(ino).ok_or(Errno::ENOENT)?;
         if node.xattrs.remove(name).is_none() {
             return Err(Errno::ENODATA);
         }
         node.attr.ctime = SystemTime::now();
-        self.dirty = true;
+        self.set_dirty();
         Ok(())
     }
 
@@ -1192,7 +1585,7 @@ impl FileTree {
         self.validate_parent_for_create(parent, name)?;
         let target_os: OsString = target.as_os_str().to_owned();
         let size = target_os.len() as u64;
-        let ino = self.next_inode();
+        let ino = self.alloc_inode(parent, name);
         let node = FsNode::new_node(
             ino,
             name.to_owned(),
@@ -1207,8 +1600,8 @@ impl FileTree {
         self.insert_node(node, Some(parent))
             .map_err(|_| Errno::EIO)?;
         self.touch_parent(parent, SystemTime::now());
-        self.dirty = true;
-        Ok(self.inodes.get(&ino).unwrap())
+        self.set_dirty();
+        Ok(self.get_mut(ino).unwrap())
     }
 
     /// Create a special file (block device, char device, fifo, or socket).
@@ -1228,7 +1621,7 @@ impl FileTree {
         owner: Owner,
     ) -> Result<&FsNode, Errno> {
         self.validate_parent_for_create(parent, name)?;
-        let ino = self.next_inode();
+        let ino = self.alloc_inode(parent, name);
         let node = FsNode::new_node(
             ino,
             name.to_owned(),
@@ -1243,8 +1636,8 @@ impl FileTree {
         self.insert_node(node, Some(parent))
             .map_err(|_| Errno::EIO)?;
         self.touch_parent(parent, SystemTime::now());
-        self.dirty = true;
-        Ok(self.inodes.get(&ino).unwrap())
+        self.set_dirty();
+        Ok(self.get_mut(ino).unwrap())
     }
 
     /// Add a directory entry under `(parent, name)` that points at the same
@@ -1258,68 +1651,75 @@ impl FileTree {
         name: &OsStr,
         source: Inode,
     ) -> Result<&FsNode, Errno> {
-        let parent_node = self.inodes.get(&parent).ok_or(Errno::ENOENT)?;
-        if !matches!(parent_node.content, FsContent::Directory(_)) {
+        if !matches!(
+            self.get(parent).ok_or(Errno::ENOENT)?.content,
+            FsContent::Directory(_)
+        ) {
             return Err(Errno::ENOTDIR);
         }
         if self.lookup_child(parent, name).is_some() {
             return Err(Errno::EEXIST);
         }
         // POSIX: hardlinking a directory is EPERM.
-        let source_node = self.inodes.get(&source).ok_or(Errno::ENOENT)?;
-        if matches!(source_node.content, FsContent::Directory(_)) {
+        if matches!(
+            self.get(source).ok_or(Errno::ENOENT)?.content,
+            FsContent::Directory(_)
+        ) {
             return Err(Errno::EPERM);
         }
         let now = SystemTime::now();
-        if let Some(parent_mut) = self.inodes.get_mut(&parent)
+        if let Some(parent_mut) = self.get_mut(parent)
             && let FsContent::Directory(dir) = &mut parent_mut.content
         {
             dir.insert(name.to_owned(), source);
         }
         self.touch_parent(parent, now);
-        if let Some(src_mut) = self.inodes.get_mut(&source) {
+        if let Some(src_mut) = self.get_mut(source) {
             src_mut.attr.nlink += 1;
             src_mut.attr.ctime = now;
         }
-        self.dirty = true;
-        Ok(self.inodes.get(&source).unwrap())
+        self.set_dirty();
+        Ok(self.get_mut(source).unwrap())
     }
 
     // ── Traversal / bulk helpers ───────────────────────────────────
 
-    /// Return every node except root in pre-order traversal (children sorted
-    /// lexicographically by name) together with its full archive path
-    /// (e.g. `"dir/subdir/file.txt"`).
-    pub(crate) fn collect_dfs(&self) -> Vec<(Inode, &FsNode, String)> {
+    /// Return every node except the archive root in pre-order traversal
+    /// (children sorted lexicographically by name) together with its full
+    /// archive path (e.g. `"dir/subdir/file.txt"`).
+    ///
+    /// The result holds the read lock of every node at once, and the walk
+    /// read-locks each directory again while its children are listed. Call
+    /// it only under the tree's *write* lock (or on a tree nobody else
+    /// sees), where no data operation can be waiting for a node's write
+    /// lock; under the read lock, such a waiter between two recursive read
+    /// locks deadlocks the walk.
+    pub(crate) fn collect_dfs(&self) -> Vec<(Inode, NodeRef<'_>, String)> {
         let mut result = Vec::new();
-        if let Some(root) = self.inodes.get(&ROOT_INODE)
-            && let FsContent::Directory(ref dir) = root.content
-        {
-            self.collect_dfs_recurse(dir, &mut result, &mut String::new());
-        }
+        self.collect_dfs_recurse(self.archive_root, &mut result, "");
         result
     }
 
     fn collect_dfs_recurse<'a>(
         &'a self,
-        dir: &DirContent,
-        result: &mut Vec<(Inode, &'a FsNode, String)>,
-        prefix: &mut String,
+        dir: Inode,
+        result: &mut Vec<(Inode, NodeRef<'a>, String)>,
+        prefix: &str,
     ) {
-        for (name, &ino) in &dir.children {
-            let node = match self.inodes.get(&ino) {
-                Some(n) => n,
-                None => continue,
-            };
+        let Some(children) = self.children(dir) else {
+            return;
+        };
+        for (name, node) in children {
+            let ino = node.attr.ino.0;
             let path = if prefix.is_empty() {
                 name.to_string_lossy().into_owned()
             } else {
                 format!("{}/{}", prefix, name.to_string_lossy())
             };
+            let is_dir = matches!(node.content, FsContent::Directory(_));
             result.push((ino, node, path.clone()));
-            if let FsContent::Directory(ref child_dir) = node.content {
-                let mut child_prefix = path;
-                self.collect_dfs_recurse(child_dir, result, &mut child_prefix);
+            if is_dir {
+                self.collect_dfs_recurse(ino, result, &path);
             }
         }
     }
@@ -1335,8 +1735,8 @@ impl FileTree {
             if let Some(child) = self.lookup_child(parent, name) {
                 parent = child.attr.ino.0;
             } else {
-                let ino = self.next_inode();
-                let dir_node = make_dir_node(ino, name.to_owned());
+                let ino = self.alloc_inode(parent, name);
+                let dir_node = self.synthesized_dir(ino, name.to_owned());
                 self.insert_node(dir_node, Some(parent))?;
                 parent = ino;
             }
@@ -1344,6 +1744,53 @@ impl FileTree {
         Ok(parent)
     }
 
+    /// Make the directory at `path` the mount root (`--subdir`) by swapping
+    /// its inode number with [`ROOT_INODE`]'s. The rest of the archive
+    /// stays in the tree, out of the mount's reach, so saving writes it
+    /// back unchanged and paths created in the mount land under `path`.
+    pub(crate) fn reroot(&mut self, path: &Path) -> io::Result<()> {
+        let ino = self.resolve_path(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no directory {} in the archive", path.display()),
+            )
+        })?;
+        if !matches!(
+            self.get(ino).map(|n| n.attr.kind),
+            Some(FileType::Directory)
+        ) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} in the archive is not a directory", path.display()),
+            ));
+        }
+        if ino == self.archive_root {
+            return Ok(());
+        }
+        let swap = |n: Inode| match n {
+            n if n == ino => ROOT_INODE,
+            ROOT_INODE => ino,
+            n => n,
+        };
+        self.inodes = std::mem::take(&mut self.inodes)
+            .into_iter()
+            .map(|(n, mut cell)| {
+                let node = node_mut(&mut cell);
+                node.attr.ino = INodeNo(swap(n));
+                node.parent = node.parent.map(swap);
+                if let FsContent::Directory(dir) = &mut node.content {
+                    for child in dir.children.values_mut() {
+                        *child = swap(*child);
+                    }
+                }
+                (swap(n), cell)
+            })
+            .collect();
+        self.archive_root = swap(self.archive_root);
+        self.renumber_hidden(swap);
+        Ok(())
+    }
+
     /// POSIX: every directory's `nlink` is `2 + #subdirectories` (`.` self
     /// link plus one `..` from each child directory). The archive load path
     /// builds the tree out of unrelated PNA entries and has no convenient
@@ -1352,52 +1799,227 @@ impl FileTree {
     pub(crate) fn recompute_directory_nlinks(&mut self) {
         let counts: Vec<(Inode, u32)> = self
             .inodes
-            .iter()
-            .filter_map(|(ino, n)| {
-                let FsContent::Directory(d) = &n.content else {
-                    return None;
-                };
-                let subdirs = d
-                    .iter()
-                    .filter(|(_, child_ino)| {
-                        matches!(
-                            self.inodes.get(*child_ino).map(|n| &n.content),
-                            Some(FsContent::Directory(_))
-                        )
-                    })
+            .keys()
+            .filter_map(|&ino| {
+                let subdirs = self
+                    .children(ino)?
+                    .filter(|(_, child)| matches!(child.content, FsContent::Directory(_)))
                     .count();
-                Some((*ino, 2 + subdirs as u32))
+                Some((ino, 2 + subdirs as u32))
             })
             .collect();
         for (ino, nlink) in counts {
-            if let Some(node) = self.inodes.get_mut(&ino) {
+            if let Some(node) = self.get_mut(ino) {
                 node.attr.nlink = nlink;
             }
         }
     }
 
+    /// Take over inode numbers from `old`, the tree this freshly loaded one
+    /// replaces on a live reload, and report what the kernel must forget.
+    ///
+    /// A path that exists in both trees with the same file type keeps the
+    /// inode number it had in `old`, so open fds, cached dentries and tools
+    /// keyed on `st_ino` keep pointing at the same file. Everything else
+    /// keeps its own path-derived number unless `old` still uses it, in
+    /// which case it is re-probed, so a new file can never be confused with
+    /// an inode the kernel still remembers from `old`.
+    ///
+    /// Inodes of `old` that still have open fds but no longer exist in the
+    /// new archive are carried over as orphans (`nlink == 0`), exactly like
+    /// an unlink-while-open, so reads through those fds keep working until
+    /// the matching `release`.
+    pub(crate) fn adopt_inodes(&mut self, mut old: FileTree) -> ReloadChanges {
+        let old_paths: HashMap<String, Inode> = old
+            .collect_dfs()
+            .into_iter()
+            .map(|(ino, _, path)| (path, ino))
+            .collect();
+
+        let mut mapping: HashMap<Inode, Inode> =
+            HashMap::from([(self.archive_root, old.archive_root)]);
+        let mut taken: HashSet<Inode> = [old.archive_root].into();
+        let mut unmatched = Vec::new();
+        let mut seen = HashSet::from([self.archive_root]);
+        for (ino, node, path) in self.collect_dfs() {
+            // A hardlink's second path maps through its first one.
+            if !seen.insert(ino) {
+                continue;
+            }
+            if let Some(&old_ino) = old_paths.get(&path)
+                && old.get(old_ino).map(|n| n.attr.kind) == Some(node.attr.kind)
+                && taken.insert(old_ino)
+            {
+                mapping.insert(ino, old_ino);
+            } else {
+                unmatched.push((ino, path));
+            }
+        }
+        // Nodes without a path (a fresh load has none, but be total).
+        let mut detached: Vec<Inode> = self
+            .inodes
+            .keys()
+            .copied()
+            .filter(|ino| !seen.contains(ino))
+            .collect();
+        detached.sort_unstable();
+        for ino in detached {
+            let path = String::from_utf8_lossy(&self.path_bytes(ino)).into_owned();
+            unmatched.push((ino, path));
+        }
+        let mut issued = std::mem::take(&mut old.issued);
+        issued.extend(taken);
+        for (ino, path) in unmatched {
+            let renumbered = if issued.contains(&ino) {
+                probe_inode(path.as_bytes(), |candidate| issued.contains(&candidate))
+            } else {
+                ino
+            };
+            issued.insert(renumbered);
+            mapping.insert(ino, renumbered);
+        }
+
+        let remapped: HashMap<Inode, RwLock<FsNode>> = std::mem::take(&mut self.inodes)
+            .into_iter()
+            .map(|(ino, cell)| {
+                let mut node = into_node(cell);
+                let ino = mapping[&ino];
+                node.attr.ino = INodeNo(ino);
+                node.parent = node.parent.map(|p| mapping[&p]);
+                if let FsContent::Directory(dir) = &mut node.content {
+                    for child in dir.children.values_mut() {
+                        *child = mapping[child];
+                    }
+                }
+                if let Some(prev) = old.get(ino) {
+                    node.open_count
+                        .store(prev.open_count.load(Ordering::Acquire), Ordering::Release);
+                }
+                (ino, RwLock::new(node))
+            })
+            .collect();
+        self.inodes = remapped;
+        self.issued = issued;
+        self.generations = std::mem::take(&mut old.generations);
+        self.archive_root = old.archive_root;
+        self.renumber_hidden(|ino| mapping[&ino]);
+
+        let mut changes = ReloadChanges::default();
+        for (&ino, new_node) in &self.inodes {
+            let new_node = read_node(new_node);
+            let Some(old_node) = old.get(ino) else {
+                continue;
+            };
+            // atime / crtime are left out: load stamps the load time into
+            // them when the archive carries none, which would flag every
+            // entry on every reload while telling the kernel nothing useful.
+            let mut changed = FileAttr {
+                atime: old_node.attr.atime,
+                crtime: old_node.attr.crtime,
+                ..new_node.attr
+            } != old_node.attr;
+            match (&old_node.content, &new_node.content) {
+                (FsContent::Directory(before), FsContent::Directory(after)) => {
+                    let listed = changes.entries.len();
+                    for (name, child) in before.iter() {
+                        if after.get(name) != Some(*child) {
+                            changes.entries.push((ino, name.clone()));
+                        }
+                    }
+                    for (name, _) in after.iter() {
+                        if before.get(name).is_none() {
+                            changes.entries.push((ino, name.clone()));
+                        }
+                    }
+                    // The listing itself changed: drop the readdir cache too.
+                    changed |= changes.entries.len() != listed;
+                }
+                (FsContent::File(before), FsContent::File(after)) => {
+                    changed |= before.data() != after.data();
+                }
+                (FsContent::Symlink(before), FsContent::Symlink(after)) => {
+                    changed |= before != after;
+                }
+                _ => changed = true,
+            }
+            if changed {
+                changes.inodes.push(ino);
+            }
+        }
+
+        for (ino, mut cell) in old.inodes.drain() {
+            let node = node_mut(&mut cell);
+            if self.inodes.contains_key(&ino) || node.open_count.load(Ordering::Acquire) == 0 {
+                continue;
+            }
+            node.attr.nlink = 0;
+            node.parent = None;
+            self.inodes.insert(ino, cell);
+        }
+        changes
+    }
+
     /// Constructs an empty tree with just the root directory node, intended
     /// for use in unit tests.
     #[cfg(test)]
     pub(crate) fn new_for_test(archive_path: PathBuf, password: Option<String>) -> Self {
         let mut tree = Self::new(archive_path, password);
-        let root = make_dir_node(ROOT_INODE, ".".into());
+        let root = tree.synthesized_dir(ROOT_INODE, ".".into());
         tree.insert_node(root, None).unwrap();
         tree
     }
 }
 
+/// Inode number for the archive path `path` on probe `attempt`: 64-bit
+/// FNV-1a over the path bytes, with the attempt number mixed in after the
+/// first try. FNV is spelled out rather than taken from `std::hash`
+/// because the numbers must not change between builds or Rust releases.
+/// `0` and [`ROOT_INODE`] are never produced.
+pub(crate) fn stable_inode(path: &[u8], attempt: u64) -> Inode {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let fnv = |hash: u64, bytes: &[u8]| {
+        bytes
+            .iter()
+            .fold(hash, |h, &b| (h ^ u64::from(b)).wrapping_mul(PRIME))
+    };
+    let mut hash = fnv(OFFSET_BASIS, path);
+    if attempt > 0 {
+        hash = fnv(hash, &attempt.to_le_bytes());
+    }
+    if hash <= ROOT_INODE {
+        hash += ROOT_INODE + 1;
+    }
+    hash
+}
+
+/// First [`stable_inode`] for `path` that `taken` does not reject.
+fn probe_inode(path: &[u8], taken: impl Fn(Inode) -> bool) -> Inode {
+    (0..)
+        .map(|attempt| stable_inode(path, attempt))
+        .find(|&ino| !taken(ino))
+        .expect("the probe sequence is unbounded")
+}
+
 /// Build an [`FsNode`] representing an empty directory. Used both for the
 /// archive's root node (load) and for synthesized parent dirs in
-/// `make_dir_all`. `make_dir` has its own path that goes through
-/// `FsNode::new_node` directly so it can honour the request's umask + uid.
-pub(crate) fn make_dir_node(ino: Inode, name: OsString) -> FsNode {
+/// `make_dir_all`, through [`FileTree::synthesized_dir`]. `make_dir` has
+/// its own path that goes through `FsNode::new_node` directly so it can
+/// honour the request's umask + uid.
+/// `path` split into its first component and the rest.
+fn split_first(path: &Path) -> Option<(&Path, &Path)> {
+    let mut components = path.components();
+    let first = components.next()?;
+    Some((Path::new(first.as_os_str()), components.as_path()))
+}
+
+fn make_dir_node(ino: Inode, name: OsString, perm: u16, owner: Owner) -> FsNode {
     FsNode::new_node(
         ino,
         name,
         FileType::Directory,
-        0o775,
-        Owner::new(current_uid(), current_gid()),
+        perm,
+        owner,
         FsContent::Directory(DirContent::new()),
         0,
         512,
@@ -1405,24 +2027,13 @@ pub(crate) fn make_dir_node(ino: Inode, name: OsString) -> FsNode {
     )
 }
 
-#[cfg(unix)]
-fn current_uid() -> u32 {
-    Uid::current().as_raw()
-}
+/// The id shown for archive owners that do not fit in 32 bits, as the
+/// kernel does for ids it cannot map (`/proc/sys/kernel/overflowuid`).
+pub(crate) const OVERFLOW_ID: u32 = 65534;
 
-#[cfg(unix)]
-fn current_gid() -> u32 {
-    Gid::current().as_raw()
-}
-
-#[cfg(not(unix))]
-fn current_uid() -> u32 {
-    0
-}
-
-#[cfg(not(unix))]
-fn current_gid() -> u32 {
-    0
+/// `id` as a local 32-bit id, or [`OVERFLOW_ID`] when it does not fit.
+pub(crate) fn local_id(id: u64) -> u32 {
+    u32::try_from(id).unwrap_or(OVERFLOW_ID)
 }
 
 #[cfg(unix)]
@@ -1431,7 +2042,9 @@ fn search_owner(name: &str, id: u64) -> Option<User> {
     if user.is_some() {
         return user;
     }
-    User::from_uid((id as u32).into()).ok().flatten()
+    User::from_uid(u32::try_from(id).ok()?.into())
+        .ok()
+        .flatten()
 }
 
 #[cfg(unix)]
@@ -1440,7 +2053,9 @@ fn search_group(name: &str, id: u64) -> Option<Group> {
     if group.is_some() {
         return group;
     }
-    Group::from_gid((id as u32).into()).ok().flatten()
+    Group::from_gid(u32::try_from(id).ok()?.into())
+        .ok()
+        .flatten()
 }
 
 /// Resolve the UID for a PNA permission entry. If the archive's
@@ -1456,7 +2071,9 @@ pub(crate) fn get_uid(permission: Option<&Permission>) -> u32 {
     #[cfg(unix)]
     {
         match permission {
-            Some(p) => search_owner(p.uname(), p.uid()).map_or(p.uid() as u32, |u| u.uid.as_raw()),
+            Some(p) => {
+                search_owner(p.uname(), p.uid()).map_or(local_id(p.uid()), |u| u.uid.as_raw())
+            }
             None => Uid::current().as_raw(),
         }
     }
@@ -1476,7 +2093,9 @@ pub(crate) fn get_gid(permission: Option<&Permission>) -> u32 {
     #[cfg(unix)]
     {
         match permission {
-            Some(p) => search_group(p.gname(), p.gid()).map_or(p.gid() as u32, |g| g.gid.as_raw()),
+            Some(p) => {
+                search_group(p.gname(), p.gid()).map_or(local_id(p.gid()), |g| g.gid.as_raw())
+            }
             None => Gid::current().as_raw(),
         }
     }
@@ -1632,7 +2251,7 @@ mod tests {
 
     #[test]
     fn write_file_at_offset_zero() {
-        let (mut tree, ino) = make_tree_with_file(b"");
+        let (tree, ino) = make_tree_with_file(b"");
         let written = tree.write_file(ino, 0, b"hello").unwrap();
         assert_eq!(written, 5);
         let node = tree.get(ino).unwrap();
@@ -1641,7 +2260,7 @@ mod tests {
 
     #[test]
     fn write_file_sparse_zero_fills() {
-        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let (tree, ino) = make_tree_with_file(b"hello");
         tree.write_file(ino, 10, b"!").unwrap();
         let node = tree.get(ino).unwrap();
         assert_eq!(node.attr.size, 11);
@@ -1656,7 +2275,7 @@ mod tests {
 
     #[test]
     fn write_file_empty_data_is_noop() {
-        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let (tree, ino) = make_tree_with_file(b"hello");
         let written = tree.write_file(ino, 0, b"").unwrap();
         assert_eq!(written, 0);
         assert_eq!(tree.get(ino).unwrap().attr.size, 5);
@@ -1664,7 +2283,7 @@ mod tests {
 
     #[test]
     fn write_file_bad_ino_returns_enoent() {
-        let mut tree = make_tree();
+        let tree = make_tree();
         assert_errno(
             tree.write_file(9999, 0, b"x").unwrap_err(),
             fuser::Errno::ENOENT,
@@ -1673,7 +2292,7 @@ mod tests {
 
     #[test]
     fn write_file_on_dir_returns_eisdir() {
-        let mut tree = make_tree();
+        let tree = make_tree();
         assert_errno(
             tree.write_file(ROOT_INODE, 0, b"x").unwrap_err(),
             fuser::Errno::EISDIR,
@@ -1682,7 +2301,7 @@ mod tests {
 
     #[test]
     fn write_file_append_to_existing() {
-        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let (tree, ino) = make_tree_with_file(b"hello");
         let written = tree.write_file(ino, 5, b" world").unwrap();
         assert_eq!(written, 6);
         if let FsContent::File(fd) = &tree.get(ino).unwrap().content {
@@ -1692,6 +2311,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn append_file_writes_at_the_authoritative_size() {
+        let (tree, ino) = make_tree_with_file(b"hello");
+        assert_eq!(tree.append_file(ino, b" world").unwrap(), 6);
+        assert_eq!(tree.append_file(ino, b"!").unwrap(), 1);
+        let node = tree.get(ino).unwrap();
+        assert_eq!(node.attr.size, 12);
+        if let FsContent::File(fd) = &node.content {
+            assert_eq!(fd.data(), b"hello world!");
+        } else {
+            panic!("expected File content");
+        }
+    }
+
+    #[test]
+    fn sparse_writes_and_punches_are_visible_to_seek() {
+        let (tree, ino) = make_tree_with_file(b"hello");
+        tree.write_file(ino, 100, b"tail").unwrap();
+        tree.fallocate(ino, 1, 2, 0x03).unwrap();
+        // data [0,1) hole [1,3) data [3,5) hole [5,100) data [100,104)
+        let seek = |off, data| tree.seek_hole_data(ino, off, data);
+        assert_eq!(seek(0, false), Ok(1));
+        assert_eq!(seek(1, true), Ok(3));
+        assert_eq!(seek(3, false), Ok(5));
+        assert_eq!(seek(5, true), Ok(100));
+        assert_eq!(seek(100, false), Ok(104));
+        assert_eq!(seek(104, true), Err(fuser::Errno::ENXIO));
+
+        // Shrinking drops the holes past the end; growing adds one.
+        tree.set_size(ino, 4).unwrap();
+        tree.set_size(ino, 50).unwrap();
+        assert_eq!(seek(3, false), Ok(4));
+        assert_eq!(seek(4, true), Err(fuser::Errno::ENXIO));
+        // Rewriting a hole makes it data again.
+        tree.write_file(ino, 1, b"el").unwrap();
+        assert_eq!(seek(0, false), Ok(4));
+    }
+
+    #[test]
+    fn truncate_on_open_empties_and_leaves_empty_files_alone() {
+        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let past = SystemTime::UNIX_EPOCH;
+        {
+            let mut node = tree.lock(ino).unwrap();
+            node.attr.mtime = past;
+            node.attr.ctime = past;
+        }
+        tree.mark_clean();
+        assert!(tree.truncate_on_open(ino).unwrap());
+        let node = tree.get(ino).unwrap();
+        assert_eq!(node.attr.size, 0);
+        assert!(node.attr.mtime > past && node.attr.ctime > past);
+        drop(node);
+        assert!(tree.is_dirty());
+
+        // Nothing to save when the file was empty already.
+        tree.lock(ino).unwrap().attr.mtime = past;
+        tree.mark_clean();
+        assert!(!tree.truncate_on_open(ino).unwrap());
+        assert_eq!(tree.get(ino).unwrap().attr.mtime, past);
+        assert!(!tree.is_dirty());
+        assert_errno(
+            tree.truncate_on_open(ROOT_INODE).unwrap_err(),
+            fuser::Errno::EISDIR,
+        );
+    }
+
     #[test]
     fn write_file_overwrites_clean_becomes_dirty() {
         // Get a Clean node: create -> write -> mark_clean
@@ -1757,7 +2443,7 @@ mod tests {
 
     #[test]
     fn write_file_mid_overwrite_preserves_trailing() {
-        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let (tree, ino) = make_tree_with_file(b"hello");
         tree.write_file(ino, 1, b"XY").unwrap();
         if let FsContent::File(fd) = &tree.get(ino).unwrap().content {
             assert_eq!(fd.data(), b"hXYlo");
@@ -1788,7 +2474,7 @@ mod tests {
 
     #[test]
     fn set_size_truncate() {
-        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let (tree, ino) = make_tree_with_file(b"hello");
         tree.set_size(ino, 3).unwrap();
         let node = tree.get(ino).unwrap();
         assert_eq!(node.attr.size, 3);
@@ -1801,7 +2487,7 @@ mod tests {
 
     #[test]
     fn set_size_truncate_to_zero() {
-        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let (tree, ino) = make_tree_with_file(b"hello");
         tree.set_size(ino, 0).unwrap();
         assert_eq!(tree.get(ino).unwrap().attr.size, 0);
         if let FsContent::File(fd) = &tree.get(ino).unwrap().content {
@@ -1813,7 +2499,7 @@ mod tests {
 
     #[test]
     fn set_size_extend_zero_pads() {
-        let (mut tree, ino) = make_tree_with_file(b"hi");
+        let (tree, ino) = make_tree_with_file(b"hi");
         tree.set_size(ino, 5).unwrap();
         let node = tree.get(ino).unwrap();
         assert_eq!(node.attr.size, 5);
@@ -1828,7 +2514,7 @@ mod tests {
 
     #[test]
     fn set_size_on_dir_returns_eisdir() {
-        let mut tree = make_tree();
+        let tree = make_tree();
         assert_errno(
             tree.set_size(ROOT_INODE, 0).unwrap_err(),
             fuser::Errno::EISDIR,
@@ -1837,7 +2523,7 @@ mod tests {
 
     #[test]
     fn set_size_same_length_noop() {
-        let (mut tree, ino) = make_tree_with_file(b"hello");
+        let (tree, ino) = make_tree_with_file(b"hello");
         tree.set_size(ino, 5).unwrap();
         let node = tree.get(ino).unwrap();
         assert_eq!(node.attr.size, 5);
@@ -1869,7 +2555,7 @@ mod tests {
 
     #[test]
     fn set_size_bad_ino_returns_enoent() {
-        let mut tree = make_tree();
+        let tree = make_tree();
         let err = tree.set_size(9999, 0).unwrap_err();
         assert!(errno_eq(err, fuser::Errno::ENOENT));
     }
@@ -2081,22 +2767,22 @@ mod tests {
         let new_node = tree
             .create_file(ROOT_INODE, OsStr::new("test.txt"), 0o644, Owner::new(0, 0))
             .unwrap();
-        assert_ne!(new_node.attr.ino.0, old_ino);
+        // The freed number is the path's own, so it comes back.
+        assert_eq!(new_node.attr.ino.0, old_ino);
         let children: Vec<_> = tree.children(ROOT_INODE).unwrap().collect();
         assert_eq!(children.len(), 1);
     }
 
     #[test]
     fn unlink_recreate_data_isolation() {
-        let (mut tree, old_ino) = make_tree_with_file(b"old data");
+        let (mut tree, _) = make_tree_with_file(b"old data and more");
         tree.unlink(ROOT_INODE, OsStr::new("test.txt")).unwrap();
         let new_node = tree
             .create_file(ROOT_INODE, OsStr::new("test.txt"), 0o644, Owner::new(0, 0))
             .unwrap();
         let new_ino = new_node.attr.ino.0;
         tree.write_file(new_ino, 0, b"new data").unwrap();
-        // Verify old data is completely gone
-        assert!(tree.get(old_ino).is_none());
+        // Verify old data is completely gone, even under the same number
         if let FsContent::File(fd) = &tree.get(new_ino).unwrap().content {
             assert_eq!(fd.data(), b"new data");
         } else {
@@ -2108,7 +2794,7 @@ mod tests {
 
     #[test]
     fn write_then_truncate_preserves_prefix() {
-        let (mut tree, ino) = make_tree_with_file(b"hello world");
+        let (tree, ino) = make_tree_with_file(b"hello world");
         tree.set_size(ino, 5).unwrap();
         let node = tree.get(ino).unwrap();
         assert_eq!(node.attr.size, 5);
@@ -2736,12 +3422,7 @@ mod tests {
         a.finalize().unwrap();
 
         let mut tree = crate::archive_io::load(&archive, None).unwrap();
-        let (_, node) = tree
-            .children(ROOT_INODE)
-            .unwrap()
-            .find(|(n, _)| n.to_str() == Some("doc.txt"))
-            .unwrap();
-        let ino = node.attr.ino.0;
+        let ino = tree.resolve_path(Path::new("doc.txt")).unwrap();
         tree.setxattr(ino, "user.color", b"green", 0).unwrap();
         crate::archive_io::save(&tree).unwrap();
 
@@ -3040,6 +3721,7 @@ mod tests {
         tree.try_free_orphan(ino);
         let node = tree.get(ino).expect("still orphaned with 1 fd left");
         assert_eq!(node.open_count.load(Ordering::Relaxed), 1);
+        drop(node);
         let _ = tree.release_open(ino);
         tree.try_free_orphan(ino);
         assert!(tree.get(ino).is_none());
@@ -3080,6 +3762,7 @@ mod tests {
         let orphan = tree.get(dst_ino).expect("overwritten target must orphan");
         assert_eq!(orphan.attr.nlink, 0);
         assert_eq!(orphan.open_count.load(Ordering::Relaxed), 1);
+        drop(orphan);
         let _ = tree.release_open(dst_ino);
         tree.try_free_orphan(dst_ino);
         assert!(tree.get(dst_ino).is_none());
@@ -3139,6 +3822,7 @@ mod tests {
             FsContent::File(fd) => assert_eq!(fd.data(), b"orphan-payload"),
             _ => panic!("expected file content on orphan"),
         }
+        drop(orphan);
 
         // Closing the last fd frees the orphan inode.
         let was_last = tree.release_open(ino);
@@ -3408,7 +4092,7 @@ mod tests {
 
     #[test]
     fn fallocate_grows_file_with_zero_fill() {
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         tree.fallocate(ino, 0, 16, 0).unwrap();
         let data = read_file_data(&tree, ino);
         assert_eq!(data.len(), 16);
@@ -3420,7 +4104,7 @@ mod tests {
     #[test]
     fn fallocate_keep_size_grows_buffer_but_not_size() {
         const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         tree.fallocate(ino, 0, 16, FALLOC_FL_KEEP_SIZE).unwrap();
         let data = read_file_data(&tree, ino);
         assert_eq!(data.len(), 16, "buffer should be reserved");
@@ -3433,7 +4117,7 @@ mod tests {
 
     #[test]
     fn fallocate_offset_grows_to_offset_plus_length() {
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         tree.fallocate(ino, 10, 5, 0).unwrap();
         let data = read_file_data(&tree, ino);
         assert_eq!(data.len(), 15);
@@ -3445,7 +4129,7 @@ mod tests {
     #[test]
     fn fallocate_punch_hole_zeroes_range_within_file() {
         const FLAGS: i32 = 0x02 | 0x01; // PUNCH_HOLE | KEEP_SIZE
-        let (mut tree, ino) = make_tree_with_file(b"AAAABBBBCCCC");
+        let (tree, ino) = make_tree_with_file(b"AAAABBBBCCCC");
         tree.fallocate(ino, 4, 4, FLAGS).unwrap();
         let data = read_file_data(&tree, ino);
         assert_eq!(data.as_slice(), b"AAAA\0\0\0\0CCCC");
@@ -3455,7 +4139,7 @@ mod tests {
     #[test]
     fn fallocate_punch_hole_past_eof_is_noop_within_size() {
         const FLAGS: i32 = 0x02 | 0x01;
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         tree.fallocate(ino, 100, 50, FLAGS).unwrap();
         let data = read_file_data(&tree, ino);
         assert_eq!(data.as_slice(), b"abc", "no growth, no change");
@@ -3465,7 +4149,7 @@ mod tests {
     #[test]
     fn fallocate_punch_hole_without_keep_size_is_einval() {
         const FLAGS: i32 = 0x02; // PUNCH_HOLE alone
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         assert_errno(
             tree.fallocate(ino, 0, 3, FLAGS).unwrap_err(),
             fuser::Errno::EINVAL,
@@ -3475,7 +4159,7 @@ mod tests {
     #[test]
     fn fallocate_zero_range_grows_and_zeroes() {
         const FALLOC_FL_ZERO_RANGE: i32 = 0x10;
-        let (mut tree, ino) = make_tree_with_file(b"AAAA");
+        let (tree, ino) = make_tree_with_file(b"AAAA");
         tree.fallocate(ino, 2, 6, FALLOC_FL_ZERO_RANGE).unwrap();
         let data = read_file_data(&tree, ino);
         assert_eq!(data.len(), 8);
@@ -3486,7 +4170,7 @@ mod tests {
 
     #[test]
     fn fallocate_zero_length_is_einval() {
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         assert_errno(
             tree.fallocate(ino, 0, 0, 0).unwrap_err(),
             fuser::Errno::EINVAL,
@@ -3496,7 +4180,7 @@ mod tests {
     #[test]
     fn fallocate_unknown_flags_returns_enotsup() {
         const FALLOC_FL_COLLAPSE_RANGE: i32 = 0x08;
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         assert_errno(
             tree.fallocate(ino, 0, 3, FALLOC_FL_COLLAPSE_RANGE)
                 .unwrap_err(),
@@ -3506,7 +4190,7 @@ mod tests {
 
     #[test]
     fn fallocate_on_directory_returns_eisdir() {
-        let mut tree = make_tree();
+        let tree = make_tree();
         assert_errno(
             tree.fallocate(ROOT_INODE, 0, 4, 0).unwrap_err(),
             fuser::Errno::EISDIR,
@@ -3515,7 +4199,7 @@ mod tests {
 
     #[test]
     fn fallocate_on_unknown_inode_returns_enoent() {
-        let mut tree = make_tree();
+        let tree = make_tree();
         assert_errno(
             tree.fallocate(9999, 0, 4, 0).unwrap_err(),
             fuser::Errno::ENOENT,
@@ -3524,7 +4208,7 @@ mod tests {
 
     #[test]
     fn fallocate_bumps_mtime_and_ctime() {
-        let (mut tree, ino) = make_tree_with_file(b"abc");
+        let (tree, ino) = make_tree_with_file(b"abc");
         let before_m = tree.get(ino).unwrap().attr.mtime;
         let before_c = tree.get(ino).unwrap().attr.ctime;
         std::thread::sleep(std::time::Duration::from_millis(2));
@@ -3604,7 +4288,7 @@ mod tests {
 
     #[test]
     fn copy_file_range_within_same_inode_non_overlapping() {
-        let (mut tree, ino) = make_tree_with_file(b"AAAA....BBBB");
+        let (tree, ino) = make_tree_with_file(b"AAAA....BBBB");
         let n = tree.copy_file_range(ino, 0, ino, 4, 4).unwrap();
         assert_eq!(n, 4);
         assert_eq!(read_file_data(&tree, ino), b"AAAAAAAABBBB");
@@ -3627,7 +4311,7 @@ mod tests {
 
     #[test]
     fn copy_file_range_unknown_inode_returns_enoent() {
-        let (mut tree, src) = make_tree_with_file(b"abc");
+        let (tree, src) = make_tree_with_file(b"abc");
         assert_errno(
             tree.copy_file_range(src, 0, 9999, 0, 1).unwrap_err(),
             fuser::Errno::ENOENT,
@@ -3943,4 +4627,263 @@ mod tests {
         tree.set_times(ino, None, None).unwrap();
         assert!(!tree.is_dirty());
     }
+
+    // ── adopt_inodes (live reload) ──────────────────────────────────
+
+    #[test]
+    fn adopt_inodes_keeps_numbers_for_surviving_paths() {
+        let mut old = make_tree();
+        let a = old
+            .create_file(ROOT_INODE, OsStr::new("a"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        old.create_file(ROOT_INODE, OsStr::new("b"), 0o644, Owner::new(0, 0))
+            .unwrap();
+
+        // The reloaded tree allocates in a different order, so without
+        // adoption "a" would come back under another number.
+        let mut new = make_tree();
+        let c = new
+            .create_file(ROOT_INODE, OsStr::new("c"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        new.create_file(ROOT_INODE, OsStr::new("a"), 0o644, Owner::new(0, 0))
+            .unwrap();
+        let old_issued = old.issued.clone();
+        let changes = new.adopt_inodes(old);
+
+        let a_after = new.lookup_child(ROOT_INODE, OsStr::new("a")).unwrap();
+        assert_eq!(a_after.attr.ino.0, a);
+        let c_after = new.lookup_child(ROOT_INODE, OsStr::new("c")).unwrap();
+        assert_eq!(
+            c_after.attr.ino.0, c,
+            "a path new to the reload keeps its own stable number"
+        );
+        assert!(!old_issued.contains(&c));
+        assert_eq!(c_after.parent, Some(ROOT_INODE));
+        let names: Vec<_> = changes
+            .entries
+            .iter()
+            .map(|(p, n)| (*p, n.to_str().unwrap()))
+            .collect();
+        assert!(names.contains(&(ROOT_INODE, "b")));
+        assert!(names.contains(&(ROOT_INODE, "c")));
+        assert!(!names.contains(&(ROOT_INODE, "a")));
+        assert!(changes.inodes.contains(&ROOT_INODE));
+    }
+
+    #[test]
+    fn adopt_inodes_reports_content_changes() {
+        let (mut old, ino) = make_tree_with_file(b"before");
+        let (mut new, new_ino) = make_tree_with_file(b"after!");
+        // Same attrs apart from the content bytes.
+        new.get_mut(new_ino).unwrap().attr = FileAttr {
+            ino: INodeNo(new_ino),
+            ..old.get(ino).unwrap().attr
+        };
+        old.mark_clean();
+        let changes = new.adopt_inodes(old);
+        assert!(changes.inodes.contains(&ino));
+        assert!(changes.entries.is_empty());
+    }
+
+    #[test]
+    fn adopt_inodes_keeps_open_vanished_file_as_orphan() {
+        let (old, ino) = make_tree_with_file(b"still open");
+        old.bump_open(ino).unwrap();
+        let mut new = make_tree();
+        new.adopt_inodes(old);
+
+        assert!(
+            new.lookup_child(ROOT_INODE, OsStr::new("test.txt"))
+                .is_none()
+        );
+        let orphan = new.get(ino).expect("open inode must survive the reload");
+        assert_eq!(orphan.attr.nlink, 0);
+        drop(orphan);
+        assert!(new.release_open(ino), "last release frees the orphan");
+        new.try_free_orphan(ino);
+        assert!(new.get(ino).is_none());
+    }
+
+    #[test]
+    fn adopt_inodes_carries_open_count_for_kept_inode() {
+        let (old, ino) = make_tree_with_file(b"x");
+        old.bump_open(ino).unwrap();
+        let (mut new, _) = make_tree_with_file(b"x");
+        new.adopt_inodes(old);
+        assert_eq!(new.get(ino).unwrap().open_count.load(Ordering::Acquire), 1);
+    }
+
+    #[test]
+    fn stable_inode_never_returns_reserved_numbers() {
+        for attempt in 0..4 {
+            for path in [&b""[..], b"a", b"dir/file"] {
+                assert!(stable_inode(path, attempt) > ROOT_INODE);
+            }
+        }
+        assert_ne!(stable_inode(b"a", 0), stable_inode(b"a", 1));
+        assert_eq!(stable_inode(b"dir/file", 0), stable_inode(b"dir/file", 0));
+    }
+
+    #[test]
+    fn alloc_inode_probes_past_issued_numbers() {
+        let mut tree = make_tree();
+        let natural = stable_inode(b"x", 0);
+        tree.issued.insert(natural);
+        let ino = tree.alloc_inode(ROOT_INODE, OsStr::new("x"));
+        assert_eq!(ino, stable_inode(b"x", 1));
+        // Deterministic: an identical tree probes to the same number.
+        let mut other = make_tree();
+        other.issued.insert(natural);
+        assert_eq!(other.alloc_inode(ROOT_INODE, OsStr::new("x")), ino);
+    }
+
+    #[test]
+    fn freed_inodes_release_their_numbers() {
+        let mut tree = make_tree();
+        let owner = Owner::new(0, 0);
+        for generation in 0..3 {
+            let f = tree
+                .create_file(ROOT_INODE, OsStr::new("f"), 0o644, owner)
+                .unwrap()
+                .attr
+                .ino
+                .0;
+            assert_eq!(
+                f,
+                stable_inode(b"f", 0),
+                "recreated paths keep their number"
+            );
+            // ... under a new generation, as the kernel may still cache
+            // the unlinked inode.
+            assert_eq!(tree.generation(f), generation);
+            tree.unlink(ROOT_INODE, OsStr::new("f")).unwrap();
+            let d = tree
+                .make_dir(ROOT_INODE, OsStr::new("d"), 0o755, 0, owner)
+                .unwrap()
+                .attr
+                .ino
+                .0;
+            assert_eq!(d, stable_inode(b"d", 0));
+            assert_eq!(tree.generation(d), generation);
+            tree.rmdir(ROOT_INODE, OsStr::new("d")).unwrap();
+        }
+        assert_eq!(tree.issued, HashSet::from([ROOT_INODE]));
+
+        // An open orphan keeps its number until it is released.
+        let f = tree
+            .create_file(ROOT_INODE, OsStr::new("f"), 0o644, owner)
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        tree.bump_open(f).unwrap();
+        tree.unlink(ROOT_INODE, OsStr::new("f")).unwrap();
+        assert!(tree.issued.contains(&f));
+        let again = tree.alloc_inode(ROOT_INODE, OsStr::new("f"));
+        assert_ne!(again, f);
+        tree.issued.remove(&again);
+        assert!(tree.release_open(f));
+        tree.try_free_orphan(f);
+        assert!(!tree.issued.contains(&f));
+    }
+
+    #[test]
+    fn alloc_inode_hashes_the_full_path() {
+        let mut tree = make_tree();
+        let dir = tree
+            .make_dir(ROOT_INODE, OsStr::new("d"), 0o755, 0, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        assert_eq!(dir, stable_inode(b"d", 0));
+        let file = tree
+            .create_file(dir, OsStr::new("f"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        assert_eq!(file, stable_inode(b"d/f", 0));
+    }
+
+    #[test]
+    fn data_ops_on_other_files_proceed_while_a_node_is_locked() {
+        let (mut tree, a) = make_tree_with_file(b"a");
+        let b = tree
+            .create_file(ROOT_INODE, OsStr::new("b"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        let tree = &tree;
+        let held = tree.lock(a).unwrap();
+        std::thread::scope(|s| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            s.spawn(move || {
+                tree.write_file(b, 0, b"parallel").unwrap();
+                tree.set_size(b, 4).unwrap();
+                tx.send(()).unwrap();
+            });
+            rx.recv_timeout(std::time::Duration::from_secs(10))
+                .expect("a data op on another file must not wait for a's lock");
+            drop(held);
+        });
+        assert_eq!(tree.get(b).unwrap().attr.size, 4);
+        assert!(tree.is_dirty());
+    }
+
+    #[test]
+    fn copy_file_range_holds_both_files_for_the_whole_copy() {
+        let (mut tree, a) = make_tree_with_file(&[b'a'; 4096]);
+        let b = tree
+            .create_file(ROOT_INODE, OsStr::new("b"), 0o644, Owner::new(0, 0))
+            .unwrap()
+            .attr
+            .ino
+            .0;
+        tree.write_file(b, 0, &[b'b'; 4096]).unwrap();
+        let tree = &tree;
+        let uniform = |ino| match &tree.get(ino).unwrap().content {
+            FsContent::File(fd) => fd.data().iter().all(|&c| c == fd.data()[0]),
+            _ => panic!("expected File"),
+        };
+        // Copies in both directions take the two locks in the same order,
+        // and a writer never lands in the middle of one.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..500 {
+                    tree.copy_file_range(a, 0, b, 0, 4096).unwrap();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..500 {
+                    tree.copy_file_range(b, 0, a, 0, 4096).unwrap();
+                }
+            });
+            s.spawn(|| {
+                for i in 0..500u32 {
+                    tree.write_file(a, 0, &[b'0' + (i % 10) as u8; 4096])
+                        .unwrap();
+                    assert!(uniform(b));
+                }
+            });
+        });
+        assert!(uniform(a) && uniform(b));
+    }
+
+    #[test]
+    fn copy_file_range_within_one_file_takes_one_lock_at_a_time() {
+        let (tree, ino) = make_tree_with_file(b"abcd");
+        assert_eq!(tree.copy_file_range(ino, 0, ino, 2, 4).unwrap(), 4);
+        match &tree.get(ino).unwrap().content {
+            FsContent::File(fd) => assert_eq!(fd.data(), b"ababcd"),
+            _ => panic!("expected File"),
+        }
+    }
 }
@@ -1,18 +1,22 @@
-use crate::archive_io;
-use crate::file_tree::{FileTree, FsContent, NodeKind, Owner, ROOT_INODE};
+use crate::archive_io::{self, LoadOptions};
+use crate::file_handles::{DirHandleTable, HandleTable};
+use crate::file_locks::{LockKind, LockTable};
+use crate::file_tree::{FileTree, FsContent, Inode, NodeKind, NodeRef, Owner, ROOT_INODE};
+use crate::permissions::{self, Caller, PermissionMode};
 use fuser::{
-    BsdFileFlags, Errno, FileHandle, Filesystem, FopenFlags, Generation, INodeNo, LockOwner,
-    OpenAccMode, OpenFlags, RenameFlags, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
-    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+    AccessFlags, BsdFileFlags, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags,
+    Generation, INodeNo, InitFlags, KernelConfig, LockOwner, OpenAccMode, OpenFlags, RenameFlags,
+    ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry,
+    ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
     WriteFlags,
 };
 use log::info;
 use std::collections::HashSet;
-use std::ffi::{CString, OsStr};
+use std::ffi::{CString, OsStr, OsString};
 use std::io;
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
-use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::{Duration, SystemTime};
 
 /// When to flush dirty data back to the archive.
@@ -20,13 +24,58 @@ use std::time::{Duration, SystemTime};
 pub(crate) enum WriteStrategy {
     /// Flush only on unmount (destroy).
     Lazy,
-    /// Flush on every file close (release).
+    /// Flush when a handle that modified its file is closed (release),
+    /// and on any close after changes made without a handle.
     Immediate,
 }
 
+/// How long the kernel may cache what pnafs tells it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct CachePolicy {
+    /// TTL of inode attributes (`getattr`, and those returned by lookups).
+    pub attr_ttl: Duration,
+    /// TTL of name -> inode lookups.
+    pub entry_ttl: Duration,
+    /// Reply `FOPEN_KEEP_CACHE` so cached file data survives `open`.
+    pub keep_cache: bool,
+    /// Negotiate `FUSE_WRITEBACK_CACHE` (`--write` mounts only): the
+    /// kernel buffers writes in the page cache and sends them in large
+    /// batches. It then owns the size of regular files: it extends its
+    /// cached `i_size` on buffered writes, computes `O_APPEND` offsets
+    /// from it and stops taking the size from `getattr`. The tree catches
+    /// up once the dirty pages are written back, which the kernel does
+    /// before `flush`, `fsync`, `fallocate`, `copy_file_range` and
+    /// `lseek`, so pnafs must never resize a file behind its back.
+    pub writeback: bool,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            keep_cache: false,
+            writeback: false,
+        }
+    }
+}
+
 pub(crate) struct PnaFS {
-    tree: RwLock<FileTree>,
+    /// Shared with the `--watch` reloader, which swaps in a freshly loaded
+    /// tree under the write lock.
+    tree: Arc<RwLock<FileTree>>,
     write_strategy: Option<WriteStrategy>,
+    /// POSIX record locks. Shared with the threads that serve blocking
+    /// `F_SETLKW` requests.
+    locks: Arc<LockTable>,
+    handles: HandleTable,
+    dirs: DirHandleTable,
+    cache: CachePolicy,
+    permissions: PermissionMode,
+    /// Hide setuid / setgid bits from reported attributes (`--nosuid`).
+    nosuid: bool,
+    /// Why the save on unmount failed, for whoever waits on the unmount.
+    unmount_error: Arc<Mutex<Option<io::Error>>>,
 }
 
 impl PnaFS {
@@ -34,14 +83,63 @@ impl PnaFS {
         archive: PathBuf,
         password: Option<String>,
         write_strategy: Option<WriteStrategy>,
+        options: LoadOptions,
     ) -> io::Result<Self> {
-        let tree = archive_io::load(&archive, password)?;
+        let tree = archive_io::load_with(&archive, password, options)?;
         Ok(Self {
-            tree: RwLock::new(tree),
+            tree: Arc::new(RwLock::new(tree)),
             write_strategy,
+            locks: Arc::default(),
+            handles: HandleTable::default(),
+            dirs: DirHandleTable::default(),
+            permissions: PermissionMode::default(),
+            cache: CachePolicy::default(),
+            nosuid: false,
+            unmount_error: Arc::default(),
         })
     }
 
+    pub(crate) fn with_cache_policy(mut self, cache: CachePolicy) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub(crate) fn with_permissions(mut self, permissions: PermissionMode) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    pub(crate) fn with_nosuid(mut self, nosuid: bool) -> Self {
+        self.nosuid = nosuid;
+        self
+    }
+
+    /// Save sparse files with their holes cut out (`--sparse`). Called
+    /// before mounting, so the lock is always free.
+    pub(crate) fn set_store_holes(&self, store_holes: bool) {
+        if let Ok(mut tree) = self.tree.write() {
+            tree.set_store_holes(store_holes);
+        }
+    }
+
+    /// Handle on the tree for background work that outlives a single
+    /// request, such as the `--watch` reloader.
+    pub(crate) fn shared_tree(&self) -> Arc<RwLock<FileTree>> {
+        Arc::clone(&self.tree)
+    }
+
+    /// Handle on the outcome of the save in `destroy`, which runs while
+    /// the session ends and so cannot return it.
+    pub(crate) fn shared_unmount_error(&self) -> Arc<Mutex<Option<io::Error>>> {
+        Arc::clone(&self.unmount_error)
+    }
+
+    fn set_unmount_error(&self, error: io::Error) {
+        if let Ok(mut slot) = self.unmount_error.lock() {
+            *slot = Some(error);
+        }
+    }
+
     fn require_writable(&self) -> Result<(), Errno> {
         if self.write_strategy.is_none() {
             Err(Errno::EROFS)
@@ -52,8 +150,9 @@ impl PnaFS {
 
     /// Acquire the tree read lock, fail-stop on poisoning.
     ///
-    /// All tree mutations happen under the write lock, so a panic in any
-    /// handler can leave the tree half-mutated. Once that happens the
+    /// Structural mutations happen under the write lock (per-file data
+    /// operations under the read lock plus a node lock, see [`FileTree`]),
+    /// so a panic in any handler can leave the tree half-mutated. Once that happens the
     /// only trustworthy state is the last successfully saved archive:
     /// every subsequent operation fails with `EIO` instead of observing
     /// (or persisting) a possibly-inconsistent tree.
@@ -73,6 +172,153 @@ impl PnaFS {
         })
     }
 
+    fn caller(req: &Request) -> Caller {
+        Caller {
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+        }
+    }
+
+    /// Under `--permissions pnafs`, `EACCES` unless the caller may access
+    /// `ino` for `mask`. A missing `ino` passes: the operation itself
+    /// reports that.
+    fn check_access(
+        &self,
+        req: &Request,
+        tree: &FileTree,
+        ino: Inode,
+        mask: AccessFlags,
+    ) -> Result<(), Errno> {
+        if self.permissions != PermissionMode::Pnafs {
+            return Ok(());
+        }
+        let Some(attr) = tree.get(ino).map(|n| n.attr) else {
+            return Ok(());
+        };
+        permissions::check(&attr, &Self::caller(req), mask)
+    }
+
+    /// Under `--permissions pnafs`, the checks for adding an entry to
+    /// directory `parent`, or for removing or replacing its entry
+    /// `victim`: write and search permission on `parent`, and the
+    /// sticky-bit rule for an existing `victim`.
+    fn check_dir_change(
+        &self,
+        req: &Request,
+        tree: &FileTree,
+        parent: Inode,
+        victim: Option<&OsStr>,
+    ) -> Result<(), Errno> {
+        if self.permissions != PermissionMode::Pnafs {
+            return Ok(());
+        }
+        let Some(dir) = tree.get(parent).map(|n| n.attr) else {
+            return Ok(());
+        };
+        let caller = Self::caller(req);
+        permissions::check(&dir, &caller, AccessFlags::W_OK | AccessFlags::X_OK)?;
+        if let Some(victim) = victim.and_then(|name| tree.lookup_child(parent, name)) {
+            permissions::check_sticky(&dir, &victim.attr, &caller)?;
+        }
+        Ok(())
+    }
+
+    /// Under `--permissions pnafs`, [`Self::check_dir_change`] on both
+    /// ends of a rename, plus write permission on a directory that moves
+    /// to a new parent, whose `..` entry changes.
+    fn check_rename(
+        &self,
+        req: &Request,
+        tree: &FileTree,
+        parent: Inode,
+        name: &OsStr,
+        newparent: Inode,
+        newname: &OsStr,
+    ) -> Result<(), Errno> {
+        self.check_dir_change(req, tree, parent, Some(name))?;
+        self.check_dir_change(req, tree, newparent, Some(newname))?;
+        let moved = tree
+            .lookup_child(parent, name)
+            .filter(|n| matches!(n.content, FsContent::Directory(_)))
+            .map(|n| n.attr.ino.0);
+        match moved {
+            Some(dir) if parent != newparent => {
+                self.check_access(req, tree, dir, AccessFlags::W_OK)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Under `--permissions pnafs`, the data-side checks of `setattr`:
+    /// truncating by path needs write permission, and so does setting
+    /// the times to now unless the caller owns the file. Explicit times
+    /// are for the owner (or root) only.
+    fn check_setattr(
+        &self,
+        req: &Request,
+        ino: Inode,
+        truncate: bool,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+    ) -> Result<(), Errno> {
+        if self.permissions != PermissionMode::Pnafs {
+            return Ok(());
+        }
+        let tree = self.read_tree()?;
+        if truncate {
+            self.check_access(req, &tree, ino, AccessFlags::W_OK)?;
+        }
+        let owner = tree.get(ino).map(|n| n.attr.uid);
+        if (atime.is_none() && mtime.is_none()) || req.uid() == 0 || owner == Some(req.uid()) {
+            return Ok(());
+        }
+        let explicit = [atime, mtime]
+            .iter()
+            .any(|t| matches!(t, Some(TimeOrNow::SpecificTime(_))));
+        if explicit {
+            Err(Errno::EPERM)
+        } else {
+            self.check_access(req, &tree, ino, AccessFlags::W_OK)
+        }
+    }
+
+    /// `(name, inode)` entries of directory `ino`.
+    fn dir_listing(tree: &FileTree, ino: Inode) -> Result<Vec<(OsString, Inode)>, Errno> {
+        match tree.get(ino) {
+            Some(n) if matches!(n.content, FsContent::Directory(_)) => {}
+            Some(_) => return Err(Errno::ENOTDIR),
+            None => return Err(Errno::ENOENT),
+        }
+        tree.listing(ino).ok_or(Errno::ENOTDIR)
+    }
+
+    /// The entries of the directory open as `fh` from readdir `offset` on,
+    /// each with the offset of the entry after it. Offsets index the
+    /// handle's snapshot of the listing (see [`DirHandleTable::listing`]);
+    /// entries unlinked or renamed since it was taken are left out, so
+    /// `readdirplus` never hands the kernel a dentry for a stale name.
+    fn dir_page<'a>(
+        &self,
+        tree: &'a FileTree,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+    ) -> Result<impl Iterator<Item = (u64, OsString, NodeRef<'a>)>, Errno> {
+        let listing = self
+            .dirs
+            .listing(fh, ino.0, offset, || Self::dir_listing(tree, ino.0))?;
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        Ok((start..listing.len()).filter_map(move |i| {
+            let (name, child) = &listing[i];
+            if tree.child_ino(ino.0, name) != Some(*child) {
+                return None;
+            }
+            let node = tree.get(*child)?;
+            Some((i as u64 + 1, name.clone(), node))
+        }))
+    }
+
     /// POSIX NAME_MAX on Linux. The kernel does not always enforce this before
     /// passing the request to FUSE, so reject oversized names here to match
     /// the syscall semantics tests expect.
@@ -85,16 +331,97 @@ impl PnaFS {
         }
     }
 
+    /// The work of `release`: drop the handle and its owner's locks, free
+    /// the inode if it was the last fd of an orphan, and save under
+    /// `--write-strategy immediate`.
+    fn close(&self, ino: Inode, fh: FileHandle, lock_owner: Option<u64>) -> Result<(), Errno> {
+        let handle = self.handles.remove(fh);
+        if let Some(owner) = lock_owner.or(handle.and_then(|h| h.lock_owner)) {
+            self.locks.unlock_owner(ino, owner);
+        }
+        // Decrement the fd counter under a read lock; only escalate to a
+        // write lock when this drop turns an orphan into a candidate for
+        // freeing, or when the write strategy saves on this close.
+        let (needs_free, unsaved_metadata) = {
+            let tree = self.read_tree()?;
+            (tree.release_open(ino), tree.has_unsaved_metadata())
+        };
+        // Data is saved when the handle that wrote it is closed. The
+        // writeback cache sends dirty pages through whichever writable
+        // handle the kernel picks, so any of them may hold the changes.
+        // Changes made without a handle (mkdir, rename, chmod, ...) are
+        // saved on the next close of any file.
+        let immediate = self.write_strategy == Some(WriteStrategy::Immediate)
+            && (unsaved_metadata
+                || handle.is_some_and(|h| h.dirtied || (self.cache.writeback && h.writable())));
+        if needs_free || immediate {
+            let mut tree = self.write_tree()?;
+            if needs_free {
+                tree.try_free_orphan(ino);
+                if tree.get(ino).is_none() {
+                    self.locks.forget_inode(ino);
+                }
+            }
+            if immediate && let Err(e) = Self::save_if_dirty(&mut tree) {
+                log::error!("Failed to save on release: {e}");
+                return Err(Errno::EIO);
+            }
+        }
+        Ok(())
+    }
+
     /// Save the archive and mark the tree clean. Returns `Ok(())` even when
     /// there is nothing to save.
+    ///
+    /// A failed save (including one refused because the archive changed on
+    /// disk) leaves the tree dirty, so the delta is retried on the next
+    /// flush point rather than silently dropped.
     fn save_if_dirty(tree: &mut FileTree) -> io::Result<()> {
         if tree.is_dirty() {
-            archive_io::save(tree)?;
+            let written = archive_io::save(tree)?;
+            tree.set_origin(written);
             tree.mark_clean();
         }
         Ok(())
     }
 
+    /// Reply flags for an open with `flags`: `O_DIRECT` opens bypass the
+    /// page cache, others keep it under `--kernel-cache`.
+    fn fopen_flags(&self, flags: OpenFlags) -> FopenFlags {
+        if flags.0 & libc::O_DIRECT != 0 {
+            FopenFlags::FOPEN_DIRECT_IO
+        } else if self.cache.keep_cache {
+            FopenFlags::FOPEN_KEEP_CACHE
+        } else {
+            FopenFlags::empty()
+        }
+    }
+
+    /// `attr` as reported to the kernel. The archived mode keeps its
+    /// setuid / setgid bits even when `--nosuid` hides them.
+    fn reported(&self, attr: &FileAttr) -> FileAttr {
+        let mut attr = *attr;
+        if self.nosuid {
+            // S_ISGID on a directory only makes new entries inherit its
+            // group; it grants nothing, so it stays visible.
+            let mut hidden = libc::S_ISUID;
+            if attr.kind != FileType::Directory {
+                hidden |= libc::S_ISGID;
+            }
+            attr.perm &= !(hidden as u16);
+        }
+        attr
+    }
+
+    fn reply_entry(&self, reply: ReplyEntry, tree: &FileTree, attr: &FileAttr) {
+        reply.entry_with_ttls(
+            &self.cache.attr_ttl,
+            &self.cache.entry_ttl,
+            &self.reported(attr),
+            Generation(tree.generation(attr.ino.0)),
+        );
+    }
+
     /// Walk the directory tree from the root and total the byte size and
     /// inode count of every reachable node. A hard-linked inode reachable
     /// through multiple directory entries is counted once (the visited
@@ -128,7 +455,35 @@ impl PnaFS {
 const STATFS_BSIZE: u32 = 512;
 
 impl Filesystem for PnaFS {
-    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> io::Result<()> {
+        // Arbitrate POSIX record locks ourselves (see `file_locks`).
+        if let Err(missing) = config.add_capabilities(InitFlags::FUSE_POSIX_LOCKS) {
+            log::warn!("kernel lacks {missing:?}; record locks stay local to the kernel");
+        }
+        // Receive O_TRUNC in `open` instead of a separate truncating setattr.
+        if let Err(missing) = config.add_capabilities(InitFlags::FUSE_ATOMIC_O_TRUNC) {
+            log::warn!("kernel lacks {missing:?}; O_TRUNC arrives as a separate setattr");
+        }
+        // Answer `ls -l` with one readdirplus instead of readdir plus a
+        // lookup per entry. AUTO lets the kernel stick to plain readdir
+        // when nobody stats the entries.
+        if let Err(missing) = config.add_capabilities(InitFlags::FUSE_DO_READDIRPLUS) {
+            log::warn!("kernel lacks {missing:?}; listings need a lookup per entry");
+        } else if let Err(missing) = config.add_capabilities(InitFlags::FUSE_READDIRPLUS_AUTO) {
+            log::warn!("kernel lacks {missing:?}; every listing uses readdirplus");
+        }
+        if self.cache.writeback && self.write_strategy.is_some() {
+            if let Err(missing) = config.add_capabilities(InitFlags::FUSE_WRITEBACK_CACHE) {
+                log::warn!("kernel lacks {missing:?}; writes go through uncached");
+                self.cache.writeback = false;
+            }
+        } else {
+            self.cache.writeback = false;
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
         info!("[Implemented] lookup(parent: {parent:#x?}, name {name:?})");
         if let Err(e) = Self::check_name(name) {
             reply.error(e);
@@ -138,9 +493,15 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        // Search permission is only checked here; the kernel skips lookups
+        // of cached entries, which is why `--permissions pnafs` defaults
+        // the entry TTL to zero.
+        if let Err(e) = self.check_access(req, &tree, parent.0, AccessFlags::X_OK) {
+            reply.error(e);
+            return;
+        }
         if let Some(node) = tree.lookup_child(parent.0, name) {
-            let ttl = Duration::from_secs(1);
-            reply.entry(&ttl, &node.attr, Generation(0));
+            self.reply_entry(reply, &tree, &node.attr);
         } else {
             reply.error(Errno::ENOENT);
         }
@@ -148,13 +509,12 @@ impl Filesystem for PnaFS {
 
     fn getattr(&self, _req: &Request, ino: INodeNo, fh: Option<FileHandle>, reply: ReplyAttr) {
         info!("[Implemented] getattr(ino: {ino:#x?}, fh: {fh:#x?})");
-        let ttl = Duration::from_secs(1);
         let tree = match self.read_tree() {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
         if let Some(node) = tree.get(ino.0) {
-            reply.attr(&ttl, &node.attr);
+            reply.attr(&self.cache.attr_ttl, &self.reported(&node.attr));
         } else {
             reply.error(Errno::ENOENT);
         }
@@ -177,7 +537,7 @@ impl Filesystem for PnaFS {
         }
     }
 
-    fn open(&self, _req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+    fn open(&self, req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
         info!("[Implemented] open(ino: {ino:#x?}, flags: {flags:#x?})");
         if self.write_strategy.is_none() && flags.acc_mode() != OpenAccMode::O_RDONLY {
             reply.error(Errno::EROFS);
@@ -188,11 +548,29 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_access(req, &tree, ino.0, permissions::open_mask(flags)) {
+            reply.error(e);
+            return;
+        }
+        // Only sent with FUSE_ATOMIC_O_TRUNC, which the kernel strips
+        // unless the open is writable.
+        let truncate = flags.0 & libc::O_TRUNC != 0 && flags.acc_mode() != OpenAccMode::O_RDONLY;
+        let truncated = match truncate.then(|| tree.truncate_on_open(ino.0)) {
+            Some(Err(e)) => {
+                reply.error(e);
+                return;
+            }
+            Some(Ok(changed)) => changed,
+            None => false,
+        };
         if let Err(e) = tree.bump_open(ino.0) {
             reply.error(e);
             return;
         }
-        reply.opened(FileHandle(0), FopenFlags::empty());
+        reply.opened(
+            self.handles.insert(ino.0, flags, truncated),
+            self.fopen_flags(flags),
+        );
     }
 
     fn read(
@@ -245,28 +623,54 @@ impl Filesystem for PnaFS {
         &self,
         _req: &Request,
         ino: INodeNo,
-        _fh: FileHandle,
+        fh: FileHandle,
         offset: u64,
         data: &[u8],
-        _write_flags: WriteFlags,
+        write_flags: WriteFlags,
         _flags: OpenFlags,
-        _lock_owner: Option<LockOwner>,
+        lock_owner: Option<LockOwner>,
         reply: ReplyWrite,
     ) {
         info!(
             "[Implemented] write(ino: {ino:#x?}, offset: {offset}, data.len(): {})",
             data.len()
         );
-        if let Err(e) = self.require_writable() {
+        if let Err(e) = self
+            .require_writable()
+            .and_then(|()| self.handles.check_writable(fh, ino.0))
+        {
             reply.error(e);
             return;
         }
-        let mut tree = match self.write_tree() {
+        if let Some(owner) = lock_owner {
+            self.handles.note_owner(fh, owner.0);
+        }
+        // O_APPEND writes land at the tree's size, not the kernel's cached
+        // one. Page-cache writeback (mmap) carries its own offsets even
+        // when it borrows an O_APPEND handle, and with the writeback cache
+        // the kernel owns the size and has already placed every write.
+        let append = !self.cache.writeback
+            && !write_flags.contains(WriteFlags::FUSE_WRITE_CACHE)
+            && self
+                .handles
+                .get(fh)
+                .is_some_and(|h| h.flags.0 & libc::O_APPEND != 0);
+        // Data operation: the node's own lock serialises it, so writers
+        // to other files proceed in parallel.
+        let tree = match self.read_tree() {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
-        match tree.write_file(ino.0, offset, data) {
-            Ok(written) => reply.written(written as u32),
+        let result = if append {
+            tree.append_file(ino.0, data)
+        } else {
+            tree.write_file(ino.0, offset, data)
+        };
+        match result {
+            Ok(written) => {
+                self.handles.mark_dirty(fh);
+                reply.written(written as u32);
+            }
             Err(e) => reply.error(e),
         }
     }
@@ -280,6 +684,9 @@ impl Filesystem for PnaFS {
         reply: ReplyEmpty,
     ) {
         info!("[Implemented] flush(ino: {ino:#x?}, fh: {fh:?}, lock_owner: {lock_owner:?})");
+        // POSIX: closing any fd drops the process's record locks on the file.
+        self.locks.unlock_owner(ino.0, lock_owner.0);
+        self.handles.note_owner(fh, lock_owner.0);
         let tree = match self.read_tree() {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
@@ -295,39 +702,17 @@ impl Filesystem for PnaFS {
         &self,
         _req: &Request,
         ino: INodeNo,
-        _fh: FileHandle,
+        fh: FileHandle,
         _flags: OpenFlags,
-        _lock_owner: Option<LockOwner>,
+        lock_owner: Option<LockOwner>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        info!("[Implemented] release(ino: {ino:#x?})");
-        // Decrement the fd counter under a read lock; only escalate to a
-        // write lock when this drop turns an orphan into a candidate for
-        // freeing (or when the configured write strategy demands a save).
-        let needs_free = {
-            let tree = match self.read_tree() {
-                Ok(tree) => tree,
-                Err(e) => return reply.error(e),
-            };
-            tree.release_open(ino.0)
-        };
-        let immediate = self.write_strategy == Some(WriteStrategy::Immediate);
-        if needs_free || immediate {
-            let mut tree = match self.write_tree() {
-                Ok(tree) => tree,
-                Err(e) => return reply.error(e),
-            };
-            if needs_free {
-                tree.try_free_orphan(ino.0);
-            }
-            if immediate && let Err(e) = Self::save_if_dirty(&mut tree) {
-                log::error!("Failed to save on release: {e}");
-                reply.error(Errno::EIO);
-                return;
-            }
+        info!("[Implemented] release(ino: {ino:#x?}, fh: {fh:?})");
+        match self.close(ino.0, fh, lock_owner.map(|o| o.0)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
         }
-        reply.ok();
     }
 
     fn fsync(
@@ -339,6 +724,8 @@ impl Filesystem for PnaFS {
         reply: ReplyEmpty,
     ) {
         info!("[Implemented] fsync(ino: {_ino:#x?})");
+        // With the writeback cache the kernel writes the file's dirty
+        // pages back before sending FSYNC, so the save below includes them.
         if self.write_strategy.is_some() {
             let mut tree = match self.write_tree() {
                 Ok(tree) => tree,
@@ -357,7 +744,7 @@ impl Filesystem for PnaFS {
         &self,
         _req: &Request,
         ino: INodeNo,
-        _fh: FileHandle,
+        fh: FileHandle,
         offset: u64,
         length: u64,
         mode: i32,
@@ -366,16 +753,24 @@ impl Filesystem for PnaFS {
         info!(
             "[Implemented] fallocate(ino: {ino:#x?}, offset: {offset}, length: {length}, mode: {mode:#x})"
         );
-        if let Err(e) = self.require_writable() {
+        if let Err(e) = self
+            .require_writable()
+            .and_then(|()| self.handles.check_writable(fh, ino.0))
+        {
             reply.error(e);
             return;
         }
-        let mut tree = match self.write_tree() {
+        // Data operation: the node's own lock serialises it, so writers
+        // to other files proceed in parallel.
+        let tree = match self.read_tree() {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
         match tree.fallocate(ino.0, offset, length, mode) {
-            Ok(()) => reply.ok(),
+            Ok(()) => {
+                self.handles.mark_dirty(fh);
+                reply.ok();
+            }
             Err(e) => reply.error(e),
         }
     }
@@ -387,7 +782,7 @@ impl Filesystem for PnaFS {
         _fh_in: FileHandle,
         offset_in: u64,
         ino_out: INodeNo,
-        _fh_out: FileHandle,
+        fh_out: FileHandle,
         offset_out: u64,
         len: u64,
         _flags: fuser::CopyFileRangeFlags,
@@ -396,16 +791,24 @@ impl Filesystem for PnaFS {
         info!(
             "[Implemented] copy_file_range(ino_in: {ino_in:#x?}, offset_in: {offset_in}, ino_out: {ino_out:#x?}, offset_out: {offset_out}, len: {len})"
         );
-        if let Err(e) = self.require_writable() {
+        if let Err(e) = self
+            .require_writable()
+            .and_then(|()| self.handles.check_writable(fh_out, ino_out.0))
+        {
             reply.error(e);
             return;
         }
-        let mut tree = match self.write_tree() {
+        // Data operation: the node's own lock serialises it, so writers
+        // to other files proceed in parallel.
+        let tree = match self.read_tree() {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
         match tree.copy_file_range(ino_in.0, offset_in, ino_out.0, offset_out, len) {
-            Ok(written) => reply.written(written as u32),
+            Ok(written) => {
+                self.handles.mark_dirty(fh_out);
+                reply.written(written as u32);
+            }
             Err(e) => reply.error(e),
         }
     }
@@ -421,7 +824,7 @@ impl Filesystem for PnaFS {
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
-        _fh: Option<FileHandle>,
+        fh: Option<FileHandle>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
@@ -438,7 +841,10 @@ impl Filesystem for PnaFS {
         // chown-specific rule that only root may change a file's uid
         // (and only the owner — or root — may change gid). Without these
         // checks an --allow-other mount would let any user re-own files.
-        if uid.is_some() || gid.is_some() || mode.is_some() {
+        // `--permissions ignore` waives them along with everything else.
+        if (uid.is_some() || gid.is_some() || mode.is_some())
+            && self.permissions != PermissionMode::Ignore
+        {
             let tree = match self.read_tree() {
                 Ok(tree) => tree,
                 Err(e) => return reply.error(e),
@@ -466,6 +872,35 @@ impl Filesystem for PnaFS {
                 return;
             }
         }
+        if let Err(e) = self.check_setattr(req, ino.0, size.is_some() && fh.is_none(), atime, mtime)
+        {
+            reply.error(e);
+            return;
+        }
+        let ttl = self.cache.attr_ttl;
+        // A bare truncate is a data operation like `write`.
+        if let Some(new_size) = size
+            && atime.is_none()
+            && mtime.is_none()
+            && mode.is_none()
+            && uid.is_none()
+            && gid.is_none()
+        {
+            let tree = match self.read_tree() {
+                Ok(tree) => tree,
+                Err(e) => return reply.error(e),
+            };
+            if let Err(e) = tree.set_size(ino.0, new_size) {
+                return reply.error(e);
+            }
+            if let Some(fh) = fh {
+                self.handles.mark_dirty(fh);
+            }
+            return match tree.get(ino.0) {
+                Some(node) => reply.attr(&ttl, &self.reported(&node.attr)),
+                None => reply.error(Errno::ENOENT),
+            };
+        }
         let mut tree = match self.write_tree() {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
@@ -476,6 +911,9 @@ impl Filesystem for PnaFS {
             reply.error(e);
             return;
         }
+        if let Some(fh) = fh {
+            self.handles.mark_dirty(fh);
+        }
         if (atime.is_some() || mtime.is_some())
             && let Err(e) = tree.set_times(ino.0, atime, mtime)
         {
@@ -488,9 +926,8 @@ impl Filesystem for PnaFS {
             reply.error(e);
             return;
         }
-        let ttl = Duration::from_secs(1);
         match tree.get(ino.0) {
-            Some(node) => reply.attr(&ttl, &node.attr),
+            Some(node) => reply.attr(&ttl, &self.reported(&node.attr)),
             None => reply.error(Errno::ENOENT),
         }
     }
@@ -519,16 +956,31 @@ impl Filesystem for PnaFS {
             Err(e) => return reply.error(e),
         };
         let existing = tree.lookup_child(parent.0, name).map(|n| n.attr.ino.0);
+        let allowed = match existing {
+            Some(ino) => {
+                self.check_access(req, &tree, ino, permissions::open_mask(OpenFlags(flags)))
+            }
+            None => self.check_dir_change(req, &tree, parent.0, None),
+        };
+        if let Err(e) = allowed {
+            reply.error(e);
+            return;
+        }
+        // A new or truncated file counts as modified by the new handle.
+        let mut dirtied = existing.is_none();
         let result_ino = if let Some(ino) = existing {
             if (flags & libc::O_EXCL) != 0 {
                 reply.error(Errno::EEXIST);
                 return;
             }
-            if (flags & libc::O_TRUNC) != 0
-                && let Err(e) = tree.set_size(ino, 0)
-            {
-                reply.error(e);
-                return;
+            if (flags & libc::O_TRUNC) != 0 {
+                match tree.truncate_on_open(ino) {
+                    Ok(changed) => dirtied = changed,
+                    Err(e) => {
+                        reply.error(e);
+                        return;
+                    }
+                }
             }
             ino
         } else {
@@ -562,12 +1014,13 @@ impl Filesystem for PnaFS {
                 return;
             }
         };
+        // One TTL covers both the new entry and its attributes.
         reply.created(
-            &Duration::from_secs(1),
-            &attr,
-            Generation(0),
-            FileHandle(0),
-            FopenFlags::empty(),
+            &self.cache.attr_ttl.min(self.cache.entry_ttl),
+            &self.reported(&attr),
+            Generation(tree.generation(result_ino)),
+            self.handles.insert(result_ino, OpenFlags(flags), dirtied),
+            self.fopen_flags(OpenFlags(flags)),
         );
     }
 
@@ -605,6 +1058,10 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_dir_change(req, &tree, parent.0, None) {
+            reply.error(e);
+            return;
+        }
         let result = match kind {
             // mknod(S_IFREG, ...) is `create()` without the returned fd.
             NodeKind::Regular => tree
@@ -615,7 +1072,7 @@ impl Filesystem for PnaFS {
                 .map(|n| n.attr),
         };
         match result {
-            Ok(attr) => reply.entry(&Duration::from_secs(1), &attr, Generation(0)),
+            Ok(attr) => self.reply_entry(reply, &tree, &attr),
             Err(e) => reply.error(e),
         }
     }
@@ -642,6 +1099,10 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_dir_change(req, &tree, parent.0, None) {
+            reply.error(e);
+            return;
+        }
         match tree.make_dir(
             parent.0,
             name,
@@ -651,13 +1112,13 @@ impl Filesystem for PnaFS {
         ) {
             Ok(node) => {
                 let attr = node.attr;
-                reply.entry(&Duration::from_secs(1), &attr, Generation(0));
+                self.reply_entry(reply, &tree, &attr);
             }
             Err(e) => reply.error(e),
         }
     }
 
-    fn unlink(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
         info!("[Implemented] unlink(parent: {parent:#x?}, name: {name:?})");
         if let Err(e) = self.require_writable() {
             reply.error(e);
@@ -671,13 +1132,17 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_dir_change(req, &tree, parent.0, Some(name)) {
+            reply.error(e);
+            return;
+        }
         match tree.unlink(parent.0, name) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
     }
 
-    fn rmdir(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
         info!("[Implemented] rmdir(parent: {parent:#x?}, name: {name:?})");
         if let Err(e) = self.require_writable() {
             reply.error(e);
@@ -691,6 +1156,10 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_dir_change(req, &tree, parent.0, Some(name)) {
+            reply.error(e);
+            return;
+        }
         match tree.rmdir(parent.0, name) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
@@ -699,7 +1168,7 @@ impl Filesystem for PnaFS {
 
     fn link(
         &self,
-        _req: &Request,
+        req: &Request,
         ino: INodeNo,
         newparent: INodeNo,
         newname: &OsStr,
@@ -720,10 +1189,14 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_dir_change(req, &tree, newparent.0, None) {
+            reply.error(e);
+            return;
+        }
         match tree.create_hardlink(newparent.0, newname, ino.0) {
             Ok(node) => {
                 let attr = node.attr;
-                reply.entry(&Duration::from_secs(1), &attr, Generation(0));
+                self.reply_entry(reply, &tree, &attr);
             }
             Err(e) => reply.error(e),
         }
@@ -750,10 +1223,14 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_dir_change(req, &tree, parent.0, None) {
+            reply.error(e);
+            return;
+        }
         match tree.create_symlink(parent.0, name, link, Owner::new(req.uid(), req.gid())) {
             Ok(node) => {
                 let attr = node.attr;
-                reply.entry(&Duration::from_secs(1), &attr, Generation(0));
+                self.reply_entry(reply, &tree, &attr);
             }
             Err(e) => reply.error(e),
         }
@@ -761,7 +1238,7 @@ impl Filesystem for PnaFS {
 
     fn rename(
         &self,
-        _req: &Request,
+        req: &Request,
         parent: INodeNo,
         name: &OsStr,
         newparent: INodeNo,
@@ -788,12 +1265,48 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_rename(req, &tree, parent.0, name, newparent.0, newname) {
+            reply.error(e);
+            return;
+        }
         match tree.rename(parent.0, name, newparent.0, newname, flags) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
     }
 
+    fn opendir(&self, req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        info!("[Implemented] opendir(ino: {ino:#x?}, flags: {flags:#x?})");
+        let tree = match self.read_tree() {
+            Ok(tree) => tree,
+            Err(e) => return reply.error(e),
+        };
+        if let Err(e) = self.check_access(req, &tree, ino.0, permissions::open_mask(flags)) {
+            reply.error(e);
+            return;
+        }
+        match tree.get(ino.0) {
+            Some(n) if matches!(n.content, FsContent::Directory(_)) => {
+                reply.opened(self.dirs.insert(ino.0), FopenFlags::empty());
+            }
+            Some(_) => reply.error(Errno::ENOTDIR),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn releasedir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        reply: ReplyEmpty,
+    ) {
+        info!("[Implemented] releasedir(ino: {ino:#x?}, fh: {fh:?})");
+        self.dirs.remove(fh);
+        reply.ok();
+    }
+
     fn readdir(
         &self,
         _req: &Request,
@@ -807,35 +1320,76 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
-        let node = match tree.get(ino.0) {
-            Some(n) => n,
-            None => {
-                reply.error(Errno::ENOENT);
-                return;
-            }
+        let children = match self.dir_page(&tree, ino, fh, offset) {
+            Ok(children) => children,
+            Err(e) => return reply.error(e),
         };
-        if !matches!(node.content, FsContent::Directory(_)) {
-            reply.error(Errno::ENOTDIR);
-            return;
+        for (next, name, node) in children {
+            if reply.add(node.attr.ino, next, node.attr.kind, name) {
+                break;
+            }
         }
-        let mut current_offset = offset + 1;
-        for (name, node) in tree.children(ino.0).unwrap().skip(offset as usize) {
-            let is_full = reply.add(node.attr.ino, current_offset, node.attr.kind, name);
-            if is_full {
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        info!("[Implemented] readdirplus(ino: {ino:#x?}, fh: {fh:?}, offset: {offset})");
+        let tree = match self.read_tree() {
+            Ok(tree) => tree,
+            Err(e) => return reply.error(e),
+        };
+        let children = match self.dir_page(&tree, ino, fh, offset) {
+            Ok(children) => children,
+            Err(e) => return reply.error(e),
+        };
+        // Each entry doubles as a lookup reply; one TTL covers both parts.
+        let ttl = self.cache.attr_ttl.min(self.cache.entry_ttl);
+        for (next, name, node) in children {
+            let attr = self.reported(&node.attr);
+            let generation = Generation(tree.generation(node.attr.ino.0));
+            if reply.add(node.attr.ino, next, name, &ttl, &attr, generation) {
                 break;
-            } else {
-                current_offset += 1;
             }
         }
         reply.ok();
     }
 
-    fn getxattr(&self, _req: &Request, ino: INodeNo, name: &OsStr, size: u32, reply: ReplyXattr) {
+    fn access(&self, req: &Request, ino: INodeNo, mask: AccessFlags, reply: ReplyEmpty) {
+        info!("[Implemented] access(ino: {ino:#x?}, mask: {mask})");
+        // Only sent without `default_permissions`, i.e. under
+        // `--permissions ignore` (any existing file passes) or `pnafs`.
+        // The kernel itself answers W_OK on a read-only mount.
+        let tree = match self.read_tree() {
+            Ok(tree) => tree,
+            Err(e) => return reply.error(e),
+        };
+        if tree.get(ino.0).is_none() {
+            reply.error(Errno::ENOENT);
+            return;
+        }
+        match self.check_access(req, &tree, ino.0, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getxattr(&self, req: &Request, ino: INodeNo, name: &OsStr, size: u32, reply: ReplyXattr) {
         info!("[Implemented] getxattr(ino: {ino:#x?}, name: {name:?}, size: {size})");
         let tree = match self.read_tree() {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_access(req, &tree, ino.0, AccessFlags::R_OK) {
+            reply.error(e);
+            return;
+        }
         let Some(node) = tree.get(ino.0) else {
             reply.error(Errno::ENOENT);
             return;
@@ -855,7 +1409,7 @@ impl Filesystem for PnaFS {
 
     fn setxattr(
         &self,
-        _req: &Request,
+        req: &Request,
         ino: INodeNo,
         name: &OsStr,
         value: &[u8],
@@ -881,13 +1435,17 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_access(req, &tree, ino.0, AccessFlags::W_OK) {
+            reply.error(e);
+            return;
+        }
         match tree.setxattr(ino.0, name_str, value, flags) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
         }
     }
 
-    fn removexattr(&self, _req: &Request, ino: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+    fn removexattr(&self, req: &Request, ino: INodeNo, name: &OsStr, reply: ReplyEmpty) {
         info!("[Implemented] removexattr(ino: {ino:#x?}, name: {name:?})");
         if let Err(e) = self.require_writable() {
             reply.error(e);
@@ -904,6 +1462,10 @@ impl Filesystem for PnaFS {
             Ok(tree) => tree,
             Err(e) => return reply.error(e),
         };
+        if let Err(e) = self.check_access(req, &tree, ino.0, AccessFlags::W_OK) {
+            reply.error(e);
+            return;
+        }
         match tree.removexattr(ino.0, name_str) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e),
@@ -937,6 +1499,124 @@ impl Filesystem for PnaFS {
         }
     }
 
+    fn getlk(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        lock_owner: LockOwner,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        info!("[Implemented] getlk(ino: {ino:#x?}, start: {start}, end: {end}, typ: {typ})");
+        let kind = match LockKind::from_raw(typ) {
+            Ok(Some(kind)) => kind,
+            Ok(None) => return reply.error(Errno::EINVAL),
+            Err(e) => return reply.error(e),
+        };
+        match self.locks.test(ino.0, lock_owner.0, start, end, kind) {
+            Some(l) => reply.locked(l.start, l.end, l.kind.to_raw(), l.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, pid),
+        }
+    }
+
+    fn setlk(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        lock_owner: LockOwner,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        info!(
+            "[Implemented] setlk(ino: {ino:#x?}, start: {start}, end: {end}, typ: {typ}, sleep: {sleep})"
+        );
+        let kind = match LockKind::from_raw(typ) {
+            Ok(kind) => kind,
+            Err(e) => return reply.error(e),
+        };
+        if start > end {
+            return reply.error(Errno::EINVAL);
+        }
+        if !sleep {
+            return match self.locks.lock(ino.0, lock_owner.0, pid, start, end, kind) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            };
+        }
+        // F_SETLKW may wait indefinitely. Park it on its own thread so the
+        // FUSE workers stay free to serve the request that releases it;
+        // the number of such threads is bounded.
+        let Some(slot) = self.locks.reserve_waiter() else {
+            return reply.error(Errno::ENOLCK);
+        };
+        // The reply follows once the thread runs, so a failed spawn can
+        // still answer it.
+        let (handoff, received) = std::sync::mpsc::sync_channel::<ReplyEmpty>(1);
+        let locks = Arc::clone(&self.locks);
+        let spawned = std::thread::Builder::new()
+            .name("pnafs-setlkw".to_owned())
+            .spawn(move || {
+                let Ok(reply) = received.recv() else {
+                    return;
+                };
+                let result = locks.lock_wait(ino.0, lock_owner.0, pid, start, end, kind);
+                drop(slot);
+                match result {
+                    Ok(()) => reply.ok(),
+                    Err(e) => reply.error(e),
+                }
+            });
+        match spawned {
+            Ok(_) => {
+                // The thread holds the receiver until it has the reply.
+                let _ = handoff.send(reply);
+            }
+            Err(e) => {
+                log::error!("setlk: could not spawn a waiter thread: {e}");
+                reply.error(Errno::ENOLCK);
+            }
+        }
+    }
+
+    fn lseek(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        info!("[Implemented] lseek(ino: {ino:#x?}, offset: {offset}, whence: {whence})");
+        // The kernel resolves SEEK_SET/CUR/END itself and only forwards
+        // the hole-aware whence values.
+        let data = match whence {
+            libc::SEEK_DATA => true,
+            libc::SEEK_HOLE => false,
+            _ => return reply.error(Errno::EINVAL),
+        };
+        let Ok(offset) = u64::try_from(offset) else {
+            return reply.error(Errno::ENXIO);
+        };
+        let tree = match self.read_tree() {
+            Ok(tree) => tree,
+            Err(e) => return reply.error(e),
+        };
+        match tree.seek_hole_data(ino.0, offset, data) {
+            Ok(found) => reply.offset(found as i64),
+            Err(e) => reply.error(e),
+        }
+    }
+
     fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
         info!("[Implemented] statfs()");
         let tree = match self.read_tree() {
@@ -957,7 +1637,7 @@ impl Filesystem for PnaFS {
     fn destroy(&mut self) {
         info!("[Implemented] destroy()");
         if self.write_strategy.is_some() {
-            let tree = match self.tree.get_mut() {
+            let mut tree = match self.tree.write() {
                 Ok(tree) => tree,
                 Err(_poisoned) => {
                     // A handler panicked while holding the lock; the tree
@@ -973,12 +1653,17 @@ impl Filesystem for PnaFS {
                          possibly inconsistent; any unsaved changes since the last \
                          save were NOT persisted, to protect the on-disk archive"
                     );
+                    self.set_unmount_error(io::Error::other(
+                        "an internal panic left the in-memory state possibly \
+                         inconsistent; unsaved changes were NOT persisted",
+                    ));
                     return;
                 }
             };
-            if let Err(e) = Self::save_if_dirty(tree) {
+            if let Err(e) = Self::save_if_dirty(&mut tree) {
                 eprintln!("pnafs: CRITICAL: failed to save archive on unmount: {e}");
                 log::error!("Failed to save archive on destroy: {e}");
+                self.set_unmount_error(e);
             }
         }
     }
@@ -1086,7 +1771,7 @@ mod tests {
     fn poisoned_lock_fails_with_eio_instead_of_panicking() {
         let dir = TempDir::new().unwrap();
         let path = create_plain_archive(&dir, "a.pna", &[("f", b"x")]);
-        let fs = PnaFS::new(path, None, None).unwrap();
+        let fs = PnaFS::new(path, None, None, LoadOptions::default()).unwrap();
         poison_tree_lock(&fs);
         let read_err = fs.read_tree().map(|_| ()).unwrap_err();
         assert_eq!(read_err.code(), Errno::EIO.code());
@@ -1098,16 +1783,78 @@ mod tests {
     fn healthy_lock_hands_out_guards() {
         let dir = TempDir::new().unwrap();
         let path = create_plain_archive(&dir, "a.pna", &[("f", b"x")]);
-        let fs = PnaFS::new(path, None, None).unwrap();
+        let fs = PnaFS::new(path, None, None, LoadOptions::default()).unwrap();
         assert!(fs.read_tree().is_ok());
         assert!(fs.write_tree().is_ok());
     }
 
+    #[test]
+    fn nosuid_hides_setid_bits_but_keeps_them_stored() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "a.pna", &[("f", b"x")]);
+        let fs = PnaFS::new(path, None, None, LoadOptions::default())
+            .unwrap()
+            .with_nosuid(true);
+        let mut tree = fs.tree.write().unwrap();
+        let ino = tree.resolve_path(std::path::Path::new("f")).unwrap();
+        tree.get_mut(ino).unwrap().attr.perm = 0o6755;
+        let attr = tree.get(ino).unwrap().attr;
+        assert_eq!(fs.reported(&attr).perm, 0o755);
+        assert_eq!(attr.perm, 0o6755);
+
+        tree.get_mut(ROOT_INODE).unwrap().attr.perm = 0o6755;
+        let attr = tree.get(ROOT_INODE).unwrap().attr;
+        assert_eq!(fs.reported(&attr).perm, 0o2755);
+    }
+
+    #[test]
+    fn immediate_saves_changes_made_without_a_handle_on_any_close() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "a.pna", &[("f", b"x")]);
+        let fs = PnaFS::new(
+            path.clone(),
+            None,
+            Some(WriteStrategy::Immediate),
+            LoadOptions::default(),
+        )
+        .unwrap();
+        let f = {
+            let mut tree = fs.tree.write().unwrap();
+            tree.make_dir(
+                ROOT_INODE,
+                std::ffi::OsStr::new("d"),
+                0o755,
+                0,
+                Owner::new(0, 0),
+            )
+            .unwrap();
+            let f = tree.resolve_path(std::path::Path::new("f")).unwrap();
+            tree.bump_open(f).unwrap();
+            f
+        };
+        let fh = fs.handles.insert(f, OpenFlags(libc::O_RDONLY), false);
+        fs.close(f, fh, None).unwrap();
+        let reloaded = archive_io::load(&path, None).unwrap();
+        assert!(
+            reloaded
+                .lookup_child(ROOT_INODE, std::ffi::OsStr::new("d"))
+                .is_some(),
+            "closing a read-only handle must save the mkdir"
+        );
+        assert!(!fs.tree.read().unwrap().is_dirty());
+    }
+
     #[test]
     fn destroy_saves_dirty_tree() {
         let dir = TempDir::new().unwrap();
         let path = create_plain_archive(&dir, "a.pna", &[("f", b"x")]);
-        let mut fs = PnaFS::new(path.clone(), None, Some(WriteStrategy::Lazy)).unwrap();
+        let mut fs = PnaFS::new(
+            path.clone(),
+            None,
+            Some(WriteStrategy::Lazy),
+            LoadOptions::default(),
+        )
+        .unwrap();
         {
             let mut tree = fs.tree.write().unwrap();
             tree.create_file(
@@ -1119,6 +1866,7 @@ mod tests {
             .unwrap();
         }
         fs.destroy();
+        assert!(fs.shared_unmount_error().lock().unwrap().is_none());
         let reloaded = archive_io::load(&path, None).unwrap();
         assert!(
             reloaded
@@ -1128,12 +1876,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn destroy_records_why_the_save_failed() {
+        let dir = TempDir::new().unwrap();
+        let path = create_plain_archive(&dir, "a.pna", &[("f", b"x")]);
+        let mut fs = PnaFS::new(
+            path.clone(),
+            None,
+            Some(WriteStrategy::Lazy),
+            LoadOptions::default(),
+        )
+        .unwrap();
+        {
+            let mut tree = fs.tree.write().unwrap();
+            tree.create_file(
+                ROOT_INODE,
+                std::ffi::OsStr::new("created"),
+                0o644,
+                Owner::new(0, 0),
+            )
+            .unwrap();
+        }
+        // Someone else rewrites the archive, so the save is refused.
+        create_plain_archive(&dir, "a.pna", &[("f", b"theirs")]);
+        fs.destroy();
+        let error = fs.shared_unmount_error().lock().unwrap().take();
+        assert!(
+            error.is_some_and(|e| e.to_string().contains("modified by another process")),
+            "the refused save must be reported to the unmount"
+        );
+    }
+
     #[test]
     fn destroy_with_poisoned_lock_keeps_archive_bytes_intact() {
         let dir = TempDir::new().unwrap();
         let path = create_plain_archive(&dir, "a.pna", &[("f", b"x")]);
         let before = std::fs::read(&path).unwrap();
-        let mut fs = PnaFS::new(path.clone(), None, Some(WriteStrategy::Lazy)).unwrap();
+        let mut fs = PnaFS::new(
+            path.clone(),
+            None,
+            Some(WriteStrategy::Lazy),
+            LoadOptions::default(),
+        )
+        .unwrap();
         // Dirty the tree so a save would normally rewrite the archive,
         // then poison the lock: destroy must refuse to persist a
         // possibly half-mutated tree over the known-good archive.
@@ -1149,10 +1934,128 @@ mod tests {
         }
         poison_tree_lock(&fs);
         fs.destroy();
+        assert!(fs.shared_unmount_error().lock().unwrap().is_some());
         let after = std::fs::read(&path).unwrap();
         assert_eq!(
             before, after,
             "poisoned destroy must not rewrite the archive"
         );
     }
+
+    /// Page through the root listing of the directory open as `fh`,
+    /// `per_page` entries at a time from `offset`, like repeated readdir.
+    fn list_from(fs: &PnaFS, fh: FileHandle, offset: u64, per_page: usize) -> (Vec<String>, u64) {
+        let tree = fs.read_tree().unwrap();
+        let mut next_offset = offset;
+        let names = fs
+            .dir_page(&tree, INodeNo(ROOT_INODE), fh, offset)
+            .unwrap()
+            .take(per_page)
+            .map(|(next, name, _)| {
+                next_offset = next;
+                name.into_string().unwrap()
+            })
+            .collect();
+        (names, next_offset)
+    }
+
+    #[test]
+    fn readdir_offsets_survive_mutation_mid_listing() {
+        let dir = TempDir::new().unwrap();
+        let files: Vec<(&str, &[u8])> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|&n| (n, &b""[..]))
+            .collect();
+        let path = create_plain_archive(&dir, "a.pna", &files);
+        let fs = PnaFS::new(
+            path,
+            None,
+            Some(WriteStrategy::Lazy),
+            LoadOptions::default(),
+        )
+        .unwrap();
+        let fh = fs.dirs.insert(ROOT_INODE);
+
+        let (first, offset) = list_from(&fs, fh, 0, 2);
+        assert_eq!(first, ["a", "b"]);
+        // `rm -rf` style: delete what was listed, and add an entry that
+        // sorts first. Positional offsets would now skip "c" and "d".
+        {
+            let mut tree = fs.write_tree().unwrap();
+            tree.unlink(ROOT_INODE, OsStr::new("a")).unwrap();
+            tree.unlink(ROOT_INODE, OsStr::new("b")).unwrap();
+            tree.create_file(ROOT_INODE, OsStr::new("0"), 0o644, Owner::new(0, 0))
+                .unwrap();
+        }
+        let (rest, offset) = list_from(&fs, fh, offset, 10);
+        assert_eq!(rest, ["c", "d", "e"]);
+        assert!(list_from(&fs, fh, offset, 10).0.is_empty());
+        // Entries removed after the snapshot are left out, not repeated.
+        fs.write_tree()
+            .unwrap()
+            .unlink(ROOT_INODE, OsStr::new("e"))
+            .unwrap();
+        assert_eq!(list_from(&fs, fh, 3, 10).0, ["d"]);
+
+        // rewinddir sees the directory as it is now.
+        assert_eq!(list_from(&fs, fh, 0, 10).0, ["0", "c", "d"]);
+        fs.dirs.remove(fh);
+        let tree = fs.read_tree().unwrap();
+        assert!(matches!(
+            fs.dir_page(&tree, INodeNo(ROOT_INODE), fh, 1),
+            Err(Errno::EBADF)
+        ));
+    }
+
+    #[test]
+    fn renames_mid_listing_do_not_yield_stale_names() {
+        let dir = TempDir::new().unwrap();
+        let files: Vec<(&str, &[u8])> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|&n| (n, &b""[..]))
+            .collect();
+        let path = create_plain_archive(&dir, "a.pna", &files);
+        let fs = PnaFS::new(
+            path,
+            None,
+            Some(WriteStrategy::Lazy),
+            LoadOptions::default(),
+        )
+        .unwrap();
+        let fh = fs.dirs.insert(ROOT_INODE);
+
+        let (first, offset) = list_from(&fs, fh, 0, 2);
+        assert_eq!(first, ["a", "b"]);
+        // "c" moves to "z" and "d" is replaced by "b": the snapshot still
+        // names "c" and "d", but neither maps to the listed inode any more.
+        {
+            let mut tree = fs.write_tree().unwrap();
+            let flags = fuser::RenameFlags::empty();
+            tree.rename(
+                ROOT_INODE,
+                OsStr::new("c"),
+                ROOT_INODE,
+                OsStr::new("z"),
+                flags,
+            )
+            .unwrap();
+            tree.rename(
+                ROOT_INODE,
+                OsStr::new("b"),
+                ROOT_INODE,
+                OsStr::new("d"),
+                flags,
+            )
+            .unwrap();
+        }
+        let tree = fs.read_tree().unwrap();
+        let rest: Vec<_> = fs
+            .dir_page(&tree, INodeNo(ROOT_INODE), fh, offset)
+            .unwrap()
+            .map(|(_, name, node)| (name, node.attr.ino.0))
+            .collect();
+        assert!(rest.is_empty(), "stale entries listed: {rest:?}");
+        drop(tree);
+        assert_eq!(list_from(&fs, fh, 0, 10).0, ["a", "d", "z"]);
+    }
 }
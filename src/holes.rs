@@ -0,0 +1,168 @@
+//! Hole extents of sparse files.
+//!
+//! File contents are kept as a dense buffer, so a hole reads back as zeros
+//! like any other zero run. What makes it a hole is being listed here:
+//! ranges punched with `FALLOC_FL_PUNCH_HOLE`, or skipped over by a write
+//! or truncate that extends the file. `lseek(SEEK_DATA / SEEK_HOLE)` answers
+//! from this map, so `cp --sparse` and `tar -S` can skip the holes. Zeros
+//! written explicitly stay data, as on a real filesystem.
+
+use std::collections::BTreeMap;
+
+/// Disjoint, non-adjacent `[start, end)` byte ranges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Holes {
+    /// `start -> end`.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl Holes {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The holes as `(start, end)` pairs, in order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(&s, &e)| (s, e))
+    }
+
+    /// Make `[start, end)` a hole, merging with holes it touches.
+    pub(crate) fn punch(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        let touching: Vec<u64> = self
+            .ranges
+            .range(..=end)
+            .rev()
+            .take_while(|&(_, &e)| e >= start)
+            .map(|(&s, _)| s)
+            .collect();
+        for s in touching {
+            let e = self.ranges.remove(&s).unwrap_or(s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// Make `[start, end)` data, splitting holes that straddle it.
+    pub(crate) fn fill(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let overlapping: Vec<(u64, u64)> = self
+            .ranges
+            .range(..end)
+            .rev()
+            .take_while(|&(_, &e)| e > start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in overlapping {
+            self.ranges.remove(&s);
+            if s < start {
+                self.ranges.insert(s, start);
+            }
+            if e > end {
+                self.ranges.insert(end, e);
+            }
+        }
+    }
+
+    /// Forget everything at or past `len`, after the file shrank.
+    pub(crate) fn truncate(&mut self, len: u64) {
+        self.fill(len, u64::MAX);
+    }
+
+    /// `SEEK_DATA`: the first data offset at or after `offset` in a file of
+    /// `size` bytes, or `None` (`ENXIO`) if only holes follow.
+    pub(crate) fn seek_data(&self, offset: u64, size: u64) -> Option<u64> {
+        if offset >= size {
+            return None;
+        }
+        let data = match self.ranges.range(..=offset).next_back() {
+            Some((_, &e)) if e > offset => e,
+            _ => offset,
+        };
+        (data < size).then_some(data)
+    }
+
+    /// `SEEK_HOLE`: the first hole offset at or after `offset` in a file of
+    /// `size` bytes. End of file counts as a hole, so this is `None`
+    /// (`ENXIO`) only when `offset` is already past it.
+    pub(crate) fn seek_hole(&self, offset: u64, size: u64) -> Option<u64> {
+        if offset >= size {
+            return None;
+        }
+        if let Some((_, &e)) = self.ranges.range(..=offset).next_back()
+            && e > offset
+        {
+            return Some(offset);
+        }
+        let next = self.ranges.range(offset..).next().map_or(size, |(&s, _)| s);
+        Some(next.min(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holes(ranges: &[(u64, u64)]) -> Holes {
+        let mut h = Holes::default();
+        for &(s, e) in ranges {
+            h.punch(s, e);
+        }
+        h
+    }
+
+    fn list(h: &Holes) -> Vec<(u64, u64)> {
+        h.ranges.iter().map(|(&s, &e)| (s, e)).collect()
+    }
+
+    #[test]
+    fn punch_merges_overlapping_and_adjacent_ranges() {
+        let h = holes(&[(10, 20), (30, 40), (20, 25), (35, 50)]);
+        assert_eq!(list(&h), [(10, 25), (30, 50)]);
+        let h = holes(&[(10, 20), (30, 40), (5, 45)]);
+        assert_eq!(list(&h), [(5, 45)]);
+        assert_eq!(holes(&[(7, 7)]), Holes::default());
+    }
+
+    #[test]
+    fn fill_splits_and_trims() {
+        let mut h = holes(&[(0, 100)]);
+        h.fill(40, 60);
+        assert_eq!(list(&h), [(0, 40), (60, 100)]);
+        h.fill(30, 70);
+        assert_eq!(list(&h), [(0, 30), (70, 100)]);
+        h.fill(0, 200);
+        assert_eq!(h, Holes::default());
+    }
+
+    #[test]
+    fn truncate_drops_holes_past_the_new_end() {
+        let mut h = holes(&[(0, 10), (20, 30), (40, 50)]);
+        h.truncate(25);
+        assert_eq!(list(&h), [(0, 10), (20, 25)]);
+    }
+
+    #[test]
+    fn seek_data_and_hole_walk_the_extents() {
+        // data [0,10) hole [10,20) data [20,30) hole [30,40), size 40
+        let h = holes(&[(10, 20), (30, 40)]);
+        assert_eq!(h.seek_data(0, 40), Some(0));
+        assert_eq!(h.seek_data(12, 40), Some(20));
+        assert_eq!(h.seek_data(30, 40), None);
+        assert_eq!(h.seek_data(40, 40), None);
+        assert_eq!(h.seek_hole(0, 40), Some(10));
+        assert_eq!(h.seek_hole(15, 40), Some(15));
+        assert_eq!(h.seek_hole(25, 40), Some(30));
+        assert_eq!(h.seek_hole(40, 40), None);
+        // Without holes the only hole is the implicit one at EOF.
+        let none = Holes::default();
+        assert_eq!(none.seek_data(3, 8), Some(3));
+        assert_eq!(none.seek_hole(3, 8), Some(8));
+    }
+}
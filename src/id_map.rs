@@ -0,0 +1,235 @@
+//! Owner mapping between the archive and the mount (`--uid`, `--gid`,
+//! `--map-uid`, `--map-gid`, `--squash-owner`, `--owner-resolution`).
+//!
+//! By default, archive owners are resolved by name and fall back to the
+//! archived numeric ids (see [`get_uid`]), which turns archives built
+//! elsewhere into files owned by whichever local users happen to have
+//! those ids. An [`IdMap`] overrides that at load. Every loaded node
+//! remembers its [`ArchivedOwner`], and save writes that back for as long
+//! as the node still shows the ids it was loaded with, so mapping alone
+//! never changes the owners stored in the archive. [`OwnerResolution`]
+//! picks what identifies an owner when no mapping applies.
+
+use crate::file_tree::{get_gid, get_uid, local_id};
+#[allow(deprecated)]
+use pna::Permission;
+use std::str::FromStr;
+
+/// One `--map-uid` / `--map-gid` rule: archive id `from` is shown as `to`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct IdPair {
+    pub from: u32,
+    pub to: u32,
+}
+
+impl FromStr for IdPair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once(':')
+            .ok_or_else(|| format!("{s} is not of the form FROM:TO"))?;
+        let id = |part: &str| {
+            part.parse()
+                .map_err(|e| format!("invalid id {part:?} in {s}: {e}"))
+        };
+        Ok(Self {
+            from: id(from)?,
+            to: id(to)?,
+        })
+    }
+}
+
+/// What identifies an archive owner (`--owner-resolution`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum OwnerResolution {
+    /// The user and group names: load maps them to local ids, and save
+    /// stores the local ids and names.
+    Name,
+    /// The numeric ids: load ignores names, and save stores the ids with
+    /// whatever names they have locally.
+    Id,
+    /// Names like `name` at load, but save stores the archived ids and
+    /// names unchanged unless the owner was changed.
+    #[default]
+    Archived,
+}
+
+/// How archive owners are presented in the mount.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct IdMap {
+    pub resolution: OwnerResolution,
+    /// Show every entry as owned by this uid.
+    pub uid: Option<u32>,
+    /// Show every entry as owned by this gid.
+    pub gid: Option<u32>,
+    pub map_uid: Vec<IdPair>,
+    pub map_gid: Vec<IdPair>,
+}
+
+impl IdMap {
+    /// The uid an entry with `permission` is shown as: the forced uid,
+    /// else a matching `--map-uid` rule, else the [`OwnerResolution`].
+    #[allow(deprecated)]
+    pub(crate) fn uid(&self, permission: Option<&Permission>) -> u32 {
+        match (self.mapped_uid(permission.map(Permission::uid)), permission) {
+            (Some(uid), _) => uid,
+            (None, Some(p)) if self.resolution == OwnerResolution::Id => local_id(p.uid()),
+            (None, _) => get_uid(permission),
+        }
+    }
+
+    /// The gid counterpart of [`Self::uid`].
+    #[allow(deprecated)]
+    pub(crate) fn gid(&self, permission: Option<&Permission>) -> u32 {
+        match (self.mapped_gid(permission.map(Permission::gid)), permission) {
+            (Some(gid), _) => gid,
+            (None, Some(p)) if self.resolution == OwnerResolution::Id => local_id(p.gid()),
+            (None, _) => get_gid(permission),
+        }
+    }
+
+    /// The uid that `--uid` or a `--map-uid` rule shows the archive uid
+    /// `archived` as, if any.
+    pub(crate) fn mapped_uid(&self, archived: Option<u64>) -> Option<u32> {
+        self.uid
+            .or_else(|| archived.and_then(|id| lookup(&self.map_uid, id)))
+    }
+
+    /// The gid counterpart of [`Self::mapped_uid`].
+    pub(crate) fn mapped_gid(&self, archived: Option<u64>) -> Option<u32> {
+        self.gid
+            .or_else(|| archived.and_then(|id| lookup(&self.map_gid, id)))
+    }
+
+    /// The archive uid that a local `uid` maps back to, if a `--map-uid`
+    /// rule produced it.
+    pub(crate) fn reverse_uid(&self, uid: u32) -> Option<u32> {
+        reverse(&self.map_uid, uid)
+    }
+
+    /// The archive gid that a local `gid` maps back to, if a `--map-gid`
+    /// rule produced it.
+    pub(crate) fn reverse_gid(&self, gid: u32) -> Option<u32> {
+        reverse(&self.map_gid, gid)
+    }
+}
+
+fn lookup(rules: &[IdPair], archived: u64) -> Option<u32> {
+    rules
+        .iter()
+        .find(|r| u64::from(r.from) == archived)
+        .map(|r| r.to)
+}
+
+fn reverse(rules: &[IdPair], local: u32) -> Option<u32> {
+    rules.iter().find(|r| r.to == local).map(|r| r.from)
+}
+
+/// The owner an entry carried in the archive, next to the ids it was
+/// shown as after mapping.
+#[derive(Clone, Debug)]
+pub(crate) struct ArchivedOwner {
+    pub uid: u64,
+    pub uname: String,
+    pub gid: u64,
+    pub gname: String,
+    pub local_uid: u32,
+    pub local_gid: u32,
+}
+
+impl ArchivedOwner {
+    #[allow(deprecated)]
+    pub(crate) fn new(permission: &Permission, local_uid: u32, local_gid: u32) -> Self {
+        Self {
+            uid: permission.uid(),
+            uname: permission.uname().to_owned(),
+            gid: permission.gid(),
+            gname: permission.gname().to_owned(),
+            local_uid,
+            local_gid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_pairs_parse_from_colon_separated_ids() {
+        assert_eq!(
+            "1500:1000".parse::<IdPair>(),
+            Ok(IdPair {
+                from: 1500,
+                to: 1000
+            })
+        );
+        assert!("1500".parse::<IdPair>().is_err());
+        assert!("1500:bob".parse::<IdPair>().is_err());
+        assert!(":1000".parse::<IdPair>().is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn forced_ids_win_over_rules_and_rules_reverse() {
+        let p = Permission::new(1500, "no-such-user-pnafs".into(), 1600, "".into(), 0o644);
+        let rules = IdMap {
+            map_uid: vec![IdPair {
+                from: 1500,
+                to: 1000,
+            }],
+            map_gid: vec![IdPair {
+                from: 1600,
+                to: 100,
+            }],
+            ..IdMap::default()
+        };
+        assert_eq!((rules.uid(Some(&p)), rules.gid(Some(&p))), (1000, 100));
+        assert_eq!(rules.reverse_uid(1000), Some(1500));
+        assert_eq!(rules.reverse_uid(1500), None);
+        assert_eq!(rules.reverse_gid(100), Some(1600));
+
+        let forced = IdMap {
+            uid: Some(42),
+            gid: Some(43),
+            ..rules
+        };
+        assert_eq!((forced.uid(Some(&p)), forced.gid(Some(&p))), (42, 43));
+        assert_eq!((forced.uid(None), forced.gid(None)), (42, 43));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn id_resolution_ignores_names() {
+        // User "root" is uid 0 on every unix host, so a name lookup
+        // overrides the archived id.
+        let p = Permission::new(4321, "root".into(), 4322, "root".into(), 0o644);
+        assert_eq!(IdMap::default().uid(Some(&p)), 0);
+        let by_id = IdMap {
+            resolution: OwnerResolution::Id,
+            ..IdMap::default()
+        };
+        assert_eq!((by_id.uid(Some(&p)), by_id.gid(Some(&p))), (4321, 4322));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn ids_wider_than_32_bits_show_as_the_overflow_id() {
+        // Truncating 1 << 32 would make the entry root-owned.
+        let wide = 1u64 << 32;
+        let p = Permission::new(wide, "".into(), wide + 5, "".into(), 0o644);
+        let by_id = IdMap {
+            resolution: OwnerResolution::Id,
+            ..IdMap::default()
+        };
+        assert_eq!((by_id.uid(Some(&p)), by_id.gid(Some(&p))), (65534, 65534));
+        assert_eq!(
+            (
+                IdMap::default().uid(Some(&p)),
+                IdMap::default().gid(Some(&p))
+            ),
+            (65534, 65534)
+        );
+    }
+}
@@ -4,18 +4,29 @@ use crate::command::Command;
 use clap::Parser;
 use std::io;
 
+mod archive_dir;
 mod archive_io;
 mod archive_lock;
 mod cli;
 mod command;
+mod control;
+mod daemon;
+mod file_handles;
+mod file_locks;
 mod file_tree;
 mod filesystem;
+mod holes;
+mod id_map;
+mod mount_helper;
+mod path_filter;
+mod permissions;
+mod watch;
 
 #[cfg(test)]
 mod roundtrip_proptest;
 
 fn main() -> io::Result<()> {
-    let args = cli::Cli::parse();
+    let args = cli::Cli::parse_from(mount_helper::expand_args(std::env::args_os()));
     #[cfg(feature = "logging")]
     simple_logger::init_with_level(args.verbose.log_level().unwrap_or(log::Level::Trace))
         .map_err(io::Error::other)?;
@@ -0,0 +1,242 @@
+//! `mount(8)` calling conventions.
+//!
+//! `mount -t pnafs archive.pna /mnt -o ro,allow_other` (and `/etc/fstab`,
+//! and autofs) runs `/sbin/mount.pnafs archive.pna /mnt -o ro,allow_other`,
+//! with the options as one comma-separated list of `name` / `name=value`
+//! words. [`expand_args`] rewrites such a command line, and `-o` lists
+//! given to `pnafs mount` itself, into the long flags clap understands:
+//! `allow_other` becomes `--allow-other`, `uid=1000` becomes `--uid=1000`.
+
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+/// Program name that selects the `mount(8)` helper calling convention.
+const HELPER_NAME: &str = "mount.pnafs";
+
+/// Options `mount(8)` consumes itself or that name pnafs defaults; they
+/// are accepted and dropped.
+const IGNORED: &[&str] = &[
+    "ro", "defaults", "auto", "noauto", "user", "users", "nouser", "owner", "group", "nofail",
+    "_netdev", "suid", "dev", "exec", "atime", "relatime", "async",
+];
+
+/// Rewrite the process arguments into a `pnafs` command line.
+///
+/// When invoked as `mount.pnafs`, the arguments are those of a mount
+/// helper, `ARCHIVE MOUNTPOINT [-sfnv] [-o OPTIONS] [-t TYPE]`, and become
+/// `pnafs mount ...`. The flags `mount(8)` passes for its own bookkeeping
+/// are dropped. After a `mount` subcommand, every `-o OPTIONS` (or
+/// `-oOPTIONS`) up to a `--` is replaced by the long flags its options
+/// name. Anything else passes through untouched, so clap still reports
+/// bad input.
+pub(crate) fn expand_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = args.into_iter();
+    let Some(program) = args.next() else {
+        return Vec::new();
+    };
+    let mut out = Vec::new();
+    let mut rest: Vec<OsString> = args.collect();
+    if Path::new(&program).file_name() == Some(OsStr::new(HELPER_NAME)) {
+        out.extend([OsString::from(env!("CARGO_PKG_NAME")), "mount".into()]);
+        rest = strip_helper_flags(rest);
+    } else {
+        out.push(program);
+        // Every global flag (`-v`, `-qq`, `--help`, ...) takes no value,
+        // so the subcommand is the first argument that is not a flag.
+        let subcommand = rest
+            .iter()
+            .position(|arg| arg == "--" || !arg.to_string_lossy().starts_with('-'));
+        match subcommand {
+            Some(i) if rest[i] == "mount" => out.extend(rest.drain(..=i)),
+            _ => {
+                out.extend(rest);
+                return out;
+            }
+        }
+    }
+
+    let mut rest = rest.into_iter();
+    while let Some(arg) = rest.next() {
+        let options = if arg == "--" {
+            out.push(arg);
+            out.extend(rest);
+            break;
+        } else if arg == "-o" {
+            match rest.next() {
+                Some(options) => options,
+                None => {
+                    out.push(arg);
+                    break;
+                }
+            }
+        } else if let Some(options) = arg.to_str().and_then(|a| a.strip_prefix("-o")) {
+            options.into()
+        } else {
+            out.push(arg);
+            continue;
+        };
+        out.extend(expand_options(&options.to_string_lossy()));
+    }
+    out
+}
+
+/// Drop the `-s`, `-f`, `-n`, `-v`, `-t TYPE` and `-N NAMESPACE` flags
+/// that `mount(8)` passes to helpers, up to a `--`.
+fn strip_helper_flags(args: Vec<OsString>) -> Vec<OsString> {
+    let mut out = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-s" | "-f" | "-n" | "-v") => {}
+            Some("-t" | "-N") => {
+                args.next();
+            }
+            Some("--") => {
+                out.push(arg);
+                out.extend(args);
+                break;
+            }
+            _ => out.push(arg),
+        }
+    }
+    out
+}
+
+/// The long flags for one comma-separated option list.
+fn expand_options(options: &str) -> Vec<OsString> {
+    options
+        .split(',')
+        .filter(|option| {
+            !option.is_empty()
+                && !IGNORED.contains(option)
+                && !option.starts_with("x-")
+                && !option.starts_with("comment=")
+        })
+        .map(|option| {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (option, None),
+            };
+            let flag = match name {
+                "rw" => "write".to_owned(),
+                _ => name.replace('_', "-"),
+            };
+            match value {
+                Some(value) => format!("--{flag}={value}").into(),
+                None => format!("--{flag}").into(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(args: &[&str]) -> Vec<String> {
+        expand_args(args.iter().map(OsString::from))
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn options_become_long_flags_after_mount() {
+        assert_eq!(
+            expand(&[
+                "pnafs",
+                "-v",
+                "mount",
+                "-o",
+                "rw,allow_other,uid=1000,x-systemd.automount",
+                "a.pna",
+                "-okernel_cache",
+                "mnt",
+            ]),
+            [
+                "pnafs",
+                "-v",
+                "mount",
+                "--write",
+                "--allow-other",
+                "--uid=1000",
+                "a.pna",
+                "--kernel-cache",
+                "mnt",
+            ]
+        );
+        // Other subcommands, and a dangling `-o`, are left alone.
+        assert_eq!(
+            expand(&["pnafs", "complete", "-o"]),
+            ["pnafs", "complete", "-o"]
+        );
+        assert_eq!(expand(&["pnafs", "mount", "-o"]), ["pnafs", "mount", "-o"]);
+    }
+
+    #[test]
+    fn subcommand_is_found_past_global_flags_only() {
+        // "mount" here is the archive path of `mount-dir`, not a subcommand.
+        assert_eq!(
+            expand(&["pnafs", "-qq", "mount-dir", "mount", "-o", "ro"]),
+            ["pnafs", "-qq", "mount-dir", "mount", "-o", "ro"]
+        );
+        assert_eq!(
+            expand(&["pnafs", "complete", "mount", "-oro"]),
+            ["pnafs", "complete", "mount", "-oro"]
+        );
+        assert_eq!(
+            expand(&["pnafs", "-vv", "--quiet", "mount", "-o", "uid=1"]),
+            ["pnafs", "-vv", "--quiet", "mount", "--uid=1"]
+        );
+    }
+
+    #[test]
+    fn arguments_after_double_dash_are_left_alone() {
+        assert_eq!(
+            expand(&["pnafs", "mount", "-o", "ro", "--", "-o", "-oallow_other"]),
+            ["pnafs", "mount", "--", "-o", "-oallow_other"]
+        );
+        assert_eq!(
+            expand(&[
+                "/sbin/mount.pnafs",
+                "a.pna",
+                "/mnt",
+                "--",
+                "-v",
+                "-okernel_cache"
+            ]),
+            [
+                "pnafs",
+                "mount",
+                "a.pna",
+                "/mnt",
+                "--",
+                "-v",
+                "-okernel_cache"
+            ]
+        );
+    }
+
+    #[test]
+    fn helper_name_selects_the_mount_8_convention() {
+        assert_eq!(
+            expand(&[
+                "/sbin/mount.pnafs",
+                "/srv/a.pna",
+                "/mnt",
+                "-n",
+                "-o",
+                "ro,defaults,password_file=/etc/pnafs.pw",
+                "-t",
+                "pnafs",
+            ]),
+            [
+                "pnafs",
+                "mount",
+                "/srv/a.pna",
+                "/mnt",
+                "--password-file=/etc/pnafs.pw",
+            ]
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Which archive entries a mount shows (`--include`, `--exclude`).
+//!
+//! Entries are filtered by their archive path as the archive is loaded,
+//! before their data is decoded. Hidden entries are kept as they were
+//! read and written back on save, so filtering never changes what the
+//! archive holds.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A shell-style pattern: `*` and `?` match within one path component,
+/// `**` across components, `[abc]`, `[a-z]` and `[!abc]` match one
+/// character, and `\` quotes the next one. A pattern containing `/` is
+/// matched against the whole archive path, any other against the last
+/// component. Either way, a path also matches when one of its parent
+/// directories does, so naming a directory covers its contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Glob {
+    pattern: Vec<u8>,
+    whole_path: bool,
+}
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim_start_matches('/').trim_end_matches('/');
+        if pattern.is_empty() {
+            return Err(format!("{s:?} matches no path"));
+        }
+        Ok(Self {
+            pattern: pattern.as_bytes().to_vec(),
+            whole_path: pattern.contains('/'),
+        })
+    }
+}
+
+impl Glob {
+    /// Whether `path`, or a directory above it, matches.
+    fn matches(&self, path: &Path) -> bool {
+        let path = path.as_os_str().as_encoded_bytes();
+        let path = path.strip_prefix(b"/").unwrap_or(path);
+        let mut end = 0;
+        while end < path.len() {
+            end = path[end..]
+                .iter()
+                .position(|&b| b == b'/')
+                .map_or(path.len(), |slash| end + slash);
+            let prefix = &path[..end];
+            let subject = if self.whole_path {
+                prefix
+            } else {
+                prefix.rsplit(|&b| b == b'/').next().unwrap_or(prefix)
+            };
+            if glob_match(&self.pattern, subject) {
+                return true;
+            }
+            end += 1;
+        }
+        false
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    Matcher {
+        pattern,
+        text,
+        failed: HashSet::new(),
+    }
+    .matches(0, 0)
+}
+
+/// A match in progress. Each star tries every place its match could end,
+/// so a pattern with many stars would retry the same tails exponentially
+/// often; remembering the positions that failed bounds the work by the
+/// pattern length times the text length.
+struct Matcher<'a> {
+    pattern: &'a [u8],
+    text: &'a [u8],
+    failed: HashSet<(usize, usize)>,
+}
+
+impl Matcher<'_> {
+    /// Whether `pattern[p..]` matches `text[t..]`.
+    fn matches(&mut self, mut p: usize, mut t: usize) -> bool {
+        let (start, pattern, text) = ((p, t), self.pattern, self.text);
+        if self.failed.contains(&start) {
+            return false;
+        }
+        let matched = loop {
+            let rest = &text[t..];
+            match &pattern[p..] {
+                [] => break rest.is_empty(),
+                [b'*', b'*', ..] => break (t..=text.len()).any(|i| self.matches(p + 2, i)),
+                [b'*', ..] => {
+                    let component = rest.iter().position(|&b| b == b'/').unwrap_or(rest.len());
+                    break (t..=t + component).any(|i| self.matches(p + 1, i));
+                }
+                [b'?', ..] => match rest {
+                    [c, ..] if *c != b'/' => p += 1,
+                    _ => break false,
+                },
+                [b'[', class @ ..] => match (class_match(class, rest.first()), rest) {
+                    (Some((true, after)), [_, ..]) => p = pattern.len() - after.len(),
+                    (Some(_), _) => break false,
+                    // No closing `]`: a literal `[`.
+                    (None, [b'[', ..]) => p += 1,
+                    (None, _) => break false,
+                },
+                [b'\\', c, ..] => match rest {
+                    [r, ..] if r == c => p += 2,
+                    _ => break false,
+                },
+                [c, ..] => match rest {
+                    [r, ..] if r == c => p += 1,
+                    _ => break false,
+                },
+            }
+            t += 1;
+        };
+        if !matched {
+            self.failed.insert(start);
+        }
+        matched
+    }
+}
+
+/// Match `c` against the bracket expression starting right after `[`.
+/// Returns whether it matched and the pattern after the closing `]`, or
+/// `None` if there is no closing `]`.
+fn class_match<'a>(class: &'a [u8], c: Option<&u8>) -> Option<(bool, &'a [u8])> {
+    let (negated, mut class) = match class {
+        [b'!' | b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match class {
+            [] => return None,
+            [b']', rest @ ..] if !first => {
+                let hit = c.is_some_and(|&c| c != b'/' && matched != negated);
+                return Some((hit, rest));
+            }
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                matched |= c.is_some_and(|c| (lo..=hi).contains(&c));
+                class = rest;
+            }
+            [b, rest @ ..] => {
+                matched |= c == Some(b);
+                class = rest;
+            }
+        }
+        first = false;
+    }
+}
+
+/// The `--include` / `--exclude` patterns of a mount.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PathFilter {
+    /// If not empty, only entries matching one of these are shown.
+    /// Directories are exempt, so the layout around them stays intact.
+    pub include: Vec<Glob>,
+    /// Entries matching one of these are hidden, whatever `include` says.
+    pub exclude: Vec<Glob>,
+}
+
+impl PathFilter {
+    /// Whether the entry at archive path `path` is shown.
+    pub(crate) fn shows(&self, path: &Path, is_dir: bool) -> bool {
+        if self.exclude.iter().any(|g| g.matches(path)) {
+            return false;
+        }
+        is_dir || self.include.is_empty() || self.include.iter().any(|g| g.matches(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(s: &str) -> Glob {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn globs_match_names_or_whole_paths() {
+        assert!(glob("*.h").matches(Path::new("include/zlib.h")));
+        assert!(!glob("*.h").matches(Path::new("src/zlib.c")));
+        assert!(glob("src/*.c").matches(Path::new("src/a.c")));
+        assert!(!glob("src/*.c").matches(Path::new("src/sub/a.c")));
+        assert!(glob("src/**.c").matches(Path::new("src/sub/a.c")));
+        assert!(glob("a?[0-9][!x].txt").matches(Path::new("ab1y.txt")));
+        assert!(!glob("a?[0-9][!x].txt").matches(Path::new("ab1x.txt")));
+        assert!(glob(r"\*").matches(Path::new("*")));
+        assert!(!glob(r"\*").matches(Path::new("a")));
+        assert!(glob("[").matches(Path::new("[")));
+        assert!("/".parse::<Glob>().is_err());
+    }
+
+    #[test]
+    fn directories_cover_their_contents() {
+        assert!(glob("vendor/zlib").matches(Path::new("vendor/zlib/inflate.c")));
+        assert!(!glob("vendor/zlib").matches(Path::new("vendor/zlibx/inflate.c")));
+        assert!(glob("build").matches(Path::new("a/build/out.o")));
+    }
+
+    #[test]
+    fn many_stars_do_not_backtrack_exponentially() {
+        let text = "a".repeat(64);
+        assert!(!glob(&format!("{}b", "*a".repeat(32))).matches(Path::new(&text)));
+        assert!(!glob(&format!("{}/b", "**a".repeat(32))).matches(Path::new(&text)));
+        assert!(glob(&"*a".repeat(32)).matches(Path::new(&text)));
+    }
+
+    #[test]
+    fn exclude_wins_and_include_spares_directories() {
+        let filter = PathFilter {
+            include: vec![glob("*.h")],
+            exclude: vec![glob("internal")],
+        };
+        assert!(filter.shows(Path::new("inc/a.h"), false));
+        assert!(!filter.shows(Path::new("inc/a.c"), false));
+        assert!(filter.shows(Path::new("inc"), true));
+        assert!(!filter.shows(Path::new("inc/internal/b.h"), false));
+        assert!(!filter.shows(Path::new("inc/internal"), true));
+        assert!(PathFilter::default().shows(Path::new("any"), false));
+    }
+}
@@ -0,0 +1,210 @@
+//! Permission enforcement (`--permissions`).
+//!
+//! By default the mount carries `default_permissions` and the kernel
+//! checks every access against the mode, uid and gid pnafs reports. That
+//! is wrong for archives made on other machines, whose owners mean
+//! nothing locally, so the checks can instead be switched off or done by
+//! pnafs itself. Without `default_permissions` the kernel forwards
+//! `access(2)` as a FUSE request and otherwise leaves each operation to
+//! the filesystem, which is where [`check`] and [`check_sticky`] come in.
+
+use fuser::{AccessFlags, Errno, FileAttr, FileType, OpenAccMode, OpenFlags};
+use std::fs;
+
+/// Who enforces file permissions.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum PermissionMode {
+    /// The kernel, against the reported attributes (`default_permissions`).
+    #[default]
+    Kernel,
+    /// Nobody: every user the mount admits may read and change anything.
+    Ignore,
+    /// pnafs, against the uids and gids it reports.
+    Pnafs,
+}
+
+/// The process behind a request.
+#[derive(Clone, Debug)]
+pub(crate) struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl Caller {
+    /// Whether the caller is in group `gid`, counting supplementary
+    /// groups. FUSE only forwards the primary gid, so the rest is read
+    /// from `/proc/<pid>/status`; a caller that has already exited is
+    /// treated as having none.
+    fn in_group(&self, gid: u32) -> bool {
+        gid == self.gid
+            || fs::read_to_string(format!("/proc/{}/status", self.pid))
+                .is_ok_and(|status| supplementary_groups(&status).contains(&gid))
+    }
+}
+
+/// The `Groups:` line of a `/proc/<pid>/status` file.
+fn supplementary_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|g| g.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The access an `open(2)` with `flags` needs.
+pub(crate) fn open_mask(flags: OpenFlags) -> AccessFlags {
+    let mask = match flags.acc_mode() {
+        OpenAccMode::O_RDONLY => AccessFlags::R_OK,
+        OpenAccMode::O_WRONLY => AccessFlags::W_OK,
+        OpenAccMode::O_RDWR => AccessFlags::R_OK | AccessFlags::W_OK,
+    };
+    if flags.0 & libc::O_TRUNC != 0 {
+        mask | AccessFlags::W_OK
+    } else {
+        mask
+    }
+}
+
+/// `EACCES` unless `caller` may access `attr` for every bit of `mask`,
+/// following the owner / group / other classes of the mode. Root passes
+/// read and write checks unconditionally, and execute checks when the node
+/// is a directory or has any execute bit.
+pub(crate) fn check(attr: &FileAttr, caller: &Caller, mask: AccessFlags) -> Result<(), Errno> {
+    let mask = mask.bits() as u16 & 0o7;
+    if mask == 0 {
+        return Ok(());
+    }
+    let granted = if caller.uid == 0 {
+        let exec = attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+        0o6 | if exec { 0o1 } else { 0 }
+    } else if caller.uid == attr.uid {
+        attr.perm >> 6
+    } else if caller.in_group(attr.gid) {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    } & 0o7;
+    if granted & mask == mask {
+        Ok(())
+    } else {
+        Err(Errno::EACCES)
+    }
+}
+
+/// `EPERM` if `dir` is sticky and `caller` owns neither it nor `victim`,
+/// the entry being removed or replaced.
+pub(crate) fn check_sticky(
+    dir: &FileAttr,
+    victim: &FileAttr,
+    caller: &Caller,
+) -> Result<(), Errno> {
+    if dir.perm & libc::S_ISVTX as u16 == 0
+        || caller.uid == 0
+        || caller.uid == dir.uid
+        || caller.uid == victim.uid
+    {
+        Ok(())
+    } else {
+        Err(Errno::EPERM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuser::INodeNo;
+    use std::time::UNIX_EPOCH;
+
+    fn attr(kind: FileType, perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            ino: INodeNo(2),
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+
+    /// A caller whose pid can't have a `/proc` entry, so only its
+    /// primary group counts.
+    fn caller(uid: u32, gid: u32) -> Caller {
+        Caller {
+            uid,
+            gid,
+            pid: u32::MAX,
+        }
+    }
+
+    const R: AccessFlags = AccessFlags::R_OK;
+    const W: AccessFlags = AccessFlags::W_OK;
+    const X: AccessFlags = AccessFlags::X_OK;
+
+    #[test]
+    fn mode_classes_are_exclusive() {
+        let file = attr(FileType::RegularFile, 0o604, 1000, 100);
+        assert_eq!(check(&file, &caller(1000, 1), R | W), Ok(()));
+        // The group class has no bits, and a group member doesn't fall
+        // through to "other".
+        assert_eq!(check(&file, &caller(2000, 100), R), Err(Errno::EACCES));
+        assert_eq!(check(&file, &caller(2000, 200), R), Ok(()));
+        assert_eq!(check(&file, &caller(2000, 200), W), Err(Errno::EACCES));
+        assert_eq!(check(&file, &caller(2000, 200), AccessFlags::F_OK), Ok(()));
+    }
+
+    #[test]
+    fn root_bypasses_all_but_missing_exec_bits() {
+        let file = attr(FileType::RegularFile, 0o000, 1000, 100);
+        assert_eq!(check(&file, &caller(0, 0), R | W), Ok(()));
+        assert_eq!(check(&file, &caller(0, 0), X), Err(Errno::EACCES));
+        let dir = attr(FileType::Directory, 0o000, 1000, 100);
+        assert_eq!(check(&dir, &caller(0, 0), X), Ok(()));
+        let script = attr(FileType::RegularFile, 0o100, 1000, 100);
+        assert_eq!(check(&script, &caller(0, 0), X), Ok(()));
+    }
+
+    #[test]
+    fn sticky_dirs_protect_other_users_entries() {
+        let tmp = attr(FileType::Directory, 0o1777, 0, 0);
+        let theirs = attr(FileType::RegularFile, 0o666, 1000, 100);
+        assert_eq!(check_sticky(&tmp, &theirs, &caller(1000, 1)), Ok(()));
+        assert_eq!(
+            check_sticky(&tmp, &theirs, &caller(2000, 1)),
+            Err(Errno::EPERM)
+        );
+        assert_eq!(check_sticky(&tmp, &theirs, &caller(0, 0)), Ok(()));
+        let plain = attr(FileType::Directory, 0o777, 0, 0);
+        assert_eq!(check_sticky(&plain, &theirs, &caller(2000, 1)), Ok(()));
+    }
+
+    #[test]
+    fn open_mask_follows_access_mode_and_truncation() {
+        assert_eq!(open_mask(OpenFlags(libc::O_RDONLY)), R);
+        assert_eq!(open_mask(OpenFlags(libc::O_WRONLY | libc::O_APPEND)), W);
+        assert_eq!(open_mask(OpenFlags(libc::O_RDWR)), R | W);
+        assert_eq!(open_mask(OpenFlags(libc::O_RDONLY | libc::O_TRUNC)), R | W);
+    }
+
+    #[test]
+    fn supplementary_groups_come_from_the_status_groups_line() {
+        let status = "Name:\tcat\nGid:\t100\t100\t100\t100\nGroups:\t4 24 27 \nNgid:\t0\n";
+        assert_eq!(supplementary_groups(status), [4, 24, 27]);
+        assert!(supplementary_groups("Groups:\n").is_empty());
+        assert!(supplementary_groups("Name:\tcat\n").is_empty());
+    }
+}
@@ -1278,9 +1278,9 @@ fn build_and_save(archive_path: &Path, input: &TestInput) -> io::Result<Vec<(Str
 fn snapshot(tree: &FileTree) -> BTreeMap<String, ObservedNode> {
     let mut out = BTreeMap::new();
     let root = tree.get(ROOT_INODE).expect("tree has a root");
-    out.insert(String::new(), observed(root));
+    out.insert(String::new(), observed(&root));
     for (_ino, node, path) in tree.collect_dfs() {
-        out.insert(path, observed(node));
+        out.insert(path, observed(&node));
     }
     out
 }
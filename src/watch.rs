@@ -0,0 +1,262 @@
+//! Live reload of read-only mounts (`pnafs mount --watch`).
+//!
+//! A background thread watches the archive's *directory* with inotify
+//! rather than the archive file itself: the usual way to publish a new
+//! archive is to write it elsewhere and `rename(2)` it into place, which
+//! replaces the inode an inode-level watch would be attached to. Any
+//! `IN_CLOSE_WRITE` (in-place rewrite) or `IN_MOVED_TO` (rename-over) for
+//! the archive's file name schedules a reload.
+//!
+//! A reload decodes the new archive into a fresh [`FileTree`] *outside*
+//! the tree lock, so the mount keeps serving the old content while the
+//! new one is parsed, then swaps it in under the write lock. Inode
+//! numbers are carried over per path ([`FileTree::adopt_inodes`]) and the
+//! kernel is told to drop exactly the dentries and inodes that changed.
+//! Notifications are sent after the lock is released: the kernel may call
+//! back into the filesystem while processing them.
+//!
+//! An archive that cannot be decoded (e.g. a writer that has not finished
+//! yet) is logged and skipped; the mount keeps the last good tree and the
+//! next event retries.
+
+use crate::archive_io;
+use crate::file_tree::{FileTree, ReloadChanges};
+use fuser::{INodeNo, Notifier};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// How long to let a burst of events settle before reloading, so a writer
+/// that closes the archive several times in a row costs one reload.
+const SETTLE: Duration = Duration::from_millis(250);
+
+/// Start watching `archive` and reloading `tree` whenever it is replaced.
+///
+/// The inotify watch is set up before returning so that setup errors
+/// (e.g. an exhausted `max_user_watches`) fail the mount instead of
+/// silently disabling the feature. The thread itself lives as long as
+/// the process.
+pub(crate) fn spawn(
+    archive: &Path,
+    tree: Arc<RwLock<FileTree>>,
+    notifier: Notifier,
+) -> io::Result<()> {
+    let name = archive
+        .file_name()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("archive path {} has no file name", archive.display()),
+            )
+        })?
+        .to_owned();
+    let dir = match archive.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+    inotify.add_watch(
+        &dir,
+        AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+    )?;
+    thread::Builder::new()
+        .name("pnafs-watch".to_owned())
+        .spawn(move || {
+            loop {
+                let events = match inotify.read_events() {
+                    Ok(events) => events,
+                    Err(e) => {
+                        log::error!("watch: reading inotify events failed: {e}; live reload stops");
+                        return;
+                    }
+                };
+                if !events.iter().any(|e| e.name.as_deref() == Some(&*name)) {
+                    continue;
+                }
+                thread::sleep(SETTLE);
+                match reload(&tree) {
+                    Ok(Some(changes)) => invalidate(&notifier, &changes),
+                    Ok(None) => {}
+                    Err(e) => log::warn!("watch: keeping previous archive contents: {e}"),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Re-load the archive behind `tree` and swap it in if it changed.
+///
+/// Returns `None` when there is nothing to do — the archive on disk is
+/// still the one the tree was loaded from, or it is momentarily missing
+/// in the middle of a replace.
+pub(crate) fn reload(tree: &RwLock<FileTree>) -> io::Result<Option<ReloadChanges>> {
+    let (path, password, origin, options) = {
+        let tree = tree.read().map_err(|_| poisoned())?;
+        (
+            tree.archive_path().to_path_buf(),
+            tree.password().map(str::to_owned),
+            tree.origin().cloned(),
+            tree.load_options().clone(),
+        )
+    };
+    let unchanged = match &origin {
+        Some(origin) => origin.matches_path(&path)?,
+        None => path.try_exists()?.then_some(false),
+    };
+    if unchanged != Some(false) {
+        return Ok(None);
+    }
+    let fresh = archive_io::load_with(&path, password, options)?;
+    let mut tree = tree.write().map_err(|_| poisoned())?;
+    let old = std::mem::replace(&mut *tree, fresh);
+    let changes = tree.adopt_inodes(old);
+    log::info!(
+        "watch: reloaded {} ({} inodes, {} entries changed)",
+        path.display(),
+        changes.inodes.len(),
+        changes.entries.len(),
+    );
+    Ok(Some(changes))
+}
+
+fn invalidate(notifier: &Notifier, changes: &ReloadChanges) {
+    for (parent, name) in &changes.entries {
+        if let Err(e) = notifier.inval_entry(INodeNo(*parent), name) {
+            log::warn!("watch: inval_entry({parent}, {name:?}) failed: {e}");
+        }
+    }
+    for ino in &changes.inodes {
+        if let Err(e) = notifier.inval_inode(INodeNo(*ino), 0, 0) {
+            log::warn!("watch: inval_inode({ino}) failed: {e}");
+        }
+    }
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("tree lock poisoned by an earlier panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_tree::ROOT_INODE;
+    use pna::{Archive, Metadata, WriteOptions};
+    use std::ffi::OsStr;
+    use std::io::Write as IoWrite;
+    use tempfile::TempDir;
+
+    /// Entries carry a fixed mtime: without one, load stamps "now" and
+    /// every reload would (correctly) report every inode as changed.
+    fn write_archive(path: &Path, files: &[(&str, &[u8])]) {
+        let mut archive = Archive::write_header(std::fs::File::create(path).unwrap()).unwrap();
+        for (name, data) in files {
+            archive
+                .write_file(
+                    pna::EntryName::from_lossy(name),
+                    Metadata::new().with_modified(Some(pna::Duration::seconds(1_000_000))),
+                    WriteOptions::builder().build(),
+                    |w| w.write_all(data),
+                )
+                .unwrap();
+        }
+        archive.finalize().unwrap();
+    }
+
+    fn data_at(tree: &FileTree, path: &str) -> Vec<u8> {
+        let ino = tree.resolve_path(Path::new(path)).unwrap();
+        match &tree.get(ino).unwrap().content {
+            crate::file_tree::FsContent::File(fd) => fd.data().to_vec(),
+            _ => panic!("{path} is not a file"),
+        }
+    }
+
+    #[test]
+    fn reload_of_unchanged_archive_is_a_no_op() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.pna");
+        write_archive(&path, &[("f", b"x")]);
+        let tree = RwLock::new(archive_io::load(&path, None).unwrap());
+        assert!(reload(&tree).unwrap().is_none());
+    }
+
+    #[test]
+    fn reload_swaps_in_new_content_and_keeps_inodes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.pna");
+        write_archive(
+            &path,
+            &[("keep", b"same"), ("edit", b"old"), ("gone", b"x")],
+        );
+        let tree = RwLock::new(archive_io::load(&path, None).unwrap());
+        let (keep, edit) = {
+            let t = tree.read().unwrap();
+            (
+                t.resolve_path(Path::new("keep")).unwrap(),
+                t.resolve_path(Path::new("edit")).unwrap(),
+            )
+        };
+
+        // Publish a new archive the way CI does: write aside, rename over.
+        let staged = dir.path().join("a.pna.new");
+        write_archive(
+            &staged,
+            &[("keep", b"same"), ("edit", b"new"), ("added", b"y")],
+        );
+        std::fs::rename(&staged, &path).unwrap();
+
+        let changes = reload(&tree).unwrap().expect("archive changed");
+        let t = tree.read().unwrap();
+        assert_eq!(t.resolve_path(Path::new("keep")), Some(keep));
+        assert_eq!(t.resolve_path(Path::new("edit")), Some(edit));
+        assert_eq!(data_at(&t, "edit"), b"new");
+        assert!(t.resolve_path(Path::new("gone")).is_none());
+        assert!(t.resolve_path(Path::new("added")).is_some());
+
+        assert!(changes.inodes.contains(&edit));
+        assert!(!changes.inodes.contains(&keep));
+        for name in ["gone", "added"] {
+            assert!(
+                changes
+                    .entries
+                    .iter()
+                    .any(|(p, n)| *p == ROOT_INODE && n == OsStr::new(name)),
+                "{name} must be invalidated in the root directory"
+            );
+        }
+    }
+
+    #[test]
+    fn reload_keeps_last_good_tree_when_archive_is_garbage() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.pna");
+        write_archive(&path, &[("f", b"x")]);
+        let tree = RwLock::new(archive_io::load(&path, None).unwrap());
+        std::fs::write(&path, b"half-written").unwrap();
+        reload(&tree).unwrap_err();
+        assert_eq!(data_at(&tree.read().unwrap(), "f"), b"x");
+    }
+
+    #[test]
+    fn reload_keeps_the_subdir_as_the_root() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.pna");
+        write_archive(&path, &[("top/f", b"old"), ("other", b"x")]);
+        let options = archive_io::LoadOptions {
+            subdir: Some("top".into()),
+            ..archive_io::LoadOptions::default()
+        };
+        let tree = RwLock::new(archive_io::load_with(&path, None, options).unwrap());
+        let f = tree.read().unwrap().resolve_path(Path::new("f")).unwrap();
+
+        write_archive(&path, &[("top/f", b"new"), ("other", b"y")]);
+        let changes = reload(&tree).unwrap().expect("archive changed");
+        let t = tree.read().unwrap();
+        assert_eq!(t.resolve_path(Path::new("f")), Some(f));
+        assert_eq!(data_at(&t, "f"), b"new");
+        assert!(changes.inodes.contains(&f));
+        assert!(t.resolve_path(Path::new("other")).is_none());
+    }
+}